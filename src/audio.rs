@@ -0,0 +1,53 @@
+//! Sound output through an SDL audio queue. There is no APU yet, so only cartridge
+//! expansion audio such as the Disk System's wavetable channel is heard.
+
+use super::sdl2::AudioSubsystem;
+use super::sdl2::audio::{AudioQueue, AudioSpecDesired};
+
+const SAMPLE_RATE: usize = 44100;
+const CPU_FREQUENCY: usize = 1789773; // NTSC
+// Samples are queued in blocks so SDL isn't called after every instruction
+const BLOCK_SAMPLES: usize = 512;
+// Nothing paces emulation to real time yet, so once this much is queued new
+// blocks are dropped instead of letting the delay grow
+const MAX_QUEUED_SAMPLES: usize = SAMPLE_RATE / 10;
+
+pub struct Audio {
+    queue: AudioQueue<f32>,
+    block: Vec<f32>,
+    cycles: usize, // CPU cycles since the last sample, scaled by SAMPLE_RATE
+}
+
+impl Audio {
+    pub fn new(subsystem: &AudioSubsystem) -> Result<Self, String> {
+        let spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(1),
+            samples: None,
+        };
+        let queue = subsystem.open_queue::<f32, _>(None, &spec)?;
+        queue.resume();
+        Ok(Audio {
+            queue: queue,
+            block: Vec::with_capacity(BLOCK_SAMPLES),
+            cycles: 0,
+        })
+    }
+
+    // Called after every instruction with the output level, 0.0 to 1.0, it held
+    // for the CPU cycles it took
+    pub fn push(&mut self, level: f32, cpu_cycles: usize) {
+        self.cycles += cpu_cycles * SAMPLE_RATE;
+        while self.cycles >= CPU_FREQUENCY {
+            self.cycles -= CPU_FREQUENCY;
+            self.block.push(level);
+        }
+        if self.block.len() >= BLOCK_SAMPLES {
+            let queued = self.queue.size() as usize / 4; // Bytes of f32 samples
+            if queued < MAX_QUEUED_SAMPLES {
+                self.queue.queue(&self.block);
+            }
+            self.block.clear();
+        }
+    }
+}
@@ -1,55 +1,245 @@
 #![allow(dead_code)]
 
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+use super::cdl::{self, Cdl};
+use super::fds::Fds;
 use super::nes::KILOBYTE;
-use super::rom::Rom;
+use super::rom::{Mirroring, Rom};
 
 pub struct Cartridge {
     mapper: Box<Mapper>,
+    save_path: Option<PathBuf>,
+    saved_ram: Vec<u8>, // Battery RAM as last loaded or written
+    prg_size: usize,
+    chr_size: usize,
+    cdl: Option<Cdl>,
 }
 
 impl Cartridge {
-    pub fn new(rom: Rom) -> Self {
-        Cartridge {
+    pub fn new(rom: Rom) -> Result<Self, String> {
+        let prg_size = rom.prg_rom.len();
+        let chr_size = rom.chr_rom.len();
+        Ok(Cartridge {
             mapper: match rom.header.mapper_no() {
                 0 => Box::new(Mapper000::new(rom)),
-                _ => panic!("Mapper {:#} not implemented", rom.header.mapper_no()),
+                mapper_no => return Err(format!("Mapper {} not implemented", mapper_no)),
             },
+            save_path: None,
+            saved_ram: Vec::new(),
+            prg_size: prg_size,
+            chr_size: chr_size,
+            cdl: None,
+        })
+    }
+
+    pub fn new_fds(rom: Rom, bios: Vec<u8>) -> Self {
+        Cartridge {
+            mapper: Box::new(Fds::new(rom, bios)),
+            save_path: None,
+            saved_ram: Vec::new(),
+            prg_size: 0, // Disk System programs run from RAM
+            chr_size: 0,
+            cdl: None,
+        }
+    }
+
+    pub fn prg_size(&self) -> usize {
+        self.prg_size
+    }
+
+    // Loads battery backed RAM from the save file if it exists, and remembers the
+    // path for later flushes. Does nothing for carts without a battery.
+    pub fn attach_save_file(&mut self, path: PathBuf) -> io::Result<()> {
+        if self.mapper.battery_ram().is_none() {
+            return Ok(());
+        }
+        if path.exists() {
+            let mut data = Vec::new();
+            File::open(&path)?.read_to_end(&mut data)?;
+            self.mapper.load_battery_ram(&data);
+        }
+        self.saved_ram = self.mapper.battery_ram().map_or(Vec::new(), |ram| ram.to_vec());
+        self.save_path = Some(path);
+        Ok(())
+    }
+
+    // Only writes when the RAM changed since the last flush
+    pub fn flush_save_file(&mut self) -> io::Result<()> {
+        if let (Some(path), Some(ram)) = (&self.save_path, self.mapper.battery_ram()) {
+            if ram != &self.saved_ram[..] {
+                File::create(path)?.write_all(ram)?;
+                self.saved_ram = ram.to_vec();
+            }
+        }
+        Ok(())
+    }
+
+    // Starts code/data logging, merging with what an earlier run logged to path
+    pub fn attach_cdl_file(&mut self, path: PathBuf) -> io::Result<()> {
+        if self.prg_size == 0 {
+            return Err(io::Error::other("nothing to log without PRG ROM"));
+        }
+        self.cdl = Some(Cdl::open(path, self.prg_size, self.chr_size)?);
+        Ok(())
+    }
+
+    pub fn flush_cdl_file(&self) -> io::Result<()> {
+        match self.cdl {
+            Some(ref cdl) => cdl.save(),
+            None => Ok(()),
+        }
+    }
+
+    // Flags are ORed into the log for whatever PRG ROM byte addr maps to
+    pub fn log_prg(&mut self, addr: u16, flags: u8) {
+        if let Some(ref mut cdl) = self.cdl {
+            if let Some(offset) = self.mapper.prg_rom_offset(addr) {
+                cdl.log_prg(offset, addr, flags);
+            }
         }
     }
 
-    pub fn prg_read(&self, addr: u16) -> u8 {
+    pub fn prg_read(&mut self, addr: u16) -> u8 {
+        self.log_prg(addr, cdl::DATA);
         self.mapper.prg_read(addr)
     }
 
+    // Reads an opcode or operand
+    pub fn prg_fetch(&mut self, addr: u16) -> u8 {
+        self.log_prg(addr, cdl::CODE);
+        self.mapper.prg_read(addr)
+    }
+
+    // Reads without side effects for the debugger
+    pub fn prg_peek(&self, addr: u16) -> u8 {
+        self.mapper.prg_peek(addr)
+    }
+
     pub fn prg_write(&mut self, addr: u16, val: u8) {
         self.mapper.prg_write(addr, val);
     }
 
-    pub fn chr_read(&self, addr: u16) -> u8 {
+    // Writes without side effects for the debugger
+    pub fn prg_poke(&mut self, addr: u16, val: u8) {
+        self.mapper.prg_poke(addr, val);
+    }
+
+    // Reads pattern data for the debugger and views
+    pub fn chr_peek(&self, addr: u16) -> u8 {
+        self.mapper.chr_read(addr)
+    }
+
+    // Pattern data read by the CPU through PPUDATA
+    pub fn chr_read(&mut self, addr: u16) -> u8 {
+        if let Some(ref mut cdl) = self.cdl {
+            if let Some(offset) = self.mapper.chr_rom_offset(addr) {
+                cdl.log_chr(offset, cdl::CHR_READ);
+            }
+        }
         self.mapper.chr_read(addr)
     }
 
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.mirroring()
+    }
+
+    pub fn prg_rom(&self) -> &[u8] {
+        self.mapper.prg_rom()
+    }
+
+    pub fn chr_rom(&self) -> &[u8] {
+        self.mapper.chr_rom()
+    }
+
+    pub fn poke_prg_rom(&mut self, offset: usize, val: u8) {
+        self.mapper.poke_prg_rom(offset, val);
+    }
+
+    pub fn poke_chr_rom(&mut self, offset: usize, val: u8) {
+        self.mapper.poke_chr_rom(offset, val);
+    }
+
     pub fn chr_write(&mut self, addr: u16, val: u8) {
         self.mapper.chr_write(addr, val);
     }
+
+    pub fn clock(&mut self, cpu_cycles: usize) {
+        self.mapper.clock(cpu_cycles);
+    }
+
+    pub fn irq_pending(&self) -> bool {
+        self.mapper.irq_pending()
+    }
+
+    pub fn audio_output(&self) -> f32 {
+        self.mapper.audio_output()
+    }
+
+    // The side inserted, if the cart has a disk drive
+    pub fn switch_disk_side(&mut self) -> Option<usize> {
+        self.mapper.switch_disk_side()
+    }
+
+    pub fn eject_disk(&mut self) -> bool {
+        self.mapper.eject_disk()
+    }
 }
 
-trait Mapper {
-    fn prg_read(&self, addr: u16) -> u8;
+pub trait Mapper {
+    fn prg_read(&mut self, addr: u16) -> u8;
+    // Same as prg_read without acknowledging IRQs, clocking latches etc.
+    fn prg_peek(&self, addr: u16) -> u8;
     fn prg_write(&mut self, addr: u16, val: u8);
+    // Writes memory for the debugger. Registers and unmapped addresses are ignored,
+    // so a poke never switches banks.
+    fn prg_poke(&mut self, _addr: u16, _val: u8) {}
     fn chr_read(&self, addr: u16) -> u8;
     fn chr_write(&mut self, addr: u16, val: u8);
+    fn mirroring(&self) -> Mirroring;
+
+    // Where an address currently maps in PRG or CHR ROM, for code/data logging.
+    // None for RAM and registers.
+    fn prg_rom_offset(&self, _addr: u16) -> Option<usize> { None }
+    fn chr_rom_offset(&self, _addr: u16) -> Option<usize> { None }
+
+    // ROM images for the debugger's hex editor
+    fn prg_rom(&self) -> &[u8] { &[] }
+    fn chr_rom(&self) -> &[u8] { &[] }
+    fn poke_prg_rom(&mut self, _offset: usize, _val: u8) {}
+    fn poke_chr_rom(&mut self, _offset: usize, _val: u8) {}
+
+    // Called after every CPU instruction with the number of CPU cycles it took
+    fn clock(&mut self, _cpu_cycles: usize) {}
+    fn irq_pending(&self) -> bool { false }
+    // Expansion audio output in the range 0.0 to 1.0
+    fn audio_output(&self) -> f32 { 0.0 }
+
+    // Battery backed PRG-RAM, if the cart has any
+    fn battery_ram(&self) -> Option<&[u8]> { None }
+    fn load_battery_ram(&mut self, _data: &[u8]) {}
+
+    // Disk System only, None and false for carts without a drive
+    fn switch_disk_side(&mut self) -> Option<usize> { None }
+    fn eject_disk(&mut self) -> bool { false }
 }
 
 struct Mapper000 {
     prg_rom: Vec<u8>,
+    prg_rom_size: usize, // Before 16KB roms are duplicated
     prg_ram: Vec<u8>,
-    chr_rom: Vec<u8>,
+    chr_rom: Vec<u8>, // 8KB of CHR RAM if the rom has no CHR ROM
+    chr_ram: bool,
+    battery: bool,
+    mirroring: Mirroring,
 }
 
 impl Mapper000 {
     pub fn new(rom: Rom) -> Self {
         let prg_ram = vec![0; 8 * KILOBYTE];
+        let chr_ram = rom.chr_rom.is_empty();
 
         Mapper000 {
             prg_rom: {
@@ -63,15 +253,19 @@ impl Mapper000 {
                 }
                 temp
             },
+            prg_rom_size: rom.prg_rom.len(),
             prg_ram: prg_ram,
-            chr_rom: rom.chr_rom,
+            battery: rom.header.has_battery(),
+            mirroring: rom.header.mirroring(),
+            chr_rom: if chr_ram { vec![0; 8 * KILOBYTE] } else { rom.chr_rom },
+            chr_ram: chr_ram,
         }
     }
 }
 
 impl Mapper for Mapper000 {
-    fn prg_read(&self, addr: u16) -> u8 {
-        if (addr >= 0x6000) & (addr < 0x7FFF) {
+    fn prg_read(&mut self, addr: u16) -> u8 {
+        if (0x6000..=0x7FFF).contains(&addr) {
             self.prg_ram[(addr - 0x6000) as usize]
         } else if addr >= 0x8000 {
             self.prg_rom[(addr - 0x8000) as usize]
@@ -80,8 +274,18 @@ impl Mapper for Mapper000 {
         }
     }
 
+    fn prg_peek(&self, addr: u16) -> u8 {
+        if (0x6000..=0x7FFF).contains(&addr) {
+            self.prg_ram[(addr - 0x6000) as usize]
+        } else if addr >= 0x8000 {
+            self.prg_rom[(addr - 0x8000) as usize]
+        } else {
+            (addr >> 8) as u8 // Open bus
+        }
+    }
+
     fn prg_write(&mut self, addr: u16, val: u8) {
-        if (addr >= 0x6000) & (addr < 0x7FFF) {
+        if (0x6000..=0x7FFF).contains(&addr) {
             self.prg_ram[(addr - 0x6000) as usize] = val;
         } else if addr >= 0x8000 {
             self.prg_rom[(addr - 0x8000) as usize] = val;
@@ -90,9 +294,17 @@ impl Mapper for Mapper000 {
         }
     }
 
+    fn prg_poke(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x6000...0x7FFF => self.prg_ram[(addr - 0x6000) as usize] = val,
+            0x8000...0xFFFF => self.prg_rom[(addr - 0x8000) as usize] = val,
+            _ => {}
+        }
+    }
+
     fn chr_read(&self, addr: u16) -> u8 {
         if addr < 0x2000 {
-            self.chr_rom[(addr - 0x2000) as usize]
+            self.chr_rom[addr as usize]
         } else {
             panic!("Unrecognized CHR address: {:#X}", addr);
         }
@@ -100,9 +312,64 @@ impl Mapper for Mapper000 {
 
     fn chr_write(&mut self, addr: u16, val: u8) {
         if addr < 0x2000 {
-            self.chr_rom[(addr - 0x2000) as usize] = val;
+            self.chr_rom[addr as usize] = val;
         } else {
             panic!("Unrecognized CHR address: {:#X}", addr);
         }
     }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn prg_rom_offset(&self, addr: u16) -> Option<usize> {
+        if addr >= 0x8000 {
+            Some((addr - 0x8000) as usize % self.prg_rom_size)
+        } else {
+            None
+        }
+    }
+
+    fn chr_rom_offset(&self, addr: u16) -> Option<usize> {
+        if !self.chr_ram && addr < 0x2000 {
+            Some(addr as usize)
+        } else {
+            None
+        }
+    }
+
+    fn prg_rom(&self) -> &[u8] {
+        &self.prg_rom[..self.prg_rom_size]
+    }
+
+    fn chr_rom(&self) -> &[u8] {
+        if self.chr_ram { &[] } else { &self.chr_rom }
+    }
+
+    // 16KB roms are stored twice, both copies need the change
+    fn poke_prg_rom(&mut self, offset: usize, val: u8) {
+        let mut offset = offset;
+        while offset < self.prg_rom.len() {
+            self.prg_rom[offset] = val;
+            offset += self.prg_rom_size;
+        }
+    }
+
+    fn poke_chr_rom(&mut self, offset: usize, val: u8) {
+        if self.chr_ram {
+            return;
+        }
+        if let Some(byte) = self.chr_rom.get_mut(offset) {
+            *byte = val;
+        }
+    }
+
+    fn battery_ram(&self) -> Option<&[u8]> {
+        if self.battery { Some(&self.prg_ram) } else { None }
+    }
+
+    fn load_battery_ram(&mut self, data: &[u8]) {
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+    }
 }
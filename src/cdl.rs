@@ -0,0 +1,136 @@
+//! Code/data logger writing the .cdl format used by FCEUX
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+// PRG ROM byte flags. Bits 2-3 hold which 8KB window of $8000-$FFFF the byte
+// was accessed through. Bit 6 marks DMC samples, which needs an APU.
+pub const CODE: u8 = 0x01;
+pub const DATA: u8 = 0x02;
+pub const INDIRECT_CODE: u8 = 0x10; // Reached through JMP ($xxxx)
+pub const INDIRECT_DATA: u8 = 0x20; // Read through ($xx,X) or ($xx),Y
+
+// CHR ROM byte flags. Bit 0 marks bytes the PPU drew, but it doesn't render yet,
+// so only reads through $2007 are logged and drawn bytes come from a merged file.
+pub const CHR_READ: u8 = 0x02;
+
+// The file is one flag byte per PRG ROM byte followed by one per CHR ROM byte
+pub struct Cdl {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    path: PathBuf,
+}
+
+impl Cdl {
+    // Merges with the log already at path, if any, so runs accumulate
+    pub fn open(path: PathBuf, prg_size: usize, chr_size: usize) -> io::Result<Self> {
+        let mut cdl = Cdl {
+            prg: vec![0; prg_size],
+            chr: vec![0; chr_size],
+            path: path,
+        };
+        if cdl.path.exists() {
+            let mut data = Vec::new();
+            File::open(&cdl.path)?.read_to_end(&mut data)?;
+            if data.len() != prg_size + chr_size {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "CDL file size doesn't match the rom"));
+            }
+            for (flags, old) in cdl.prg.iter_mut().chain(cdl.chr.iter_mut()).zip(data) {
+                *flags |= old;
+            }
+        }
+        Ok(cdl)
+    }
+
+    pub fn log_prg(&mut self, offset: usize, addr: u16, flags: u8) {
+        if let Some(entry) = self.prg.get_mut(offset) {
+            *entry |= flags | (((addr >> 13) & 0x3) << 2) as u8;
+        }
+    }
+
+    pub fn log_chr(&mut self, offset: usize, flags: u8) {
+        if let Some(entry) = self.chr.get_mut(offset) {
+            *entry |= flags;
+        }
+    }
+
+    pub fn save(&self) -> io::Result<()> {
+        let mut file = File::create(&self.path)?;
+        file.write_all(&self.prg)?;
+        file.write_all(&self.chr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use cart::Cartridge;
+    use ppu::Ppu;
+    use rom::test_rom;
+    use super::*;
+
+    fn test_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("gadget_nes_test_{}_{}.cdl", name, process::id()))
+    }
+
+    #[test]
+    fn flag_bits() {
+        let mut cdl = Cdl::open(test_path("flags"), 4, 2).unwrap();
+        // Bits 2-3 are the 8KB window of $8000-$FFFF
+        cdl.log_prg(0, 0x8000, CODE);
+        cdl.log_prg(1, 0xA001, DATA);
+        cdl.log_prg(2, 0xC002, CODE | INDIRECT_CODE);
+        cdl.log_prg(3, 0xE003, DATA | INDIRECT_DATA);
+        cdl.log_prg(3, 0xE003, CODE);
+        cdl.log_prg(4, 0xE004, CODE); // Past the end
+        cdl.log_chr(1, CHR_READ);
+        assert_eq!(cdl.prg, [0x01, 0x06, 0x19, 0x2F]);
+        assert_eq!(cdl.chr, [0x00, 0x02]);
+    }
+
+    #[test]
+    fn merge() {
+        let path = test_path("merge");
+        fs::write(&path, [0x01, 0x00, 0x02, 0x01]).unwrap();
+        let mut cdl = Cdl::open(path.clone(), 3, 1).unwrap();
+        cdl.log_prg(0, 0x8000, DATA);
+        cdl.log_prg(1, 0x8001, CODE);
+        cdl.log_chr(0, CHR_READ);
+        cdl.save().unwrap();
+        assert_eq!(fs::read(&path).unwrap(), [0x03, 0x01, 0x02, 0x03]);
+
+        // A log for a different rom is refused rather than merged
+        assert!(Cdl::open(path.clone(), 4, 1).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cartridge_logging() {
+        let path = test_path("cart");
+        let mut cart = Cartridge::new(test_rom(&[])).unwrap();
+        cart.attach_cdl_file(path.clone()).unwrap();
+        cart.prg_fetch(0xC000);
+        cart.prg_read(0xC001);
+        cart.prg_read(0x6000); // PRG RAM isn't logged
+
+        let mut ppu = Ppu::new();
+        ppu.write_register(&mut cart, 0x2006, 0x00);
+        ppu.write_register(&mut cart, 0x2006, 0x10);
+        ppu.read_register(&mut cart, 0x2007);
+        ppu.read_register(&mut cart, 0x2007);
+
+        cart.flush_cdl_file().unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let (prg, chr) = data.split_at(0x4000);
+        assert_eq!(prg[..3], [0x09, 0x0A, 0x00]);
+        assert!(prg[3..].iter().all(|&flags| flags == 0));
+        assert_eq!(chr.len(), 0x2000);
+        assert_eq!(chr.iter().position(|&flags| flags != 0), Some(0x10));
+        assert_eq!(chr[0x10..0x13], [CHR_READ, CHR_READ, 0x00]);
+    }
+}
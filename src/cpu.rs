@@ -1,14 +1,137 @@
+use std::collections::HashMap;
 use std::fmt;
 
+use super::cdl;
 use super::interconnect::Interconnect;
 use super::nes::KILOBYTE;
 
+const NMI_VECTOR: u16 = 0xFFFA;
 const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR: u16 = 0xFFFE;
+
+// Base cycle count of every opcode. Branches and the read-only instructions add
+// their page crossing penalties as they execute.
+const OPCODE_CYCLES: [u8; 256] = [
+//  0  1  2  3  4  5  6  7  8  9  A  B  C  D  E  F
+    7, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6, // 0
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 1
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6, // 2
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 3
+    6, 6, 2, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6, // 4
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 5
+    6, 6, 2, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6, // 6
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // 7
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // 8
+    2, 6, 2, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5, // 9
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4, // A
+    2, 5, 2, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4, // B
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // C
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // D
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6, // E
+    2, 5, 2, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7, // F
+];
 
 pub struct Cpu {
     registers: Registers,
     cycles: usize,
     ram: [u8; 2 * KILOBYTE],
+
+    // Debugger support
+    instruction_pc: u16, // PC of the instruction currently executing
+    watchpoints: HashMap<usize, Watchpoint>,
+    watch_hits: Vec<WatchHit>,
+    entered_interrupt: Option<Interrupt>, // During the last instruction
+    call_stack: Vec<Frame>,
+    stack_desyncs: usize,
+    last_stack_desync: Option<StackDesync>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+// Status flags that can be set from the debugger
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Flag {
+    Carry,
+    Zero,
+    Interrupt,
+    Decimal,
+    Overflow,
+    Negative,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Watchpoint {
+    pub kind: WatchKind,
+    pub low_addr: u16,
+    pub high_addr: u16,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+    Brk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FrameKind {
+    Jsr,
+    Interrupt(Interrupt),
+}
+
+// An entry on the shadow call stack kept alongside the real one
+#[derive(Debug, Clone, Copy)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub call_pc: u16, // The JSR or BRK, or the instruction an NMI/IRQ happened before
+    pub target: u16,
+    pub return_addr: u16, // Where RTS/RTI should land
+    s: u8, // Stack pointer after the return address (and P for interrupts) was pushed
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DesyncKind {
+    // Returned past frames whose return addresses were discarded, e.g. by PLA or TXS
+    Skipped(usize),
+    // A new call reused stack space still owned by frames that never returned
+    Overwritten(usize),
+    // RTS/RTI without a matching call, like the RTS trick for jump tables
+    NoFrame,
+    // The return address on the stack was changed before returning
+    Redirected(u16),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StackDesync {
+    pub pc: u16,
+    pub kind: DesyncKind,
+}
+
+impl fmt::Display for StackDesync {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Call stack desync @ ${:04X}: ", self.pc)?;
+        match self.kind {
+            DesyncKind::Skipped(count) => write!(f, "returned past {} frame(s) removed from the stack", count),
+            DesyncKind::Overwritten(count) => write!(f, "call overwrote {} frame(s) that never returned", count),
+            DesyncKind::NoFrame => write!(f, "return without a matching call"),
+            DesyncKind::Redirected(expected) => write!(f, "return address changed from ${:04X}", expected),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WatchHit {
+    pub key: usize,
+    pub pc: u16,
+    pub addr: u16,
+    pub kind: WatchKind, // Read or Write
+    pub old_val: Option<u8>, // Only known for writes
+    pub new_val: u8,
 }
 
 // Register names match what's listed on NESDevWiki
@@ -40,6 +163,12 @@ trait AddressingMode {
     fn init(_cpu: &mut Cpu, _interconnect: &mut Interconnect) -> Self;
     fn load(&self, cpu: &mut Cpu, interconnect: &mut Interconnect) -> u8;
     fn store(&self, cpu: &mut Cpu, interconnect: &mut Interconnect, val: u8);
+
+    // Load for instructions that only read, which take an extra cycle when indexing
+    // crosses a page. Stores and read-modify-writes always take it.
+    fn read(&self, cpu: &mut Cpu, interconnect: &mut Interconnect) -> u8 {
+        self.load(cpu, interconnect)
+    }
 }
 
 struct AccumulatorAM;
@@ -52,7 +181,7 @@ struct ZeroPageIdxYAM { arg: u8, }
 struct AbsoluteIdxXAM { arg: u16, }
 struct AbsoluteIdxYAM { arg: u16, }
 struct IndexedIndirectAM { addr: u16, }
-struct IndirectIndexedAM { addr: u16, }
+struct IndirectIndexedAM { addr: u16, page_crossed: bool, }
 
 impl AddressingMode for AccumulatorAM {
     fn init(_cpu: &mut Cpu, _interconnect: &mut Interconnect) -> Self {
@@ -163,6 +292,14 @@ impl AddressingMode for AbsoluteIdxXAM {
         let x = cpu.registers.x as u16;
         cpu.store(interconnect, self.arg.wrapping_add(x), val);
     }
+
+    fn read(&self, cpu: &mut Cpu, interconnect: &mut Interconnect) -> u8 {
+        let x = cpu.registers.x as u16;
+        if page_crossed(self.arg, self.arg.wrapping_add(x)) {
+            cpu.add_cycles(1);
+        }
+        self.load(cpu, interconnect)
+    }
 }
 
 impl AddressingMode for AbsoluteIdxYAM {
@@ -179,6 +316,14 @@ impl AddressingMode for AbsoluteIdxYAM {
         let y = cpu.registers.y as u16;
         cpu.store(interconnect, self.arg.wrapping_add(y), val);
     }
+
+    fn read(&self, cpu: &mut Cpu, interconnect: &mut Interconnect) -> u8 {
+        let y = cpu.registers.y as u16;
+        if page_crossed(self.arg, self.arg.wrapping_add(y)) {
+            cpu.add_cycles(1);
+        }
+        self.load(cpu, interconnect)
+    }
 }
 
 impl AddressingMode for IndexedIndirectAM {
@@ -189,6 +334,7 @@ impl AddressingMode for IndexedIndirectAM {
     }
 
     fn load(&self, cpu: &mut Cpu, interconnect: &mut Interconnect) -> u8 {
+        interconnect.log_cdl(self.addr, cdl::INDIRECT_DATA);
         cpu.fetch_byte(interconnect, self.addr)
     }
 
@@ -200,17 +346,30 @@ impl AddressingMode for IndexedIndirectAM {
 impl AddressingMode for IndirectIndexedAM {
     fn init(cpu: &mut Cpu, interconnect: &mut Interconnect) -> Self {
         let index = cpu.load_next_byte_bump_pc(interconnect);
-        let addr = cpu.zero_page_addr(interconnect, index).wrapping_add(cpu.registers.y as u16);
-        IndirectIndexedAM{ addr: addr }
+        let base = cpu.zero_page_addr(interconnect, index);
+        let addr = base.wrapping_add(cpu.registers.y as u16);
+        IndirectIndexedAM{ addr: addr, page_crossed: page_crossed(base, addr) }
     }
 
     fn load(&self, cpu: &mut Cpu, interconnect: &mut Interconnect) -> u8 {
+        interconnect.log_cdl(self.addr, cdl::INDIRECT_DATA);
         cpu.fetch_byte(interconnect, self.addr)
     }
 
     fn store(&self, cpu: &mut Cpu, interconnect: &mut Interconnect, val: u8) {
         cpu.store(interconnect, self.addr, val);
     }
+
+    fn read(&self, cpu: &mut Cpu, interconnect: &mut Interconnect) -> u8 {
+        if self.page_crossed {
+            cpu.add_cycles(1);
+        }
+        self.load(cpu, interconnect)
+    }
+}
+
+fn page_crossed(base: u16, addr: u16) -> bool {
+    base & 0xFF00 != addr & 0xFF00
 }
 
 impl Cpu {
@@ -219,20 +378,34 @@ impl Cpu {
             registers: Registers::default(),
             cycles: 0,
             ram: [0u8; 2 * KILOBYTE],
+
+            instruction_pc: 0,
+            watchpoints: HashMap::new(),
+            watch_hits: Vec::new(),
+            entered_interrupt: None,
+            call_stack: Vec::new(),
+            stack_desyncs: 0,
+            last_stack_desync: None,
         }
     }
 
     pub fn reset(&mut self, interconnect: &mut Interconnect) {
-        self.registers.pc = 0xC000;
-        // self.registers.pc = self.fetch_word(interconnect, RESET_VECTOR);
+        self.registers.pc = self.fetch_word(interconnect, RESET_VECTOR);
         self.registers.s = 0xFD;
         self.registers.p = ProcessorFlags::from_bits(0x24).unwrap();
+        self.call_stack.clear();
+        // The reset sequence takes 7 cycles, as seen at the start of nestest.log
+        self.add_cycles(7);
     }
 
+    // Instruction fetches don't trigger watchpoints
     fn load_next_byte_bump_pc(&mut self, interconnect: &mut Interconnect) -> u8 {
         let pc = self.registers.pc;
         self.registers.pc += 1;
-        self.fetch_byte(interconnect, pc)
+        match pc {
+            0x0000...0x1FFF => self.read_bus(interconnect, pc),
+            _ => interconnect.read_code(pc),
+        }
     }
 
     fn load_next_word_bump_pc(&mut self, interconnect: &mut Interconnect) -> u16 {
@@ -251,11 +424,79 @@ impl Cpu {
         self.registers.pc
     }
 
+    pub fn a(&self) -> u8 {
+        self.registers.a
+    }
+
+    pub fn x(&self) -> u8 {
+        self.registers.x
+    }
+
+    pub fn y(&self) -> u8 {
+        self.registers.y
+    }
+
+    pub fn s(&self) -> u8 {
+        self.registers.s
+    }
+
+    pub fn p(&self) -> u8 {
+        self.registers.p.bits()
+    }
+
+    // Reads memory without side effects or triggering watchpoints
+    pub fn peek_byte(&self, interconnect: &Interconnect, addr: u16) -> u8 {
+        match addr {
+            0x0...0x1FFF => self.ram[(addr % 0x0800) as usize],
+            0x2000...0xFFFF => interconnect.peek_byte(addr),
+            _ => unreachable!(),
+        }
+    }
+
     pub fn cycles(&self) -> usize {
         self.cycles
     }
 
+    // Services a maskable interrupt. The caller keeps asserting the line until acknowledged.
+    pub fn irq(&mut self, interconnect: &mut Interconnect) {
+        if self.registers.p.contains(ProcessorFlags::INTERRUPT) {
+            return;
+        }
+        let pc = self.registers.pc;
+        self.interrupt(interconnect, Interrupt::Irq, pc);
+        self.add_cycles(7);
+    }
+
+    pub fn nmi(&mut self, interconnect: &mut Interconnect) {
+        let pc = self.registers.pc;
+        self.interrupt(interconnect, Interrupt::Nmi, pc);
+        self.add_cycles(7);
+    }
+
+    fn interrupt(&mut self, interconnect: &mut Interconnect, kind: Interrupt, return_addr: u16) {
+        self.stack_push_word(return_addr);
+        let mut p = (self.registers.p.bits() | ProcessorFlags::ALWAYS_SET.bits()) & !ProcessorFlags::STACK_COPY.bits();
+        if kind == Interrupt::Brk {
+            p |= ProcessorFlags::STACK_COPY.bits();
+        }
+        self.stack_push_byte(p);
+        self.registers.p.set(ProcessorFlags::INTERRUPT, true);
+        let vector = if kind == Interrupt::Nmi { NMI_VECTOR } else { IRQ_VECTOR };
+        self.registers.pc = self.fetch_word(interconnect, vector);
+
+        let call_pc = if kind == Interrupt::Brk { self.instruction_pc } else { return_addr };
+        let target = self.registers.pc;
+        self.push_frame(FrameKind::Interrupt(kind), call_pc, target, return_addr);
+        self.entered_interrupt = Some(kind);
+    }
+
     pub fn fetch_byte(&mut self, interconnect: &mut Interconnect, addr: u16) -> u8 {
+        let val = self.read_bus(interconnect, addr);
+        self.check_watchpoints(addr, WatchKind::Read, None, val);
+        val
+    }
+
+    fn read_bus(&mut self, interconnect: &mut Interconnect, addr: u16) -> u8 {
         match addr {
             0x0...0x07FF => self.ram[addr as usize],
             0x0800...0x1FFF => self.ram[(addr % 0x0800) as usize],
@@ -270,16 +511,45 @@ impl Cpu {
     }
 
     fn store(&mut self, interconnect: &mut Interconnect, addr: u16, val: u8) {
+        if !self.watchpoints.is_empty() {
+            // Old values of registers aren't meaningful, only report them for RAM and cartridge space
+            let old_val = match addr {
+                0x0...0x1FFF | 0x4020...0xFFFF => Some(self.peek_byte(interconnect, addr)),
+                _ => None,
+            };
+            self.check_watchpoints(addr, WatchKind::Write, old_val, val);
+        }
+        self.write_bus(interconnect, addr, val);
+    }
+
+    fn write_bus(&mut self, interconnect: &mut Interconnect, addr: u16, val: u8) {
         match addr {
             0x0...0x07FF => self.ram[addr as usize] = val,
             0x0800...0x1FFF => self.ram[(addr % 0x0800) as usize] = val,
+            0x4014 => {
+                interconnect.write_byte(addr, val);
+                self.oam_dma(interconnect, val);
+            }
             0x2000...0xFFFF => interconnect.write_byte(addr, val),
             _ => unreachable!(),
         }
     }
 
+    // Copies a page to OAM, halting the CPU for 513 cycles or 514 from an odd one
+    fn oam_dma(&mut self, interconnect: &mut Interconnect, page: u8) {
+        let extra = (self.cycles / 3) % 2;
+        for offset in 0..0x100 {
+            let val = self.read_bus(interconnect, (page as u16) << 8 | offset);
+            interconnect.ppu.write_oam_data(val);
+        }
+        self.add_cycles(513 + extra);
+    }
+
     fn stack_push_byte(&mut self, val: u8) {
-        self.ram[(self.registers.s as usize) + 0x100] = val;
+        let addr = (self.registers.s as usize) + 0x100;
+        let old_val = self.ram[addr];
+        self.check_watchpoints(addr as u16, WatchKind::Write, Some(old_val), val);
+        self.ram[addr] = val;
         self.registers.s -= 1;
     }
 
@@ -292,7 +562,9 @@ impl Cpu {
 
     fn stack_pop_byte(&mut self) -> u8 {
         self.registers.s += 1;
-        let val = self.ram[(self.registers.s as usize) + 0x100];
+        let addr = (self.registers.s as usize) + 0x100;
+        let val = self.ram[addr];
+        self.check_watchpoints(addr as u16, WatchKind::Read, None, val);
         val
     }
 
@@ -308,6 +580,9 @@ impl Cpu {
     }
 
     pub fn step(&mut self, interconnect: &mut Interconnect) -> u8 {
+        self.instruction_pc = self.registers.pc;
+        self.watch_hits.clear();
+        self.entered_interrupt = None;
         let opcode = self.load_next_byte_bump_pc(interconnect);
         match opcode {
             // Branches
@@ -491,6 +766,7 @@ impl Cpu {
             // Jumps
             0x4C => self.jmp_absolute(interconnect),
             0x6C => self.jmp_indirect(interconnect),
+            0x00 => self.brk(interconnect),
             0x20 => self.jsr(interconnect),
             0x40 => self.rti(),
             0x60 => self.rts(),
@@ -499,9 +775,159 @@ impl Cpu {
 
             _ => panic!("Unrecognized opcode: {:#X}", opcode),
         };
+        self.add_cycles(OPCODE_CYCLES[opcode as usize] as usize);
         opcode
     }
 
+    // Methods for debugger
+    pub fn set_pc(&mut self, val: u16) {
+        self.registers.pc = val;
+    }
+
+    pub fn set_a(&mut self, val: u8) {
+        self.registers.a = val;
+    }
+
+    pub fn set_x(&mut self, val: u8) {
+        self.registers.x = val;
+    }
+
+    pub fn set_y(&mut self, val: u8) {
+        self.registers.y = val;
+    }
+
+    pub fn set_s(&mut self, val: u8) {
+        self.registers.s = val;
+    }
+
+    pub fn set_p(&mut self, val: u8) {
+        self.registers.p = ProcessorFlags::from_bits_truncate(val);
+    }
+
+    pub fn set_flag(&mut self, flag: Flag, on: bool) {
+        let flag = match flag {
+            Flag::Carry => ProcessorFlags::CARRY,
+            Flag::Zero => ProcessorFlags::ZERO,
+            Flag::Interrupt => ProcessorFlags::INTERRUPT,
+            Flag::Decimal => ProcessorFlags::DECIMAL,
+            Flag::Overflow => ProcessorFlags::OVERFLOW,
+            Flag::Negative => ProcessorFlags::NEGATIVE,
+        };
+        self.registers.p.set(flag, on);
+    }
+
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    // Writes memory for the debugger without triggering watchpoints. Stays off the
+    // live bus so registers are only latched, see Interconnect::poke_byte.
+    pub fn poke_byte(&mut self, interconnect: &mut Interconnect, addr: u16, val: u8) {
+        match addr {
+            0x0...0x1FFF => self.ram[(addr % 0x0800) as usize] = val,
+            _ => interconnect.poke_byte(addr, val),
+        }
+    }
+
+    pub fn add_watchpoint(&mut self, key: usize, watchpoint: Watchpoint) {
+        self.watchpoints.insert(key, watchpoint);
+    }
+
+    pub fn remove_watchpoint(&mut self, key: usize) -> bool {
+        self.watchpoints.remove(&key).is_some()
+    }
+
+    pub fn watchpoints(&self) -> &HashMap<usize, Watchpoint> {
+        &self.watchpoints
+    }
+
+    pub fn has_watch_hits(&self) -> bool {
+        !self.watch_hits.is_empty()
+    }
+
+    pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+        self.watch_hits.drain(..).collect()
+    }
+
+    pub fn entered_interrupt(&self) -> Option<Interrupt> {
+        self.entered_interrupt
+    }
+
+    // Innermost frame last
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    // Desyncs are common in games (jump tables via RTS), so only a count and
+    // the most recent one are kept
+    pub fn stack_desyncs(&self) -> (usize, Option<StackDesync>) {
+        (self.stack_desyncs, self.last_stack_desync)
+    }
+
+    fn stack_desync(&mut self, kind: DesyncKind) {
+        self.stack_desyncs += 1;
+        self.last_stack_desync = Some(StackDesync {
+            pc: self.instruction_pc,
+            kind: kind,
+        });
+    }
+
+    fn push_frame(&mut self, kind: FrameKind, call_pc: u16, target: u16, return_addr: u16) {
+        // Anything at or below the new frame's stack space can never be returned to
+        let s = self.registers.s;
+        let live = self.call_stack.iter().take_while(|frame| frame.s > s).count();
+        if live < self.call_stack.len() {
+            let count = self.call_stack.len() - live;
+            self.call_stack.truncate(live);
+            self.stack_desync(DesyncKind::Overwritten(count));
+        }
+        self.call_stack.push(Frame {
+            kind: kind,
+            call_pc: call_pc,
+            target: target,
+            return_addr: return_addr,
+            s: s,
+        });
+    }
+
+    // Called with the stack pointer from before RTS/RTI popped anything
+    fn pop_frame(&mut self, s: u8, return_addr: u16) {
+        let live = self.call_stack.iter().take_while(|frame| frame.s >= s).count();
+        if live < self.call_stack.len() {
+            let count = self.call_stack.len() - live;
+            self.call_stack.truncate(live);
+            self.stack_desync(DesyncKind::Skipped(count));
+        }
+        match self.call_stack.last().cloned() {
+            Some(frame) if frame.s == s => {
+                self.call_stack.pop();
+                if frame.return_addr != return_addr {
+                    self.stack_desync(DesyncKind::Redirected(frame.return_addr));
+                }
+            }
+            _ => self.stack_desync(DesyncKind::NoFrame),
+        }
+    }
+
+    fn check_watchpoints(&mut self, addr: u16, kind: WatchKind, old_val: Option<u8>, new_val: u8) {
+        if self.watchpoints.is_empty() {
+            return;
+        }
+        for (&key, watchpoint) in self.watchpoints.iter() {
+            let kind_matches = watchpoint.kind == WatchKind::ReadWrite || watchpoint.kind == kind;
+            if kind_matches && addr >= watchpoint.low_addr && addr <= watchpoint.high_addr {
+                self.watch_hits.push(WatchHit {
+                    key: key,
+                    pc: self.instruction_pc,
+                    addr: addr,
+                    kind: kind,
+                    old_val: old_val,
+                    new_val: new_val,
+                });
+            }
+        }
+    }
+
     // INSTRUCTIONS
     // Branches
     fn bcs(&mut self, am: RelativeAM) { self.branch(am, ProcessorFlags::CARRY, true); }
@@ -516,6 +942,8 @@ impl Cpu {
     fn branch(&mut self, am: RelativeAM, flag: ProcessorFlags, set: bool) {
         let offset_addr = self.registers.pc.wrapping_add((am.arg as i8) as u16);
         if set == self.registers.p.contains(flag) {
+            // Taken branches cost an extra cycle, and another if they cross a page
+            self.add_cycles(if page_crossed(self.registers.pc, offset_addr) { 2 } else { 1 });
             self.registers.pc = offset_addr;
         }
     }
@@ -573,7 +1001,7 @@ impl Cpu {
     // ALU Ops
     fn adc<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
         let a = self.registers.a;
-        let m = am.load(self, interconnect);
+        let m = am.read(self, interconnect);
         let c = if self.registers.p.contains(ProcessorFlags::CARRY) { 1 } else { 0 };
         let result = a.wrapping_add(m).wrapping_add(c);
         self.registers.a = result;
@@ -593,7 +1021,7 @@ impl Cpu {
     }
 
     fn and<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
-        let a = self.registers.a & am.load(self, interconnect);
+        let a = self.registers.a & am.read(self, interconnect);
         self.registers.a = a;
         self.registers.p.set(ProcessorFlags::ZERO, a == 0);
         self.registers.p.set(ProcessorFlags::NEGATIVE, a & (1 << 7) != 0);
@@ -601,7 +1029,7 @@ impl Cpu {
 
     fn bit<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
         let a = self.registers.a;
-        let m = am.load(self, interconnect);
+        let m = am.read(self, interconnect);
         self.registers.p.set(ProcessorFlags::ZERO, a & m == 0);
         self.registers.p.set(ProcessorFlags::OVERFLOW, m & (1 << 6) != 0);
         self.registers.p.set(ProcessorFlags::NEGATIVE, m & (1 << 7) != 0);
@@ -615,25 +1043,25 @@ impl Cpu {
 
     fn cmp<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
         let a = self.registers.a;
-        let m = am.load(self, interconnect);
+        let m = am.read(self, interconnect);
         self.compare(a, m);
     }
 
     fn cpx<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
         let x = self.registers.x;
-        let m = am.load(self, interconnect);
+        let m = am.read(self, interconnect);
         self.compare(x, m);
     }
 
     fn cpy<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
         let y = self.registers.y;
-        let m = am.load(self, interconnect);
+        let m = am.read(self, interconnect);
         self.compare(y, m);
     }
 
     fn eor<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
         let a = self.registers.a;
-        let m = am.load(self, interconnect);
+        let m = am.read(self, interconnect);
         self.registers.a = a ^ m;
         self.registers.p.set(ProcessorFlags::ZERO, self.registers.a == 0);
         self.registers.p.set(ProcessorFlags::NEGATIVE, self.registers.a & (1 << 7) != 0);
@@ -650,7 +1078,7 @@ impl Cpu {
 
     fn ora<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
         let a = self.registers.a;
-        let m = am.load(self, interconnect);
+        let m = am.read(self, interconnect);
         self.registers.a = a | m;
         self.registers.p.set(ProcessorFlags::ZERO, self.registers.a == 0);
         self.registers.p.set(ProcessorFlags::NEGATIVE, self.registers.a & (1 << 7) != 0);
@@ -678,7 +1106,7 @@ impl Cpu {
 
     fn sbc<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
         let a = self.registers.a;
-        let m = am.load(self, interconnect);
+        let m = am.read(self, interconnect);
         let c = if self.registers.p.contains(ProcessorFlags::CARRY) { 1 } else { 0 };
         let result = a.wrapping_add(m).wrapping_add(c);
         self.registers.a = result;
@@ -768,19 +1196,19 @@ impl Cpu {
 
     // Loads
     fn lda<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
-        self.registers.a = am.load(self, interconnect);
+        self.registers.a = am.read(self, interconnect);
         self.registers.p.set(ProcessorFlags::ZERO, self.registers.a == 0);
         self.registers.p.set(ProcessorFlags::NEGATIVE, self.registers.a & (1 << 7) != 0 );
     }
 
     fn ldx<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
-        self.registers.x = am.load(self, interconnect);
+        self.registers.x = am.read(self, interconnect);
         self.registers.p.set(ProcessorFlags::ZERO, self.registers.x == 0);
         self.registers.p.set(ProcessorFlags::NEGATIVE, self.registers.x & (1 << 7) != 0);
     }
 
     fn ldy<AM: AddressingMode>(&mut self, interconnect: &mut Interconnect, am: AM) {
-        self.registers.y = am.load(self, interconnect);
+        self.registers.y = am.read(self, interconnect);
         self.registers.p.set(ProcessorFlags::ZERO, self.registers.y == 0);
         self.registers.p.set(ProcessorFlags::NEGATIVE, self.registers.y & (1 << 7) != 0);
     }
@@ -816,23 +1244,37 @@ impl Cpu {
         } else {
             self.fetch_word(interconnect, addr)
         };
+        interconnect.log_cdl(final_addr, cdl::INDIRECT_CODE);
         self.registers.pc = final_addr;
     }
 
+    // The byte after BRK is skipped, so it can be used as a signature
+    fn brk(&mut self, interconnect: &mut Interconnect) {
+        let return_addr = self.registers.pc + 1;
+        self.interrupt(interconnect, Interrupt::Brk, return_addr);
+    }
+
     fn jsr(&mut self, interconnect: &mut Interconnect) {
         let target = self.load_next_word_bump_pc(interconnect);
         let addr = self.registers.pc - 1;
         self.stack_push_word(addr);
         self.registers.pc = target;
+        let call_pc = self.instruction_pc;
+        self.push_frame(FrameKind::Jsr, call_pc, target, addr + 1);
     }
 
     fn rts(&mut self) {
+        let s = self.registers.s;
         self.registers.pc = self.stack_pop_word() + 1;
+        let pc = self.registers.pc;
+        self.pop_frame(s, pc);
     }
 
     fn rti(&mut self) {
+        let s = self.registers.s;
         let p = self.stack_pop_byte();
         let pc = self.stack_pop_word();
+        self.pop_frame(s, pc);
         self.registers.p = ProcessorFlags::from_bits(p).unwrap();
         self.registers.p.set(ProcessorFlags::ALWAYS_SET, true);
         self.registers.pc = pc;
@@ -850,9 +1292,75 @@ impl fmt::Debug for Cpu {
     }
 }
 
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.kind, self.old_val) {
+            (WatchKind::Read, _) => write!(f, "Watchpoint {}: PC ${:04X} read ${:04X} = {:02X}",
+                                           self.key, self.pc, self.addr, self.new_val),
+            (_, Some(old_val)) => write!(f, "Watchpoint {}: PC ${:04X} wrote ${:04X}: {:02X} -> {:02X}",
+                                         self.key, self.pc, self.addr, old_val, self.new_val),
+            (_, None) => write!(f, "Watchpoint {}: PC ${:04X} wrote ${:04X}: ?? -> {:02X}",
+                                self.key, self.pc, self.addr, self.new_val),
+        }
+    }
+}
+
 impl fmt::Debug for Registers {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
                self.a, self.x, self.y, self.p, self.s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cart::Cartridge;
+    use rom::test_rom;
+    use super::*;
+
+    #[test]
+    fn page_crossing_cycles() {
+        // LDX #$20, LDA $C0F0,X, STA $02F0,X, LDA ($00),Y
+        let program = [0xA2, 0x20, 0xBD, 0xF0, 0xC0, 0x9D, 0xF0, 0x02, 0xB1, 0x00];
+        let mut cart = Cartridge::new(test_rom(&program)).unwrap();
+        let mut interconnect = Interconnect::new(&mut cart);
+        let mut cpu = Cpu::new();
+        cpu.reset(&mut interconnect);
+        cpu.set_y(0x10);
+        cpu.poke_byte(&mut interconnect, 0x0000, 0xF8);
+
+        let mut cycles = Vec::new();
+        for _ in 0..4 {
+            let start = cpu.cycles();
+            cpu.step(&mut interconnect);
+            cycles.push((cpu.cycles() - start) / 3);
+        }
+        // Reads pay for crossing a page, stores always take the extra cycle
+        assert_eq!(cycles, [2, 5, 5, 6]);
+    }
+
+    #[test]
+    fn oam_dma() {
+        // LDA #$02, STA $4014
+        let program = [0xA9, 0x02, 0x8D, 0x14, 0x40];
+        let mut cart = Cartridge::new(test_rom(&program)).unwrap();
+        let mut interconnect = Interconnect::new(&mut cart);
+        let mut cpu = Cpu::new();
+        cpu.reset(&mut interconnect);
+        for i in 0..0x100 {
+            cpu.poke_byte(&mut interconnect, 0x0200 + i, !i as u8);
+        }
+        interconnect.ppu.poke_register(0x2003, 0x10);
+
+        cpu.step(&mut interconnect);
+        let start = cpu.cycles();
+        cpu.step(&mut interconnect);
+        // The copy starts at OAMADDR and wraps around
+        assert_eq!(interconnect.ppu.oam()[0x10], 0xFF);
+        assert_eq!(interconnect.ppu.oam()[0x0F], 0x00);
+        assert_eq!(interconnect.ppu.oam_dma(), 0x02);
+        // Four for the store, then one more from an odd cycle
+        assert!([4 + 513, 4 + 514].contains(&((cpu.cycles() - start) / 3)));
+        assert_eq!(interconnect.events.frames(0).1[0].kind, ::events::EventKind::PpuWrite);
+    }
+}
@@ -0,0 +1,216 @@
+//! Breakpoints on PC, interrupts, opcodes and PPU events, with optional
+//! conditions, hit counts and ignore counts
+
+use std::fmt;
+
+use cpu::Interrupt;
+use disasm;
+use nes::Nes;
+use super::expr::Expr;
+use super::VBLANK_START;
+
+const DOTS_PER_SCANLINE: usize = 341;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum BreakKind {
+    Address(u16),
+    Interrupt(Interrupt), // Stops on the handler's first instruction
+    Opcode(u8),
+    Mnemonic(String), // Any opcode disassembling to it, e.g. KIL
+    Unofficial,
+    Scanline(u16, u16), // Scanline and dot
+    VBlank,
+    RegisterWrite(u16), // Before a store to a PPU or APU register
+}
+
+// PPU state from before the last instruction, to spot events that happened during it
+#[derive(Debug, Clone, Copy)]
+pub struct PpuState {
+    frame: usize,
+    scanline: u16,
+    dot: u16,
+}
+
+impl PpuState {
+    pub fn new(nes: &Nes) -> Self {
+        let ppu = &nes.interconnect.ppu;
+        PpuState {
+            frame: ppu.frame(),
+            scanline: ppu.scanline(),
+            dot: ppu.dot(),
+        }
+    }
+
+    fn dots(&self) -> usize {
+        self.scanline as usize * DOTS_PER_SCANLINE + self.dot as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+    pub kind: BreakKind,
+    pub condition: Option<Expr>,
+    pub hit_count: usize,
+    pub ignore_count: usize, // Hits left to skip before stopping
+}
+
+impl Breakpoint {
+    pub fn new(kind: BreakKind, condition: Option<Expr>) -> Self {
+        Breakpoint {
+            kind: kind,
+            condition: condition,
+            hit_count: 0,
+            ignore_count: 0,
+        }
+    }
+
+    // Checked before each instruction, with the PPU state from before the one
+    // that just ran
+    pub fn triggered(&self, nes: &Nes, before: &PpuState) -> bool {
+        let pc = nes.cpu.pc();
+        let now = PpuState::new(nes);
+        match self.kind {
+            BreakKind::Address(addr) => pc == addr,
+            BreakKind::Interrupt(interrupt) => nes.cpu.entered_interrupt() == Some(interrupt),
+            BreakKind::Opcode(opcode) => nes.peek_byte(pc) == opcode,
+            BreakKind::Mnemonic(ref mnemonic) => {
+                let instruction = disasm::decode(pc, |addr| nes.peek_byte(addr));
+                instruction.mnemonic.trim_start_matches('*') == mnemonic
+            }
+            BreakKind::Unofficial => !disasm::is_official(nes.peek_byte(pc)),
+            BreakKind::Scanline(scanline, dot) => {
+                let target = PpuState { scanline: scanline, dot: dot, ..now }.dots();
+                if now.frame == before.frame {
+                    before.dots() < target && target <= now.dots()
+                } else {
+                    before.dots() < target || target <= now.dots()
+                }
+            }
+            BreakKind::VBlank => before.scanline < VBLANK_START && now.scanline >= VBLANK_START,
+            BreakKind::RegisterWrite(register) => {
+                let instruction = disasm::decode(pc, |addr| nes.peek_byte(addr));
+                let addr = instruction.effective_addr(nes.cpu.x(), nes.cpu.y(), |addr| nes.peek_byte(addr));
+                instruction.writes_memory() && addr.map(register_of) == Some(register)
+            }
+        }
+    }
+
+    // Called once the breakpoint triggers. Counts the hit if the condition holds
+    // and returns whether emulation should stop.
+    pub fn hit(&mut self, nes: &Nes) -> bool {
+        if let Some(ref condition) = self.condition {
+            if !condition.is_true(nes) {
+                return false;
+            }
+        }
+        self.hit_count += 1;
+        if self.ignore_count > 0 {
+            self.ignore_count -= 1;
+            return false;
+        }
+        true
+    }
+}
+
+// PPU registers are mirrored every 8 bytes up to $3FFF
+pub fn register_of(addr: u16) -> u16 {
+    match addr {
+        0x2000...0x3FFF => 0x2000 + addr % 8,
+        _ => addr,
+    }
+}
+
+// PPU registers and their mirrors run straight into the APU's
+pub fn is_register(addr: u16) -> bool {
+    (0x2000..=0x4017).contains(&addr)
+}
+
+impl fmt::Display for BreakKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BreakKind::Address(addr) => write!(f, "${:04X}", addr),
+            BreakKind::Interrupt(Interrupt::Nmi) => write!(f, "nmi"),
+            BreakKind::Interrupt(Interrupt::Irq) => write!(f, "irq"),
+            BreakKind::Interrupt(Interrupt::Brk) => write!(f, "brk"),
+            BreakKind::Opcode(opcode) => write!(f, "op ${:02X}", opcode),
+            BreakKind::Mnemonic(ref mnemonic) => write!(f, "op {}", mnemonic),
+            BreakKind::Unofficial => write!(f, "op unofficial"),
+            BreakKind::Scanline(scanline, dot) => write!(f, "scanline {} dot {}", scanline, dot),
+            BreakKind::VBlank => write!(f, "vblank"),
+            BreakKind::RegisterWrite(register) => write!(f, "write ${:04X}", register),
+        }
+    }
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(ref condition) = self.condition {
+            write!(f, " if {}", condition)?;
+        }
+        write!(f, "\thits: {}", self.hit_count)?;
+        if self.ignore_count > 0 {
+            write!(f, " (ignoring next {})", self.ignore_count)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use cart::Cartridge;
+    use nes::StopReason;
+    use rom::test_rom;
+    use super::*;
+
+    // LDA #$1E, then STA $2009 and INC $10 forever
+    const PROGRAM: [u8; 10] = [0xA9, 0x1E, 0x8D, 0x09, 0x20, 0xE6, 0x10, 0x4C, 0x02, 0xC0];
+
+    fn run(nes: &mut Nes, kind: BreakKind) -> StopReason {
+        let mut breakpoints = HashMap::new();
+        breakpoints.insert(1, Breakpoint::new(kind, None));
+        nes.run_until(Some(&mut breakpoints), |_| false)
+    }
+
+    #[test]
+    fn scanline_catch() {
+        let mut cart = Cartridge::new(test_rom(&PROGRAM)).unwrap();
+        let mut nes = Nes::new(&mut cart, None);
+        nes.reset();
+        for &(scanline, dot) in &[(100, 200), (0, 0), (261, 340)] {
+            assert_eq!(run(&mut nes, BreakKind::Scanline(scanline, dot)), StopReason::Breakpoint(1));
+            // Stops on the first instruction boundary at or past the dot, and the
+            // longest instruction here is six cycles
+            let ppu = &nes.interconnect.ppu;
+            let past = (ppu.scanline() as usize * DOTS_PER_SCANLINE + ppu.dot() as usize + 262 * DOTS_PER_SCANLINE
+                - scanline as usize * DOTS_PER_SCANLINE - dot as usize) % (262 * DOTS_PER_SCANLINE);
+            assert!(past < 18, "{}:{} for {}:{}", ppu.scanline(), ppu.dot(), scanline, dot);
+        }
+    }
+
+    #[test]
+    fn vblank_catch() {
+        let mut cart = Cartridge::new(test_rom(&PROGRAM)).unwrap();
+        let mut nes = Nes::new(&mut cart, None);
+        nes.reset();
+        for frame in 0..2 {
+            assert_eq!(run(&mut nes, BreakKind::VBlank), StopReason::Breakpoint(1));
+            assert_eq!((nes.interconnect.ppu.frame(), nes.interconnect.ppu.scanline()), (frame, VBLANK_START));
+        }
+    }
+
+    #[test]
+    fn register_write_catch() {
+        let mut cart = Cartridge::new(test_rom(&PROGRAM)).unwrap();
+        let mut nes = Nes::new(&mut cart, None);
+        nes.reset();
+        // Mirrors count as the register, and the stop comes before the store
+        assert_eq!(run(&mut nes, BreakKind::RegisterWrite(0x2001)), StopReason::Breakpoint(1));
+        assert_eq!(nes.cpu.pc(), 0xC002);
+        assert_eq!(nes.interconnect.ppu.mask(), 0x00);
+        nes.step();
+        assert_eq!(nes.interconnect.ppu.mask(), 0x1E);
+    }
+}
@@ -0,0 +1,193 @@
+//! Expressions used by conditional breakpoints, e.g. `break C000 if A == $10 && [$0300] != 0`
+
+use std::fmt;
+
+use nes::Nes;
+use symbols::Symbols;
+
+#[derive(Debug, Clone)]
+pub enum Expr {
+    Number(i64),
+    Variable(Variable),
+    Label(String),
+    Memory(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Variable {
+    A,
+    X,
+    Y,
+    S,
+    P,
+    Pc,
+    // Individual flags of P, 0 or 1
+    Carry,
+    Zero,
+    Interrupt,
+    Decimal,
+    Overflow,
+    Negative,
+    // PPU timing
+    Scanline,
+    Dot,
+    Frame,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum UnaryOp {
+    Not,
+    Neg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Add,
+    Sub,
+}
+
+impl Expr {
+    pub fn eval(&self, nes: &Nes) -> i64 {
+        match *self {
+            Expr::Number(n) => n,
+            Expr::Variable(var) => var.eval(nes),
+            Expr::Label(ref name) => label_addr(&nes.symbols, name).unwrap_or(0) as i64,
+            Expr::Memory(ref addr) => nes.peek_byte(addr.eval(nes) as u16) as i64,
+            Expr::Unary(UnaryOp::Not, ref e) => (e.eval(nes) == 0) as i64,
+            Expr::Unary(UnaryOp::Neg, ref e) => -e.eval(nes),
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                let lhs = lhs.eval(nes);
+                // Short circuit the logical operators
+                match op {
+                    BinaryOp::Or if lhs != 0 => return 1,
+                    BinaryOp::And if lhs == 0 => return 0,
+                    _ => {}
+                }
+                let rhs = rhs.eval(nes);
+                match op {
+                    BinaryOp::Or | BinaryOp::And => (rhs != 0) as i64,
+                    BinaryOp::Eq => (lhs == rhs) as i64,
+                    BinaryOp::Ne => (lhs != rhs) as i64,
+                    BinaryOp::Lt => (lhs < rhs) as i64,
+                    BinaryOp::Le => (lhs <= rhs) as i64,
+                    BinaryOp::Gt => (lhs > rhs) as i64,
+                    BinaryOp::Ge => (lhs >= rhs) as i64,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitXor => lhs ^ rhs,
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Sub => lhs.wrapping_sub(rhs),
+                }
+            }
+        }
+    }
+
+    pub fn is_true(&self, nes: &Nes) -> bool {
+        self.eval(nes) != 0
+    }
+
+    // First label used by the expression that isn't in symbols
+    pub fn unknown_label(&self, symbols: &Symbols) -> Option<&str> {
+        match *self {
+            Expr::Label(ref name) if label_addr(symbols, name).is_none() => Some(name),
+            Expr::Memory(ref e) | Expr::Unary(_, ref e) => e.unknown_label(symbols),
+            Expr::Binary(_, ref lhs, ref rhs) => lhs.unknown_label(symbols).or_else(|| rhs.unknown_label(symbols)),
+            _ => None,
+        }
+    }
+}
+
+// Like addresses in commands, names that aren't labels are read as hex
+fn label_addr(symbols: &Symbols, name: &str) -> Option<u16> {
+    symbols.addr(name).or_else(|| u16::from_str_radix(name, 16).ok())
+}
+
+impl Variable {
+    pub fn from_name(name: &str) -> Option<Variable> {
+        use self::Variable::*;
+        match name.to_uppercase().as_str() {
+            "A" => Some(A),
+            "X" => Some(X),
+            "Y" => Some(Y),
+            "S" | "SP" => Some(S),
+            "P" => Some(P),
+            "PC" => Some(Pc),
+            "C" => Some(Carry),
+            "Z" => Some(Zero),
+            "I" => Some(Interrupt),
+            "D" => Some(Decimal),
+            "V" => Some(Overflow),
+            "N" => Some(Negative),
+            "SCANLINE" | "SL" => Some(Scanline),
+            "DOT" | "CYCLE" => Some(Dot),
+            "FRAME" => Some(Frame),
+            _ => None,
+        }
+    }
+
+    fn eval(&self, nes: &Nes) -> i64 {
+        use self::Variable::*;
+        let flag = |bit: u8| ((nes.cpu.p() >> bit) & 1) as i64;
+        match *self {
+            A => nes.cpu.a() as i64,
+            X => nes.cpu.x() as i64,
+            Y => nes.cpu.y() as i64,
+            S => nes.cpu.s() as i64,
+            P => nes.cpu.p() as i64,
+            Pc => nes.cpu.pc() as i64,
+            Carry => flag(0),
+            Zero => flag(1),
+            Interrupt => flag(2),
+            Decimal => flag(3),
+            Overflow => flag(6),
+            Negative => flag(7),
+            Scanline => nes.interconnect.ppu.scanline() as i64,
+            Dot => nes.interconnect.ppu.dot() as i64,
+            Frame => nes.interconnect.ppu.frame() as i64,
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Expr::Number(n) => write!(f, "${:X}", n),
+            Expr::Variable(var) => write!(f, "{:?}", var),
+            Expr::Label(ref name) => write!(f, "{}", name),
+            Expr::Memory(ref addr) => write!(f, "[{}]", addr),
+            Expr::Unary(UnaryOp::Not, ref e) => write!(f, "!{}", e),
+            Expr::Unary(UnaryOp::Neg, ref e) => write!(f, "-{}", e),
+            Expr::Binary(op, ref lhs, ref rhs) => {
+                let op = match op {
+                    BinaryOp::Or => "||",
+                    BinaryOp::And => "&&",
+                    BinaryOp::Eq => "==",
+                    BinaryOp::Ne => "!=",
+                    BinaryOp::Lt => "<",
+                    BinaryOp::Le => "<=",
+                    BinaryOp::Gt => ">",
+                    BinaryOp::Ge => ">=",
+                    BinaryOp::BitAnd => "&",
+                    BinaryOp::BitOr => "|",
+                    BinaryOp::BitXor => "^",
+                    BinaryOp::Add => "+",
+                    BinaryOp::Sub => "-",
+                };
+                write!(f, "({} {} {})", lhs, op, rhs)
+            }
+        }
+    }
+}
@@ -0,0 +1,508 @@
+//! GDB remote serial protocol stub so external debuggers can drive the CPU.
+//! Registers are sent in the order A X Y S P (one byte each) then PC (two bytes,
+//! little endian), as described by the target.xml sent to clients.
+
+use std::collections::HashMap;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use cpu::{Watchpoint, WatchKind};
+use nes::{Nes, StopReason};
+use super::{BreakKind, Breakpoint};
+
+// How many instructions run between checks for an interrupt from the client
+const INTERRUPT_POLL_INTERVAL: usize = 1024;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.gadgetnes.6502">
+    <reg name="a" bitsize="8" regnum="0"/>
+    <reg name="x" bitsize="8"/>
+    <reg name="y" bitsize="8"/>
+    <reg name="s" bitsize="8"/>
+    <reg name="p" bitsize="8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>"#;
+
+pub struct GdbStub<'a> {
+    nes: Nes<'a>,
+    breakpoints: HashMap<usize, Breakpoint>, // Keyed by address
+    watchpoints: HashMap<(u8, u16, u16), usize>, // (Z type, address, length) to Cpu key
+    next_watch_key: usize,
+    no_ack: bool,
+}
+
+// What to do after handling a packet
+enum Action {
+    Reply(String),
+    Detach,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(mut nes: Nes<'a>) -> Self {
+        nes.reset();
+        GdbStub {
+            nes,
+            breakpoints: HashMap::new(),
+            watchpoints: HashMap::new(),
+            next_watch_key: 1,
+            no_ack: false,
+        }
+    }
+
+    // Serves a single client on localhost until it detaches or disconnects
+    pub fn listen(&mut self, port: u16) -> io::Result<()> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for GDB on 127.0.0.1:{}", port);
+        let (stream, addr) = listener.accept()?;
+        println!("GDB connected from {}", addr);
+        stream.set_nodelay(true)?;
+        let result = self.serve(stream);
+        self.nes.save();
+        result
+    }
+
+    fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        self.no_ack = false;
+        // Everything is read through one buffer, replies are written to the stream it wraps
+        let mut reader = BufReader::new(stream);
+        loop {
+            let packet = match self.read_packet(&mut reader)? {
+                Some(packet) => packet,
+                None => return Ok(()), // Disconnected
+            };
+            match self.handle(&packet, &mut reader) {
+                Action::Reply(reply) => self.write_packet(reader.get_mut(), &reply)?,
+                Action::Detach => {
+                    self.write_packet(reader.get_mut(), "OK")?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    // Returns the packet data once its checksum is verified, skipping acks and
+    // interrupts that arrive while the CPU is already stopped
+    fn read_packet(&self, reader: &mut BufReader<TcpStream>) -> io::Result<Option<Vec<u8>>> {
+        loop {
+            let mut bytes = reader.by_ref().bytes();
+            match bytes.next() {
+                None => return Ok(None),
+                Some(Err(e)) => return Err(e),
+                Some(Ok(b'$')) => {}
+                Some(Ok(_)) => continue,
+            }
+            let mut data = Vec::new();
+            let mut checksum: u8 = 0;
+            loop {
+                match bytes.next() {
+                    None => return Ok(None),
+                    Some(Err(e)) => return Err(e),
+                    Some(Ok(b'#')) => break,
+                    Some(Ok(b)) => {
+                        checksum = checksum.wrapping_add(b);
+                        data.push(b);
+                    }
+                }
+            }
+            let mut expected = [0u8; 2];
+            for digit in expected.iter_mut() {
+                match bytes.next() {
+                    None => return Ok(None),
+                    Some(Err(e)) => return Err(e),
+                    Some(Ok(b)) => *digit = b,
+                }
+            }
+            let expected = ::std::str::from_utf8(&expected).ok().and_then(|s| u8::from_str_radix(s, 16).ok());
+            if self.no_ack {
+                return Ok(Some(unescape(&data)));
+            }
+            if expected == Some(checksum) {
+                reader.get_mut().write_all(b"+")?;
+                return Ok(Some(unescape(&data)));
+            }
+            reader.get_mut().write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&self, stream: &mut TcpStream, data: &str) -> io::Result<()> {
+        let mut packet = vec![b'$'];
+        for &b in data.as_bytes() {
+            match b {
+                b'#' | b'$' | b'}' | b'*' => packet.extend_from_slice(&[b'}', b ^ 0x20]),
+                _ => packet.push(b),
+            }
+        }
+        let checksum = packet[1..].iter().fold(0u8, |sum, &b| sum.wrapping_add(b));
+        packet.extend_from_slice(format!("#{:02x}", checksum).as_bytes());
+        stream.write_all(&packet)
+        // Acks from the client are skipped by read_packet
+    }
+
+    fn handle(&mut self, packet: &[u8], reader: &mut BufReader<TcpStream>) -> Action {
+        let packet = String::from_utf8_lossy(packet).into_owned();
+        let (command, args) = packet.split_at(if packet.is_empty() { 0 } else { 1 });
+        let reply = match command {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "c" => self.resume(args, false, reader),
+            "s" => self.resume(args, true, reader),
+            "Z" => self.insert_point(args),
+            "z" => self.remove_point(args),
+            "H" => "OK".to_owned(),
+            "D" | "k" => return Action::Detach,
+            "q" | "Q" | "v" => self.query(&packet),
+            _ => String::new(), // Unsupported
+        };
+        Action::Reply(reply)
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+;QStartNoAckMode+".to_owned()
+        } else if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            "OK".to_owned()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            read_chunk(TARGET_XML, args)
+        } else if packet == "qAttached" {
+            "1".to_owned()
+        } else if packet == "qC" {
+            "QC1".to_owned()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_owned()
+        } else if packet == "qsThreadInfo" {
+            "l".to_owned()
+        } else {
+            String::new()
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        let cpu = &self.nes.cpu;
+        let pc = cpu.pc();
+        format!("{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}", cpu.a(), cpu.x(), cpu.y(), cpu.s(), cpu.p(), pc as u8, pc >> 8)
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        match decode_hex(args) {
+            Some(ref bytes) if bytes.len() == 7 => {
+                let cpu = &mut self.nes.cpu;
+                cpu.set_a(bytes[0]);
+                cpu.set_x(bytes[1]);
+                cpu.set_y(bytes[2]);
+                cpu.set_s(bytes[3]);
+                cpu.set_p(bytes[4]);
+                cpu.set_pc(bytes[5] as u16 | (bytes[6] as u16) << 8);
+                "OK".to_owned()
+            }
+            _ => "E01".to_owned(),
+        }
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        let cpu = &self.nes.cpu;
+        match usize::from_str_radix(args, 16) {
+            Ok(0) => format!("{:02x}", cpu.a()),
+            Ok(1) => format!("{:02x}", cpu.x()),
+            Ok(2) => format!("{:02x}", cpu.y()),
+            Ok(3) => format!("{:02x}", cpu.s()),
+            Ok(4) => format!("{:02x}", cpu.p()),
+            Ok(5) => format!("{:02x}{:02x}", cpu.pc() as u8, cpu.pc() >> 8),
+            _ => "E01".to_owned(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let reg = parts.next().and_then(|reg| usize::from_str_radix(reg, 16).ok());
+        let val = parts.next().and_then(decode_hex);
+        let cpu = &mut self.nes.cpu;
+        match (reg, val) {
+            (Some(0), Some(ref val)) if val.len() == 1 => cpu.set_a(val[0]),
+            (Some(1), Some(ref val)) if val.len() == 1 => cpu.set_x(val[0]),
+            (Some(2), Some(ref val)) if val.len() == 1 => cpu.set_y(val[0]),
+            (Some(3), Some(ref val)) if val.len() == 1 => cpu.set_s(val[0]),
+            (Some(4), Some(ref val)) if val.len() == 1 => cpu.set_p(val[0]),
+            (Some(5), Some(ref val)) if val.len() == 2 => cpu.set_pc(val[0] as u16 | (val[1] as u16) << 8),
+            _ => return "E01".to_owned(),
+        }
+        "OK".to_owned()
+    }
+
+    // m addr,length
+    fn read_memory(&self, args: &str) -> String {
+        match parse_addr_len(args) {
+            Some((addr, len)) => (addr..addr + len)
+                .map(|addr| format!("{:02x}", self.nes.peek_byte(addr as u16)))
+                .collect(),
+            None => "E01".to_owned(),
+        }
+    }
+
+    // M addr,length:XX...
+    fn write_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        match (parts.next().and_then(parse_addr_len), parts.next().and_then(decode_hex)) {
+            (Some((addr, len)), Some(ref bytes)) if bytes.len() == len => {
+                for (i, &val) in bytes.iter().enumerate() {
+                    self.nes.cpu.poke_byte(&mut self.nes.interconnect, (addr + i) as u16, val);
+                }
+                "OK".to_owned()
+            }
+            _ => "E01".to_owned(),
+        }
+    }
+
+    // c [addr] and s [addr]
+    fn resume(&mut self, args: &str, single_step: bool, reader: &mut BufReader<TcpStream>) -> String {
+        if !args.is_empty() {
+            match u16::from_str_radix(args, 16) {
+                Ok(addr) => self.nes.cpu.set_pc(addr),
+                Err(_) => return "E01".to_owned(),
+            }
+        }
+        let reason = if single_step {
+            self.nes.step();
+            if self.nes.cpu.has_watch_hits() { StopReason::Watchpoint } else { StopReason::Finished }
+        } else {
+            // Nes::run polls SDL, the client interrupts by sending 0x03
+            if reader.get_ref().set_nonblocking(true).is_err() {
+                return "E01".to_owned();
+            }
+            let mut instructions = 0;
+            let mut interrupted = false;
+            let reason = self.nes.run_until(Some(&mut self.breakpoints), |_| {
+                instructions += 1;
+                if instructions % INTERRUPT_POLL_INTERVAL == 0 {
+                    let mut buf = [0u8; 1];
+                    match reader.read(&mut buf) {
+                        Ok(0) => interrupted = true, // Disconnected
+                        Ok(_) => interrupted = buf[0] == 0x03,
+                        Err(_) => {}
+                    }
+                }
+                interrupted
+            });
+            if reader.get_ref().set_nonblocking(false).is_err() {
+                return "E01".to_owned();
+            }
+            if reason == StopReason::Finished { StopReason::Interrupted } else { reason }
+        };
+        self.stop_reply(reason)
+    }
+
+    fn stop_reply(&mut self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Exited => "W00".to_owned(),
+            StopReason::Interrupted => format!("S{:02x}", SIGINT),
+            StopReason::Watchpoint => {
+                let hits = self.nes.cpu.take_watch_hits();
+                let watchpoints = self.nes.cpu.watchpoints();
+                match hits.first().and_then(|hit| watchpoints.get(&hit.key).map(|w| (w.kind, hit.addr))) {
+                    Some((kind, addr)) => {
+                        let kind = match kind {
+                            WatchKind::Write => "watch",
+                            WatchKind::Read => "rwatch",
+                            WatchKind::ReadWrite => "awatch",
+                        };
+                        format!("T{:02x}{}:{:04x};", SIGTRAP, kind, addr)
+                    }
+                    None => format!("S{:02x}", SIGTRAP),
+                }
+            }
+            StopReason::Breakpoint(_) | StopReason::Finished => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    // Z type,addr,kind. Types 0 and 1 are breakpoints, 2-4 are write, read and access watchpoints.
+    fn insert_point(&mut self, args: &str) -> String {
+        match parse_point(args) {
+            Some((0, addr, _)) | Some((1, addr, _)) => {
+                self.breakpoints.insert(addr as usize, Breakpoint::new(BreakKind::Address(addr), None));
+            }
+            Some((kind @ 2...4, addr, len)) if len > 0 => {
+                let watchpoint = Watchpoint {
+                    kind: match kind {
+                        2 => WatchKind::Write,
+                        3 => WatchKind::Read,
+                        _ => WatchKind::ReadWrite,
+                    },
+                    low_addr: addr,
+                    high_addr: addr.saturating_add(len - 1),
+                };
+                self.nes.cpu.add_watchpoint(self.next_watch_key, watchpoint);
+                self.watchpoints.insert((kind, addr, len), self.next_watch_key);
+                self.next_watch_key += 1;
+            }
+            Some(_) => return String::new(), // Unsupported type
+            None => return "E01".to_owned(),
+        }
+        "OK".to_owned()
+    }
+
+    fn remove_point(&mut self, args: &str) -> String {
+        match parse_point(args) {
+            Some((0, addr, _)) | Some((1, addr, _)) => {
+                self.breakpoints.remove(&(addr as usize));
+            }
+            Some((kind @ 2...4, addr, len)) => {
+                if let Some(key) = self.watchpoints.remove(&(kind, addr, len)) {
+                    self.nes.cpu.remove_watchpoint(key);
+                }
+            }
+            Some(_) => return String::new(),
+            None => return "E01".to_owned(),
+        }
+        "OK".to_owned()
+    }
+}
+
+// addr,length within the CPU address space
+fn parse_addr_len(args: &str) -> Option<(usize, usize)> {
+    match parse_offset_len(args) {
+        Some((addr, len)) if addr.checked_add(len).is_some_and(|end| end <= 0x10000) => Some((addr, len)),
+        _ => None,
+    }
+}
+
+fn parse_offset_len(args: &str) -> Option<(usize, usize)> {
+    let mut parts = args.splitn(2, ',');
+    Some((usize::from_str_radix(parts.next()?, 16).ok()?, usize::from_str_radix(parts.next()?, 16).ok()?))
+}
+
+fn parse_point(args: &str) -> Option<(u8, u16, u16)> {
+    let mut parts = args.split(',');
+    let kind = parts.next()?.parse().ok()?;
+    let addr = u16::from_str_radix(parts.next()?, 16).ok()?;
+    let len = u16::from_str_radix(parts.next()?, 16).ok()?;
+    Some((kind, addr, len))
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect()
+}
+
+// Replies to qXfer reads of offset,length with 'm' if more data follows, otherwise 'l'
+fn read_chunk(data: &str, args: &str) -> String {
+    match parse_offset_len(args) {
+        Some((offset, _)) if offset >= data.len() => "l".to_owned(),
+        Some((offset, len)) if offset.checked_add(len).is_none_or(|end| end >= data.len()) => {
+            format!("l{}", &data[offset..])
+        }
+        Some((offset, len)) => format!("m{}", &data[offset..offset + len]),
+        None => "E01".to_owned(),
+    }
+}
+
+// '}' escapes the next byte XORed with 0x20
+fn unescape(data: &[u8]) -> Vec<u8> {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&b) = bytes.next() {
+        match b {
+            b'}' => if let Some(&escaped) = bytes.next() { unescaped.push(escaped ^ 0x20) },
+            _ => unescaped.push(b),
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use cart::Cartridge;
+    use rom::test_rom;
+    use super::*;
+
+    // LDA #$01, STA $10, then INC $10 forever from $C004, where the CPU resets to
+    const PROGRAM: [u8; 9] = [0xA9, 0x01, 0x85, 0x10, 0xE6, 0x10, 0x4C, 0x04, 0xC0];
+
+    fn send(stream: &mut BufReader<TcpStream>, data: &str) -> String {
+        let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        stream.get_mut().write_all(format!("${}#{:02x}", data, checksum).as_bytes()).unwrap();
+        receive(stream)
+    }
+
+    // Skips acks and returns the data of the next packet
+    fn receive(stream: &mut BufReader<TcpStream>) -> String {
+        let mut bytes = stream.bytes().map(|b| b.unwrap());
+        loop {
+            match bytes.next() {
+                Some(b'$') => break,
+                Some(_) => {}
+                None => panic!("Stub disconnected"),
+            }
+        }
+        let data: Vec<u8> = bytes.by_ref().take_while(|&b| b != b'#').collect();
+        assert!(bytes.next().is_some() && bytes.next().is_some(), "Missing checksum");
+        String::from_utf8(data).unwrap()
+    }
+
+    fn client(port: u16) {
+        let mut stream = BufReader::new(TcpStream::connect(("127.0.0.1", port)).unwrap());
+
+        assert!(send(&mut stream, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+        assert!(send(&mut stream, "qXfer:features:read:target.xml:1,ffffffffffffffff").starts_with("l?xml"));
+
+        // Registers are A X Y S P then PC little endian, after reset PC is $C000
+        let registers = send(&mut stream, "g");
+        assert_eq!(registers.len(), 14);
+        assert!(registers.ends_with("00c0"));
+        assert_eq!(send(&mut stream, "G112233fc2400c0"), "OK");
+        assert_eq!(send(&mut stream, "g"), "112233fc2400c0");
+        assert_eq!(send(&mut stream, "G11"), "E01");
+
+        assert_eq!(send(&mut stream, "M300,2:abcd"), "OK");
+        assert_eq!(send(&mut stream, "m300,2"), "abcd");
+        assert_eq!(send(&mut stream, "m0b00,1"), "ab"); // RAM mirror
+        assert_eq!(send(&mut stream, "mc000,3"), "a90185");
+        assert_eq!(send(&mut stream, "M2001,1:1e"), "OK"); // PPU registers are only latched
+        assert_eq!(send(&mut stream, "m2001,1"), "1e");
+        assert_eq!(send(&mut stream, "mffff,2"), "E01");
+        assert_eq!(send(&mut stream, "m1,ffffffffffffffff"), "E01");
+
+        assert_eq!(send(&mut stream, "s"), "S05");
+        assert!(send(&mut stream, "g").starts_with("01"));
+        assert_eq!(send(&mut stream, "Z0,c004,1"), "OK");
+        assert_eq!(send(&mut stream, "c"), "S05");
+        assert!(send(&mut stream, "g").ends_with("04c0"));
+        assert_eq!(send(&mut stream, "m10,1"), "01");
+        assert_eq!(send(&mut stream, "z0,c004,1"), "OK");
+
+        // Without the breakpoint only an interrupt stops the CPU
+        stream.get_mut().write_all(b"$c#63").unwrap();
+        thread::sleep(Duration::from_millis(100));
+        stream.get_mut().write_all(&[0x03]).unwrap();
+        assert_eq!(receive(&mut stream), "S02");
+        assert_eq!(send(&mut stream, "D"), "OK");
+    }
+
+    #[test]
+    fn remote_protocol() {
+        let mut cart = Cartridge::new(test_rom(&PROGRAM)).unwrap();
+        let mut stub = GdbStub::new(Nes::new(&mut cart, None));
+
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || client(port));
+        let (stream, _) = listener.accept().unwrap();
+        stub.serve(stream).unwrap();
+        client.join().unwrap();
+    }
+}
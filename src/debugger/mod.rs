@@ -1,14 +1,72 @@
-use std::io::{self, Write};
+use std::io::{self, BufRead, BufReader, Write};
 use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::path::{Path, PathBuf};
 
+use rustyline::Editor;
+use rustyline::error::ReadlineError;
+
+mod breakpoint;
+mod expr;
+mod gdb;
 mod parser;
+mod search;
+
+pub use self::breakpoint::{BreakKind, Breakpoint, PpuState};
+pub use self::gdb::GdbStub;
+
+use cpu::{FrameKind, Interrupt, Watchpoint, WatchKind};
+use disasm;
+use events::{Event, EventKind};
+use memory::Space;
+use self::breakpoint::{is_register, register_of};
+use self::expr::Expr;
+use nes::{Nes, StopReason};
+use self::parser::{Address, Command, Register};
+use self::search::{Comparison, RamSearch};
+use profile::Profiler;
+use trace::Trace;
+use views::{self, View};
+
+// Instructions listed by disasm when no count is given
+const DISASM_COUNT: usize = 16;
+// Instructions shown before PC when disassembling around it
+const DISASM_CONTEXT: usize = 5;
+
+const JSR: u8 = 0x20;
+const RTI: u8 = 0x40;
+const RTS: u8 = 0x60;
+
+const PROMPT: &str = "Gidget>";
+const HISTORY_FILE: &str = ".gidget_history";
 
-use nes::Nes;
-use self::parser::Command;
+// Addresses and routines printed by profile dump
+const PROFILE_DUMP_MAX: usize = 20;
+
+// Candidates printed by search list
+const SEARCH_LIST_MAX: usize = 64;
+
+// Events printed by the events command
+const EVENT_LIST_MAX: usize = 256;
+
+// How deep source can nest, so a script sourcing itself stops
+const SOURCE_DEPTH_MAX: usize = 8;
+
+// Scanlines bounding vblank, numbered as in Ppu::scanline
+const VBLANK_START: u16 = 241;
+const PRE_RENDER_LINE: u16 = 261;
+const LAST_DOT: u16 = 340;
 
 pub struct Debugger<'a> {
     nes: Nes<'a>,
-    pub breakpoints: HashMap<usize, usize>,
+    pub breakpoints: HashMap<usize, Breakpoint>,
+    next_key: usize, // Breakpoints and watchpoints share numbering
+    editor: Editor<()>,
+    last_input: Option<String>,
+    reported_desyncs: usize, // Call stack desyncs already printed
+    search: Option<RamSearch>,
+    source_depth: usize,
 }
 
 impl<'a> Debugger<'a> {
@@ -16,69 +74,375 @@ impl<'a> Debugger<'a> {
         let mut debugger = Debugger {
             nes: nes,
             breakpoints: HashMap::new(),
+            next_key: 1,
+            editor: Editor::<()>::new(),
+            last_input: None,
+            reported_desyncs: 0,
+            search: None,
+            source_depth: 0,
         };
+        // There is no history the first time
+        let _ = debugger.editor.load_history(&history_path());
         debugger.reset();
         debugger
     }
 
-    pub fn run(&mut self){
-        let mut next_key = 1;
+    pub fn run(&mut self) {
         loop {
-            print!("Gidget>");
-            io::stdout().flush().unwrap();
+            let input = match self.editor.readline(PROMPT) {
+                Ok(input) => input,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => "quit".to_owned(),
+                Err(e) => {
+                    println!("Failed to read command: {}", e);
+                    "quit".to_owned()
+                }
+            };
+            // An empty line repeats the last command if it's safe to, handy for stepping
+            let input = match (input.trim().is_empty(), self.last_input.clone()) {
+                (true, Some(last_input)) => last_input,
+                (true, None) => continue,
+                (false, _) => {
+                    self.editor.add_history_entry(input.trim());
+                    self.last_input = match input.trim().parse::<Command>() {
+                        Ok(ref command) if command.is_repeatable() => Some(input.trim().to_owned()),
+                        _ => None,
+                    };
+                    input
+                }
+            };
+            if !self.execute(&input) {
+                break;
+            }
+        }
+        if let Err(e) = self.editor.save_history(&history_path()) {
+            println!("Failed to save history: {}", e);
+        }
+    }
+
+    // Runs the commands in a file, skipping blank lines and # comments. Returns false
+    // if the script quit the debugger.
+    pub fn source(&mut self, path: &Path) -> bool {
+        if self.source_depth >= SOURCE_DEPTH_MAX {
+            println!("Not running {}, scripts are nested more than {} deep", path.display(), SOURCE_DEPTH_MAX);
+            return true;
+        }
+        self.source_depth += 1;
+        let result = self.run_script(path);
+        self.source_depth -= 1;
+        result
+    }
 
-            let mut input = String::new();
-            io::stdin().read_line(&mut input).unwrap();
-            let command = input.trim().parse::<Command>();
-            match command {
-                Ok(Command::Step(num_steps)) => self.step(num_steps),
-                Ok(Command::Run) => self.step_forever(),
-                Ok(Command::Breakpoint(addr)) => self.set_breakpoint(&mut next_key, addr as usize),
-                Ok(Command::ListBreakPoints) => self.list_breakpoints(),
-                Ok(Command::ClearBreakpoint(num)) => self.clear_bp(&num),
-                Ok(Command::Print(addr)) => self.print(addr as usize),
-                Ok(Command::PrintRange(low_addr, high_addr)) => self.print_range(low_addr as usize, high_addr as usize),
-                Ok(Command::Help) => self.help(),
-                Ok(Command::Quit) => break,
-                Err(ref e) => println!("{}", e),
+    fn run_script(&mut self, path: &Path) -> bool {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(e) => {
+                println!("Failed to open {}: {}", path.display(), e);
+                return true;
+            }
+        };
+        for line in BufReader::new(file).lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => {
+                    println!("Failed to read {}: {}", path.display(), e);
+                    return true;
+                }
+            };
+            if line.trim().is_empty() || line.trim().starts_with('#') {
+                continue;
+            }
+            println!("{}{}", PROMPT, line.trim());
+            if !self.execute(&line) {
+                return false;
             }
         }
+        true
+    }
+
+    // Returns false when the debugger should quit
+    fn execute(&mut self, input: &str) -> bool {
+        let command = input.trim().parse::<Command>();
+        match command {
+            Ok(Command::Step(num_steps)) => self.step(num_steps),
+            Ok(Command::Run) => self.step_forever(),
+            Ok(Command::Next) => self.next(),
+            Ok(Command::Finish) => self.finish(),
+            Ok(Command::Until(addr)) => if let Some(addr) = self.resolve(&addr) { self.until(addr) },
+            Ok(Command::Frame) => self.frame(),
+            Ok(Command::Backtrace) => self.backtrace(),
+            Ok(Command::Breakpoint(addr, condition)) => if let Some(addr) = self.resolve(&addr) {
+                self.set_breakpoint(BreakKind::Address(addr), condition)
+            },
+            Ok(Command::Catch(kind, condition)) => self.set_breakpoint(kind, condition),
+            Ok(Command::CatchWrite(addr, condition)) => if let Some(addr) = self.resolve(&addr) {
+                self.set_breakpoint(BreakKind::RegisterWrite(register_of(addr)), condition)
+            },
+            Ok(Command::ListBreakPoints) => self.list_breakpoints(),
+            Ok(Command::ClearBreakpoint(num)) => self.clear_bp(&num),
+            Ok(Command::Ignore(num, count)) => self.ignore(num, count),
+            Ok(Command::Watch(kind, low_addr, high_addr)) => if let Some((low_addr, high_addr)) = self.resolve_range(&low_addr, &high_addr) {
+                self.set_watchpoint(kind, low_addr, high_addr)
+            },
+            Ok(Command::Print(addr)) => if let Some(addr) = self.resolve(&addr) { self.print(addr as usize) },
+            Ok(Command::PrintRange(low_addr, high_addr)) => if let Some((low_addr, high_addr)) = self.resolve_range(&low_addr, &high_addr) {
+                self.print_range(low_addr as usize, high_addr as usize)
+            },
+            Ok(Command::Disassemble(None, count)) => self.disassemble(None, count.unwrap_or(DISASM_COUNT)),
+            Ok(Command::Disassemble(Some(addr), count)) => if let Some(addr) = self.resolve(&addr) {
+                self.disassemble(Some(addr), count.unwrap_or(DISASM_COUNT))
+            },
+            Ok(Command::Set(register, val)) => if let Some(val) = self.resolve(&val) { self.set_register(register, val) },
+            Ok(Command::Flag(flag, on)) => self.nes.cpu.set_flag(flag, on),
+            Ok(Command::Poke(addr, val)) => if let Some(addr) = self.resolve(&addr) { self.poke(addr, val) },
+            Ok(Command::Fill(low_addr, high_addr, val)) => if let Some((low_addr, high_addr)) = self.resolve_range(&low_addr, &high_addr) {
+                self.fill(low_addr, high_addr, val)
+            },
+            Ok(Command::Trace(on)) => self.trace(on),
+            Ok(Command::Profile(on)) => self.profile(on),
+            Ok(Command::ProfileReset) => if let Some(ref mut profiler) = self.nes.profiler { profiler.reset() },
+            Ok(Command::ProfileDump) => self.profile_dump(),
+            Ok(Command::LoadSymbols(path)) => self.load_symbols(&path),
+            Ok(Command::PpuRegisters) => self.print_ppu(),
+            Ok(Command::Oam) => self.print_oam(),
+            Ok(Command::Palette) => self.print_palette(),
+            Ok(Command::Nametable(index)) => self.print_nametable(index),
+            Ok(Command::View(view)) => self.nes.toggle_view(view),
+            Ok(Command::ChrPalette(palette)) => self.chr_palette(palette),
+            Ok(Command::Hex(space, addr)) => self.hex(space, addr),
+            Ok(Command::HexWrite(addr, bytes)) => self.hex_write(addr, &bytes),
+            Ok(Command::Events) => self.events(),
+            Ok(Command::SaveEvents(path)) => self.save_events(&path),
+            Ok(Command::SearchStart) => self.search_start(),
+            Ok(Command::Search(comparison, val)) => self.search(comparison, val),
+            Ok(Command::SearchList) => self.search_list(),
+            Ok(Command::Source(path)) => if !self.source(Path::new(&path)) {
+                return false
+            },
+            Ok(Command::Help) => self.help(),
+            Ok(Command::Quit) => {
+                self.nes.save();
+                return false
+            },
+            Err(ref e) => println!("{}", e),
+        }
+        true
     }
 
     pub fn reset(&mut self) {
         self.nes.reset();
     }
 
+    fn resolve(&self, addr: &Address) -> Option<u16> {
+        match addr.resolve(&self.nes.symbols) {
+            Ok(addr) => Some(addr),
+            Err(e) => {
+                println!("{}", e);
+                None
+            }
+        }
+    }
+
+    fn resolve_range(&self, low_addr: &Address, high_addr: &Address) -> Option<(u16, u16)> {
+        match (self.resolve(low_addr), self.resolve(high_addr)) {
+            (Some(low_addr), Some(high_addr)) => Some((low_addr, high_addr)),
+            _ => None,
+        }
+    }
+
     fn step(&mut self, num_steps: usize) {
         for _ in 0..num_steps {
-            let opcode = self.nes.step();
-            print!("{:02X} {}", opcode, opcode_to_name(opcode));
-            println!("{:?}", self.nes.cpu);
+            let pc = self.nes.cpu.pc();
+            let line = self.disassemble_line(pc);
+            self.nes.step();
+            println!("{:48} {:?}", line, self.nes.cpu);
+            self.print_stack_desyncs();
+            self.nes.refresh_views();
+            if self.nes.cpu.has_watch_hits() {
+                self.print_watch_hits();
+                break;
+            }
         }
     }
 
     fn step_forever(&mut self) {
-        self.nes.run(Some(&self.breakpoints));
+        let reason = self.nes.run(Some(&mut self.breakpoints));
+        self.print_stop(reason);
+    }
+
+    // Steps over subroutine calls, anything else is a single step
+    fn next(&mut self) {
+        let pc = self.nes.cpu.pc();
+        if self.read_byte(pc) != JSR {
+            return self.step(1);
+        }
+        let (return_addr, s) = (pc.wrapping_add(3), self.nes.cpu.s());
+        // Recursive calls return to the same address deeper in the stack
+        let reason = self.nes.run_until(Some(&mut self.breakpoints), |nes| {
+            nes.cpu.pc() == return_addr && nes.cpu.s() >= s
+        });
+        self.print_stop(reason);
+    }
+
+    // Runs until the current subroutine returns
+    fn finish(&mut self) {
+        let s = self.nes.cpu.s();
+        let at_return = |nes: &Nes| {
+            let opcode = nes.peek_byte(nes.cpu.pc());
+            (opcode == RTS || opcode == RTI) && nes.cpu.s() >= s
+        };
+        if at_return(&self.nes) {
+            return self.step(1);
+        }
+        let reason = self.nes.run_until(Some(&mut self.breakpoints), at_return);
+        if reason == StopReason::Finished {
+            return self.step(1);
+        }
+        self.print_stop(reason);
+    }
+
+    fn until(&mut self, addr: u16) {
+        let reason = self.nes.run_until(Some(&mut self.breakpoints), |nes| nes.cpu.pc() == addr);
+        self.print_stop(reason);
     }
 
-    fn set_breakpoint(&mut self, key: &mut usize, addr: usize) {
-        self.breakpoints.insert(*key, addr);
-        *key += 1;
+    // Runs to the first instruction after the next vblank starts
+    fn frame(&mut self) {
+        let mut left_vblank = false;
+        let reason = self.nes.run_until(Some(&mut self.breakpoints), |nes| {
+            let scanline = nes.interconnect.ppu.scanline();
+            let in_vblank = (VBLANK_START..PRE_RENDER_LINE).contains(&scanline);
+            left_vblank |= !in_vblank;
+            left_vblank && in_vblank
+        });
+        self.print_stop(reason);
+    }
+
+    fn print_stop(&mut self, reason: StopReason) {
+        let pc = self.nes.cpu.pc();
+        match reason {
+            StopReason::Breakpoint(key) => match self.breakpoints.get(&key).map(|breakpoint| &breakpoint.kind) {
+                Some(&BreakKind::Address(_)) | None => println!("Hit breakpoint {} @ ${:04X}", key, pc),
+                Some(kind) => println!("Hit breakpoint {} ({}) @ ${:04X}", key, kind, pc),
+            },
+            StopReason::Interrupted => println!("Interrupted @ ${:04X}", pc),
+            StopReason::Exited => println!("Emulation stopped @ ${:04X}", pc),
+            StopReason::Watchpoint => self.print_watch_hits(),
+            StopReason::Finished => {}
+        }
+        self.print_stack_desyncs();
+        self.nes.refresh_views();
+        let line = self.disassemble_line(pc);
+        println!("{:48} {:?}", line, self.nes.cpu);
+    }
+
+    // Innermost first, each frame named after the subroutine or handler it's in
+    fn backtrace(&self) {
+        let mut pc = self.nes.cpu.pc();
+        let frames = self.nes.cpu.call_stack();
+        for (depth, frame) in frames.iter().rev().enumerate() {
+            let kind = match frame.kind {
+                FrameKind::Jsr => "",
+                FrameKind::Interrupt(Interrupt::Nmi) => " (NMI)",
+                FrameKind::Interrupt(Interrupt::Irq) => " (IRQ)",
+                FrameKind::Interrupt(Interrupt::Brk) => " (BRK)",
+            };
+            println!("#{:<3} ${:04X} in {}{}", depth, pc, self.describe(frame.target), kind);
+            pc = frame.call_pc;
+        }
+        println!("#{:<3} ${:04X}", frames.len(), pc);
+        if let (count, Some(desync)) = self.nes.cpu.stack_desyncs() {
+            println!("{} desync(s), last: {}", count, desync);
+        }
+    }
+
+    fn describe(&self, addr: u16) -> String {
+        match self.nes.symbols.label(addr) {
+            Some(label) => format!("{} (${:04X})", label, addr),
+            None => format!("${:04X}", addr),
+        }
+    }
+
+    // Only the latest desync is kept by the CPU, so bursts are summarized
+    fn print_stack_desyncs(&mut self) {
+        let (count, last) = self.nes.cpu.stack_desyncs();
+        if let (true, Some(desync)) = (count > self.reported_desyncs, last) {
+            match count - self.reported_desyncs {
+                1 => println!("{}", desync),
+                new => println!("{} ({} desyncs since the last report)", desync, new),
+            }
+        }
+        self.reported_desyncs = count;
+    }
+
+    fn set_breakpoint(&mut self, kind: BreakKind, condition: Option<Expr>) {
+        if let Some(label) = condition.as_ref().and_then(|c| c.unknown_label(&self.nes.symbols)) {
+            println!("Unknown label: {}", label);
+            return;
+        }
+        match kind {
+            BreakKind::Scanline(scanline, dot) if scanline > PRE_RENDER_LINE || dot > LAST_DOT => {
+                return println!("Scanlines are 0-{} and dots 0-{}", PRE_RENDER_LINE, LAST_DOT);
+            }
+            BreakKind::RegisterWrite(addr) if !is_register(addr) => {
+                return println!("${:04X} is not a PPU or APU register", addr);
+            }
+            _ => {}
+        }
+        self.breakpoints.insert(self.next_key, Breakpoint::new(kind, condition));
+        self.next_key += 1;
+    }
+
+    fn ignore(&mut self, key: usize, count: usize) {
+        match self.breakpoints.get_mut(&key) {
+            Some(breakpoint) => breakpoint.ignore_count = count,
+            None => println!("No breakpoint {}", key),
+        }
+    }
+
+    fn set_watchpoint(&mut self, kind: WatchKind, low_addr: u16, high_addr: u16) {
+        if low_addr > high_addr {
+            println!("Low address higher than high address: LOW: ${:X} HIGH: ${:X}", low_addr, high_addr);
+            return;
+        }
+        self.nes.cpu.add_watchpoint(self.next_key, Watchpoint {
+            kind: kind,
+            low_addr: low_addr,
+            high_addr: high_addr,
+        });
+        self.next_key += 1;
+    }
+
+    fn print_watch_hits(&mut self) {
+        for hit in self.nes.cpu.take_watch_hits() {
+            println!("{}", hit);
+        }
     }
 
     fn list_breakpoints(&mut self) {
-        for (key, addr) in self.breakpoints.iter() {
-            println!("{:}\t${:04X}", key, addr);
+        for (key, breakpoint) in self.breakpoints.iter() {
+            println!("{:}\t{}", key, breakpoint);
+        }
+        for (key, watchpoint) in self.nes.cpu.watchpoints().iter() {
+            let kind = match watchpoint.kind {
+                WatchKind::Read => "r",
+                WatchKind::Write => "w",
+                WatchKind::ReadWrite => "rw",
+            };
+            println!("{:}\twatch {}\t${:04X}:${:04X}", key, kind, watchpoint.low_addr, watchpoint.high_addr);
         }
     }
 
+    // Breakpoints and watchpoints share numbering, so this clears either
     fn clear_bp(&mut self, key: &usize) {
-        self.breakpoints.remove(key);
+        if self.breakpoints.remove(key).is_none() {
+            self.nes.cpu.remove_watchpoint(*key);
+        }
     }
 
     fn print(&mut self, addr: usize) {
         if addr <= 0xFFFF {
-            println!("M[${:04X}] = {:X}", addr, self.nes.cpu.fetch_byte(&mut self.nes.interconnect, addr as u16));
+            println!("M[${:04X}] = {:X}", addr, self.read_byte(addr as u16));
         } else {
             println!("Invalid address: ${:X}", addr);
         }
@@ -92,116 +456,369 @@ impl<'a> Debugger<'a> {
         } else {
             for (i, addr) in (low_addr..high_addr).enumerate() {
                 if i % 16 == 0 { print!("\n${:04X}| ", addr); io::stdout().flush().unwrap(); }
-                print!("{:02X} ", self.nes.cpu.fetch_byte(&mut self.nes.interconnect, addr as u16));
+                print!("{:02X} ", self.read_byte(addr as u16));
             }
             print!("\n");
             io::stdout().flush().unwrap();
         }
     }
 
-    fn help(&self) {
-        println!("GIDGET DEBUGGER");
-        println!("Usage: COMMAND (SHORTCUT) <Args>");
-        println!("\tbreak\t\t(b)\t<Address>\t\t\t- Sets breakpoint at specified address");
-        println!("\tlist\t\t(l)\t\t\t\t\t- Lists all active breakpoints");
-        println!("\tclear\t\t(cb)\t<Breakpoint Number>\t\t- Clears specified breakpoint");
-        println!("\tprint\t\t(p)\t<Address>\t\t\t- Prints value in memory at specified address");
-        println!("\tpr\t\t\t<Low Address>:<High Address>\t- Prints the values over the specified range of memory");
-        println!("\tstep\t\t(s)\t<Steps>\t\t\t\t- Steps the NES the specified number of times (empty steps 1)");
-        println!("\trun/continue\t(r/c)\t\t\t\t\t- Runs the NES as normal");
-        println!("\tquit\t\t(q)\t\t\t\t\t- Quits the debugger");
-        println!("\thelp\t\t(h)\t\t\t\t\t- Prints this help message");
+    fn set_register(&mut self, register: Register, val: u16) {
+        if register != Register::Pc && val > 0xFF {
+            println!("Value too large for {:?}: ${:X}", register, val);
+            return;
+        }
+        match register {
+            Register::A => self.nes.cpu.set_a(val as u8),
+            Register::X => self.nes.cpu.set_x(val as u8),
+            Register::Y => self.nes.cpu.set_y(val as u8),
+            Register::S => self.nes.cpu.set_s(val as u8),
+            Register::P => self.nes.cpu.set_p(val as u8),
+            Register::Pc => self.nes.cpu.set_pc(val),
+        }
+        println!("{:?}", self.nes.cpu);
     }
-}
-
-fn opcode_to_name(opcode: u8) -> String {
-    format!("{:20}",
-        match opcode {
-            // Branches
-            0x10 => "BPL (label)", 0x30 => "BMI (label)", 0x50 => "BVC (label)",
-            0x70 => "BVS (label)", 0x90 => "BCC (label)", 0xB0 => "BCS (label)",
-            0xD0 => "BNE (label)", 0xF0 => "BEQ (label)",
-
-            // ALU operations
-            0x61 => "ADC (d,x)", 0x65 => "ADC d",   0x69 => "ADC #v",  0x6D => "ADC a",
-            0x71 => "ADC (d),y", 0x75 => "ADC d,x", 0x79 => "ADC a,y", 0x7D => "ADC a,x",
-
-            0x21 => "AND (d,x)", 0x25 => "AND d",   0x29 => "AND #v",  0x2D => "AND a",
-            0x31 => "AND (d),y", 0x35 => "AND d,x", 0x39 => "AND a,y", 0x3D => "AND a,x",
-
-            0x06 => "ASL d",     0x0A => "ASL A",   0x0E => "ASL a",   0x16 => "ASL d,x",
-            0x1E => "ASL a,x",
 
-            0x24 => "BIT d",     0x2C => "BIT a",
-
-            0xC1 => "CMP (d,x)", 0xC5 => "CMP d",   0xC9 => "CMP #v",  0xCD => "CMP a",
-            0xD1 => "CMP (d),y", 0xD5 => "CMP d,x", 0xD9 => "CMP a,y", 0xDD => "CMP a,x",
+    fn poke(&mut self, addr: u16, val: u8) {
+        self.nes.cpu.poke_byte(&mut self.nes.interconnect, addr, val);
+    }
 
-            0xE0 => "CPX #v",    0xE4 => "CPX d",   0xEC => "CPX a",
+    fn fill(&mut self, low_addr: u16, high_addr: u16, val: u8) {
+        if low_addr > high_addr {
+            println!("Low address higher than high address: LOW: ${:X} HIGH: ${:X}", low_addr, high_addr);
+            return;
+        }
+        for addr in (low_addr as u32)..(high_addr as u32 + 1) {
+            self.poke(addr as u16, val);
+        }
+    }
 
-            0xC0 => "CPY #v",    0xC4 => "CPY d",   0xCC => "CPY a",
+    fn print_ppu(&self) {
+        let ppu = &self.nes.interconnect.ppu;
+        println!("PPUCTRL:   {:02X} ({:08b})", ppu.ctrl(), ppu.ctrl());
+        println!("PPUMASK:   {:02X} ({:08b})", ppu.mask(), ppu.mask());
+        println!("PPUSTATUS: {:02X} ({:08b})", ppu.status(), ppu.status());
+        println!("OAMADDR:   {:02X}", ppu.oam_addr());
+        for &(name, addr) in [("v", ppu.v()), ("t", ppu.t())].iter() {
+            println!("{}:         {:04X} (coarse X {:02}, coarse Y {:02}, nametable {}, fine Y {})",
+                name, addr, addr & 0x1F, (addr >> 5) & 0x1F, (addr >> 10) & 0x3, (addr >> 12) & 0x7);
+        }
+        println!("x:         {}", ppu.fine_x());
+        println!("w:         {}", ppu.w() as u8);
+        println!("Scanline:  {} Dot: {} Frame: {}", ppu.scanline(), ppu.dot(), ppu.frame());
+    }
 
-            0x41 => "EOR (d,x)", 0x45 => "EOR d",   0x49 => "EOR #v",  0x4D => "EOR a",
-            0x51 => "EOR (d),y", 0x55 => "EOR d,x", 0x59 => "EOR a,y", 0x5D => "EOR a,x",
+    fn print_oam(&self) {
+        let oam = self.nes.interconnect.ppu.oam();
+        println!("##  Y   Tile Attr X   Palette Priority Flip");
+        for (i, sprite) in oam.chunks(4).enumerate() {
+            let (y, tile, attr, x) = (sprite[0], sprite[1], sprite[2], sprite[3]);
+            let priority = if attr & 0x20 != 0 { "back" } else { "front" };
+            let flip = match (attr & 0x40 != 0, attr & 0x80 != 0) {
+                (false, false) => "",
+                (true, false) => "H",
+                (false, true) => "V",
+                (true, true) => "HV",
+            };
+            println!("{:02} {:02X}  {:02X}   {:02X}   {:02X}  {}       {:8} {}", i, y, tile, attr, x, attr & 0x3, priority, flip);
+        }
+    }
 
-            0x4A => "LSR A",     0x46 => "LSR d",   0x4E => "LSR a",   0x56 => "LSR d,x",
-            0x5E => "LSR a,x",
+    fn print_palette(&self) {
+        let palette = self.nes.interconnect.ppu.palette();
+        for (i, colors) in palette.chunks(4).enumerate() {
+            let kind = if i < 4 { "BG" } else { "Sprite" };
+            let colors: Vec<String> = colors.iter().map(|c| format!("{:02X}", c)).collect();
+            println!("${:04X} {:6} {}: {}", 0x3F00 + i * 4, kind, i % 4, colors.join(" "));
+        }
+    }
 
-            0x01 => "ORA (d,x)", 0x05 => "ORA d",   0x09 => "ORA #v",  0x0D => "ORA a",
-            0x11 => "ORA (d),y", 0x15 => "ORA d,x", 0x19 => "ORA a,y", 0x1D => "ORA a,x",
+    // Tile rows followed by the attribute table
+    fn print_nametable(&self, index: usize) {
+        if index > 1 {
+            println!("Invalid nametable: {} (PPU RAM holds nametables 0 and 1)", index);
+            return;
+        }
+        let nametable = self.nes.interconnect.ppu.nametable(index);
+        for (row, tiles) in nametable.chunks(32).enumerate() {
+            if row == 30 { println!("Attributes:"); }
+            let tiles: Vec<String> = tiles.iter().map(|t| format!("{:02X}", t)).collect();
+            println!("${:03X}| {}", row * 32, tiles.join(" "));
+        }
+    }
 
-            0x2A => "ROL A",     0x26 => "ROL d",   0x2E => "ROL a",   0x36 => "ROL d,x",
-            0x3E => "ROL a,x",
+    fn search_start(&mut self) {
+        let search = RamSearch::start(self.nes.cpu.ram());
+        println!("{} candidates", search.candidates().len());
+        self.search = Some(search);
+    }
 
-            0x6A => "ROR A",     0x66 => "ROR d",   0x6E => "ROR a",   0x76 => "ROR d,x",
-            0x7E => "ROR a,x",
+    fn search(&mut self, comparison: Comparison, val: Option<u8>) {
+        match self.search {
+            Some(ref mut search) => {
+                search.filter(self.nes.cpu.ram(), comparison, val);
+                println!("{} candidates", search.candidates().len());
+            }
+            None => println!("No search in progress, use search start"),
+        }
+    }
 
-            0xE1 => "SBC (d,x)", 0xE5 => "SBC d",   0xE9 => "SBC #v",  0xED => "SBC a",
-            0xF1 => "SBC (d),y", 0xF5 => "SBC d,x", 0xF9 => "SBC a,y", 0xFD => "SBC a,x",
+    fn search_list(&self) {
+        let search = match self.search {
+            Some(ref search) => search,
+            None => return println!("No search in progress, use search start"),
+        };
+        let candidates = search.candidates();
+        for &addr in candidates.iter().take(SEARCH_LIST_MAX) {
+            let label = self.nes.symbols.label(addr).map(|label| format!(" ({})", label)).unwrap_or_default();
+            println!("${:04X}{} = {:02X} (was {:02X})", addr, label, self.read_byte(addr), search.previous(addr));
+        }
+        if candidates.len() > SEARCH_LIST_MAX {
+            println!("... {} more", candidates.len() - SEARCH_LIST_MAX);
+        }
+    }
 
-            // Increments and Decrements
-            0xE6 => "INC d", 0xEE => "INC a", 0xF6 => "INC d,x", 0xFE => "INC a,x",
-            0xE8 => "INX",   0xC8 => "INY",
+    // Opens the pattern table view if it isn't already
+    fn chr_palette(&mut self, palette: usize) {
+        if palette >= views::CHR_PALETTES {
+            return println!("Palettes are 0-{}", views::CHR_PALETTES - 1);
+        }
+        self.nes.views.chr_palette = palette;
+        if self.nes.views.is_showing(View::Chr) {
+            self.nes.refresh_views();
+        } else {
+            self.nes.toggle_view(View::Chr);
+        }
+    }
 
-            0xC6 => "DEC d", 0xCE => "DEC a", 0xD6 => "DEC d,x", 0xDE => "DEC a,x",
-            0xCA => "DEX",   0x88 => "DEY",
+    // Opens the hex view if it isn't already. Without an address it stays put
+    // when the space doesn't change.
+    fn hex(&mut self, space: Space, addr: Option<usize>) {
+        let size = space.size(&self.nes.interconnect);
+        let current = if space == self.nes.views.hex.space { self.nes.views.hex.addr } else { 0 };
+        let addr = addr.unwrap_or(current);
+        if addr >= size {
+            return println!("{:?} memory has ${:X} bytes", space, size);
+        }
+        self.nes.views.hex.show(space, addr);
+        if self.nes.views.is_showing(View::Hex) {
+            self.nes.refresh_views();
+        } else {
+            self.nes.toggle_view(View::Hex);
+        }
+    }
 
-            // Loads
-            0xA1 => "LDA (d,x)", 0xA5 => "LDA d",   0xA9 => "LDA #v",  0xAD => "LDA a",
-            0xB1 => "LDA (d),y", 0xB5 => "LDA d,x", 0xB9 => "LDA a,y", 0xBD => "LDA a,x",
+    fn hex_write(&mut self, addr: usize, bytes: &[u8]) {
+        let space = self.nes.views.hex.space;
+        let size = space.size(&self.nes.interconnect);
+        if addr.checked_add(bytes.len()).is_none_or(|end| end > size) {
+            return println!("{:?} memory has ${:X} bytes", space, size);
+        }
+        for (i, &val) in bytes.iter().enumerate() {
+            space.poke(&mut self.nes.cpu, &mut self.nes.interconnect, addr + i, val);
+        }
+        self.nes.refresh_views();
+    }
 
-            0xA2 => "LDX #v", 0xA6 => "LDX d", 0xAE => "LDX a", 0xB6 => "LDX d,y",
-            0xBE => "LDX a,y",
+    // Same order as the events view: the rest of the last frame, then this one
+    fn events(&self) {
+        let ppu = &self.nes.interconnect.ppu;
+        let now = (ppu.scanline(), ppu.dot());
+        let (last, current) = self.nes.interconnect.events.frames(ppu.frame());
+        let events: Vec<&Event> = last.iter()
+            .filter(|event| (event.scanline, event.dot) > now)
+            .chain(current.iter())
+            .collect();
+        for event in events.iter().take(EVENT_LIST_MAX) {
+            let position = format!("{:3}:{:3}", event.scanline, event.dot);
+            let name = match event.kind {
+                EventKind::PpuWrite => "PPU",
+                EventKind::ApuWrite => "APU",
+                EventKind::MapperWrite => "Mapper",
+                EventKind::Nmi => "NMI",
+                EventKind::Irq => "IRQ",
+            };
+            match event.kind {
+                EventKind::Nmi | EventKind::Irq => println!("{}  {} -> {}", position, name, self.describe(event.addr)),
+                _ => println!("{}  {} ${:04X} = {:02X}", position, name, event.addr, event.value),
+            }
+        }
+        if events.len() > EVENT_LIST_MAX {
+            println!("... {} more", events.len() - EVENT_LIST_MAX);
+        }
+    }
 
-            0xA0 => "LDY #v", 0xA4 => "LDY d", 0xAC => "LDY a", 0xB4 => "LDY d,x",
-            0xBC => "LDY a,x",
+    fn save_events(&self, path: &str) {
+        match views::save_events(path, &self.nes.interconnect) {
+            Ok(()) => println!("Saved the event timeline to {}", path),
+            Err(e) => println!("Failed to save {}: {}", path, e),
+        }
+    }
 
-            // Stores
-            0x81 => "STA (d,x)", 0x85 => "STA d",   0x8D => "STA a",   0x91 => "STA (d),y",
-            0x95 => "STA d,x",   0x99 => "STA a,y", 0x9D => "kTA a,x",
+    fn load_symbols(&mut self, path: &str) {
+        match self.nes.symbols.load(Path::new(path)) {
+            Ok(count) => println!("Loaded {} labels from {}", count, path),
+            Err(e) => println!("Failed to load {}: {}", path, e),
+        }
+    }
 
-            0x86 => "STX d", 0x8E => "STX a", 0x96 => "STX d,y",
+    // Traces to the --trace file if one was given, otherwise to stdout
+    fn trace(&mut self, on: bool) {
+        match self.nes.trace {
+            Some(ref mut trace) => {
+                trace.enabled = on;
+                trace.flush();
+            }
+            None if on => self.nes.trace = Some(Trace::to_stdout()),
+            None => {}
+        }
+    }
 
-            0x84 => "STY d", 0x8C => "STY a", 0x94 => "STY d,x",
+    fn profile(&mut self, on: bool) {
+        match self.nes.profiler {
+            Some(ref mut profiler) => profiler.enabled = on,
+            None if on => self.nes.profiler = Some(Profiler::new(None)),
+            None => {}
+        }
+    }
 
-            // Flag sets
-            0x38 => "SEC", 0x78 => "SEI", 0xF8 => "SED",
+    fn profile_dump(&self) {
+        let profiler = match self.nes.profiler {
+            Some(ref profiler) => profiler,
+            None => return println!("Profiling is off, use profile on"),
+        };
+        let total = profiler.total();
+        println!("{} cycles over {} instructions", total.cycles, total.hits);
+        if total.cycles == 0 {
+            return;
+        }
+        let percent = |cycles: u64| cycles as f64 * 100.0 / total.cycles as f64;
+        println!("{:>12} {:>7} {:>10}  Routine", "Cycles", "%", "Hits");
+        for (routine, counts) in profiler.routines().into_iter().take(PROFILE_DUMP_MAX) {
+            let name = match routine {
+                Some(addr) => self.describe(addr),
+                None => "(top level)".to_owned(),
+            };
+            println!("{:>12} {:>6.2}% {:>10}  {}", counts.cycles, percent(counts.cycles), counts.hits, name);
+        }
+        println!("{:>12} {:>7} {:>10}  Address", "Cycles", "%", "Hits");
+        for (pc, counts) in profiler.addresses().into_iter().take(PROFILE_DUMP_MAX) {
+            println!("{:>12} {:>6.2}% {:>10}  {}", counts.cycles, percent(counts.cycles), counts.hits, self.disassemble_line(pc));
+        }
+    }
 
-            // Flag clears
-            0x18 => "CLC", 0xB8 => "CLV", 0xD8 => "CLD",
+    fn read_byte(&self, addr: u16) -> u8 {
+        self.nes.peek_byte(addr)
+    }
 
-            // Stack
-            0x08 => "PHP", 0x28 => "PLP", 0x48 => "PHA", 0x68 => "PLA",
+    // The instruction at PC also shows the addresses and values its operand resolves to
+    fn disassemble_line(&self, addr: u16) -> String {
+        let instruction = disasm::decode(addr, |addr| self.read_byte(addr));
+        if addr == self.nes.cpu.pc() {
+            let (x, y) = (self.nes.cpu.x(), self.nes.cpu.y());
+            instruction.annotated(x, y, |addr| self.read_byte(addr), &self.nes.symbols)
+        } else {
+            instruction.line(&self.nes.symbols)
+        }
+    }
 
-            // Transfers
-            0xAA => "TAX", 0xA8 => "TAY", 0xBA => "TSX", 0x8A => "TXA", 0x9A => "TXS", 0x98 => "TYA",
+    fn disassemble(&mut self, addr: Option<u16>, count: usize) {
+        let pc = self.nes.cpu.pc();
+        let mut addr = match addr {
+            Some(addr) => addr,
+            None => self.find_start_before(pc, DISASM_CONTEXT),
+        };
+        for _ in 0..count {
+            if let Some(label) = self.nes.symbols.label(addr) {
+                println!("{}:", label);
+            }
+            let marker = if addr == pc { "->" } else { "  " };
+            println!("{} {}", marker, self.disassemble_line(addr));
+            addr = disasm::decode(addr, |addr| self.read_byte(addr)).next_addr();
+        }
+    }
 
-            // Jumps
-            0x4C => "JMP a", 0x6C => "JMP (a)", 0x20 => "JSR", 0x40 => "RTI", 0x60 => "RTS",
+    // Instructions are variable length, so find the furthest start address that decodes
+    // to at most num instructions landing exactly on addr
+    fn find_start_before(&mut self, addr: u16, num: usize) -> u16 {
+        for offset in (1..(num as u16 * 3 + 1)).rev() {
+            let start = addr.wrapping_sub(offset);
+            let mut cur = start;
+            let mut decoded = 0;
+            while addr.wrapping_sub(cur) != 0 && addr.wrapping_sub(cur) <= offset {
+                cur = disasm::decode(cur, |addr| self.read_byte(addr)).next_addr();
+                decoded += 1;
+            }
+            if cur == addr && decoded <= num {
+                return start;
+            }
+        }
+        addr
+    }
 
-            0xEA => "NOP",
+    fn help(&self) {
+        println!("GIDGET DEBUGGER");
+        println!("Usage: COMMAND (SHORTCUT) <Args>");
+        println!("An empty line repeats the last step, next, finish, run, frame, print or disasm");
+        println!("<Address> and <Value> are hex, bare or with a $ or 0x prefix. Addresses can also be labels from a loaded symbol file");
+        println!("Counts, breakpoint numbers, scanlines and dots are decimal");
+        println!("\tbreak\t\t(b)\t<Address> [if <Expr>]\t\t- Sets breakpoint at specified address, optionally only when <Expr> is true");
+        println!("\t\t\t\t<Expr> can use A X Y S P PC, flags C Z I D V N, SCANLINE DOT FRAME,");
+        println!("\t\t\t\t[<Addr>] for memory, labels, hex numbers and || && == != < <= > >= & | ^ + - !");
+        println!("\tcatch\t\t\tnmi|irq|brk|vblank [if <Expr>]\t\t- Stops on interrupt entry or the start of vblank");
+        println!("\tcatch\t\t\top <Opcode>|<Mnemonic>|unofficial [if <Expr>] - Stops before an opcode executes");
+        println!("\tcatch\t\t\tscanline <Line> [<Dot>] [if <Expr>]\t- Stops when the PPU reaches a scanline and dot (decimal)");
+        println!("\tcatch\t\t\twrite <Address> [if <Expr>]\t- Stops before a write to a PPU or APU register");
+        println!("\tignore\t\t\t<Breakpoint Number> <Count>\t- Skips the next <Count> hits of a breakpoint");
+        println!("\twatch\t\t(w)\tr|w|rw <Address>[:<Address>]\t- Stops when memory in the range is read and/or written");
+        println!("\tlist\t\t(l)\t\t\t\t\t- Lists all active breakpoints and watchpoints");
+        println!("\tclear\t\t(cb)\t<Breakpoint Number>\t\t- Clears specified breakpoint or watchpoint");
+        println!("\tprint\t\t(p)\t<Address>\t\t\t- Prints value in memory at specified address");
+        println!("\tpr\t\t\t<Low Address>:<High Address>\t- Prints the values over the specified range of memory");
+        println!("\tset\t\t\ta|x|y|s|p|pc <Value>\t\t- Sets a CPU register");
+        println!("\tflag\t\t\tc|z|i|d|v|n on|off\t\t- Sets or clears a status flag");
+        println!("\tpoke\t\t\t<Address> <Value>\t\t- Writes a byte to memory (registers only have their value set)");
+        println!("\tfill\t\t\t<Low Address>:<High Address> <Value> - Writes a byte over the specified range of memory");
+        println!("\tppu\t\t\t\t\t\t- Prints PPU registers, scroll registers and timing");
+        println!("\toam\t\t\t\t\t\t- Prints OAM decoded as sprites");
+        println!("\tpalette\t\t(pal)\t\t\t\t\t- Prints palette RAM");
+        println!("\tnametable\t(nt)\t0|1\t\t\t\t- Dumps one of the PPU's nametables and its attributes");
+        println!("\tsearch\t\t\tstart\t\t\t\t- Starts a RAM search with every address as a candidate");
+        println!("\tsearch\t\t\teq|ne|gt|lt [<Value>]\t\t- Keeps candidates comparing true to <Value>, or to their last value");
+        println!("\tsearch\t\t\tchanged|unchanged\t\t- Keeps candidates that did or didn't change since the last search");
+        println!("\tsearch\t\t\tlist\t\t\t\t- Lists the remaining candidates with current and last values");
+        println!("\tview\t\t\tchr|nt|oam|pal\t\t\t- Opens or closes a window showing pattern tables, nametables, sprites or palettes");
+        println!("\tview\t\t\thex\t\t\t\t- Opens or closes the hex view of memory");
+        println!("\tview\t\t\tchr <Palette>\t\t\t- Draws the pattern tables with palette 0-7 (4-7 are sprite palettes)");
+        println!("\thex\t\t\tcpu|ppu|oam|prg|chr [<Offset>]\t- Shows memory in the hex view, bytes changed since last frame in red");
+        println!("\thex\t\t\twrite <Offset> <Value>...\t- Writes bytes into the memory the hex view is showing");
+        println!("\tview\t\t\tevents|ev\t\t\t- Opens or closes a timeline of register writes and interrupts on the 341x262 dot grid");
+        println!("\tevents\t\t\t\t\t\t- Lists the writes and interrupts shown in the events view as scanline:dot");
+        println!("\tevents\t\t\tsave <File>\t\t\t- Saves the events view as a BMP image");
+        println!("\t\t\t\tEvents are PPU pink, APU green, mapper yellow, NMI blue, IRQ magenta");
+        println!("\t\t\t\tF1-F4, F8 and F9 toggle the same windows while running, F7 cycles the pattern table palette");
+        println!("\tsymbols\t\t\t<File>\t\t\t\t- Loads labels from a ca65 .dbg, FCEUX .nl or Mesen .mlb file");
+        println!("\tprofile\t\t\ton|off|reset\t\t\t- Counts instruction hits and cycles per address and routine");
+        println!("\tprofile\t\t\tdump\t\t\t\t- Prints the most expensive routines and addresses");
+        println!("\ttrace\t\t\ton|off\t\t\t\t- Logs executed instructions in the nestest.log format");
+        println!("\tdisasm\t\t(d)\t[<Address> [<Count>]]\t\t- Disassembles <Count> instructions from <Address> (empty is around PC)");
+        println!("\tstep\t\t(s)\t<Steps>\t\t\t\t- Steps the NES the specified number of times (empty steps 1)");
+        println!("\tnext\t\t(n)\t\t\t\t\t- Steps over a subroutine call (otherwise steps 1)");
+        println!("\tfinish\t\t(fin)\t\t\t\t\t- Runs until the current subroutine returns");
+        println!("\tuntil\t\t(u)\t<Address>\t\t\t- Runs until PC reaches <Address>");
+        println!("\tframe\t\t\t\t\t\t\t- Runs until the start of the next vblank");
+        println!("\tbacktrace\t(bt)\t\t\t\t\t- Shows the JSR and interrupt frames leading to PC");
+        println!("\trun/continue\t(r/c)\t\t\t\t\t- Runs the NES until a breakpoint is hit or Ctrl-C/Pause is pressed");
+        println!("\tsource\t\t\t<File>\t\t\t\t- Runs debugger commands from a file");
+        println!("\tquit\t\t(q)\t\t\t\t\t- Quits the debugger");
+        println!("\thelp\t\t(h)\t\t\t\t\t- Prints this help message");
+    }
+}
 
-            _ => unreachable!(),
-        }
-    )
+// History is kept in the home directory, or the working directory without one
+fn history_path() -> PathBuf {
+    match env::var_os("HOME") {
+        Some(home) => Path::new(&home).join(HISTORY_FILE),
+        None => PathBuf::from(HISTORY_FILE),
+    }
 }
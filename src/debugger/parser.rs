@@ -2,51 +2,184 @@ use std::borrow::Cow;
 use std::str::{self, FromStr};
 use std::num;
 
-use nom::{IResult, digit, is_hex_digit};
+use nom::{IResult, ErrorKind, digit, is_hex_digit, is_alphabetic, is_alphanumeric, is_space};
 
-#[derive(Debug, Clone, Copy)]
+use cpu::{Flag, Interrupt, WatchKind};
+use disasm;
+use memory::Space;
+use symbols::Symbols;
+use views::View;
+use super::breakpoint::BreakKind;
+use super::expr::{Expr, Variable, UnaryOp, BinaryOp};
+use super::search::Comparison;
+
+#[derive(Debug, Clone)]
 pub enum Command {
     Step(usize),
     Run,
-    Breakpoint(u16),
+    Next,
+    Finish,
+    Until(Address),
+    Frame,
+    Backtrace,
+    Breakpoint(Address, Option<Expr>),
+    Catch(BreakKind, Option<Expr>),
+    CatchWrite(Address, Option<Expr>), // Register writes, the address may be a label
     ListBreakPoints,
     ClearBreakpoint(usize),
-    Print(u16),
-    PrintRange(u16, u16),
+    Ignore(usize, usize),
+    Watch(WatchKind, Address, Address),
+    Print(Address),
+    PrintRange(Address, Address),
+    Disassemble(Option<Address>, Option<usize>),
+    Set(Register, Address),
+    Flag(Flag, bool),
+    Poke(Address, u8),
+    Fill(Address, Address, u8),
+    Trace(bool),
+    Profile(bool),
+    ProfileReset,
+    ProfileDump,
+    LoadSymbols(String),
+    Source(String),
+    PpuRegisters,
+    Oam,
+    Palette,
+    Nametable(usize),
+    View(View),
+    ChrPalette(usize),
+    Hex(Space, Option<usize>),
+    HexWrite(usize, Vec<u8>), // Into the space the hex view is showing
+    Events,
+    SaveEvents(String),
+    SearchStart,
+    Search(Comparison, Option<u8>), // None compares against the previous search
+    SearchList,
     Help,
     Quit,
 }
 
+// Labels are resolved by the debugger once the command is parsed
+#[derive(Debug, Clone, PartialEq)]
+pub enum Address {
+    Absolute(u16), // $C000 or 0xC000
+    Name(String),  // A label, or bare hex like C000 if there is no such label
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    S,
+    P,
+    Pc,
+}
+
 impl FromStr for Command {
     type Err = Cow<'static, str>;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match command(s.as_bytes()) {
-            IResult::Done(_, c) => Ok(c),
+            IResult::Done(rest, c) if rest.iter().all(|&b| is_space(b)) => Ok(c),
+            IResult::Done(rest, _) => {
+                Err(format!("Unexpected input in command {}: {}", s, String::from_utf8_lossy(rest)).into())
+            }
             err => Err(format!("Unable to parse command {}: {:?}", s, err).into())
         }
     }
 }
 
+impl Command {
+    // Commands an empty line repeats, as in gdb. Anything that changes memory,
+    // registers or debugger state has to be typed again.
+    pub fn is_repeatable(&self) -> bool {
+        matches!(*self,
+            Command::Step(_) | Command::Run | Command::Next | Command::Finish | Command::Frame |
+            Command::Print(_) | Command::PrintRange(..) | Command::Disassemble(..))
+    }
+}
+
+impl Address {
+    pub fn resolve(&self, symbols: &Symbols) -> Result<u16, String> {
+        match *self {
+            Address::Absolute(addr) => Ok(addr),
+            Address::Name(ref name) => symbols.addr(name)
+                .or_else(|| u16::from_str_radix(name, 16).ok())
+                .ok_or_else(|| format!("Unknown label: {}", name)),
+        }
+    }
+}
+
+// Characters allowed in labels, including ca65's @cheap_locals
+pub fn is_symbol_char(c: u8) -> bool {
+    is_alphanumeric(c) || c == b'_' || c == b'@' || c == b'.'
+}
+
+// Commands end at a space or the end of the line, so pc isn't p followed by c
+fn word_end(input: &[u8]) -> IResult<&[u8], ()> {
+    match input.first() {
+        Some(&c) if is_symbol_char(c) => IResult::Error(error_position!(ErrorKind::Custom(0), input)),
+        _ => IResult::Done(input, ()),
+    }
+}
+
 fn from_hex(input: &[u8]) -> Result<u16, num::ParseIntError> {
     let hex_string = str::from_utf8(input).unwrap_or("0");
     u16::from_str_radix(hex_string, 16)
 }
 
+// Values are hex like addresses, with or without a '$' or '0x' prefix
 named!(hex_primary<&[u8], u16>,
-    map_res!(take_while!(is_hex_digit), from_hex)
+    map_res!(preceded!(opt!(alt_complete!(tag!("$") | tag!("0x"))), take_while1!(is_hex_digit)), from_hex)
+);
+
+named!(hex_byte<&[u8], u8>,
+    map_opt!(hex_primary, |val: u16| if val <= 0xFF { Some(val as u8) } else { None })
+);
+
+named!(address<Address>,
+    alt_complete!(
+        map!(map_res!(preceded!(alt_complete!(tag!("$") | tag!("0x")), take_while1!(is_hex_digit)), from_hex),
+             Address::Absolute) |
+        map!(map_res!(take_while1!(is_symbol_char), str::from_utf8), |name: &str| Address::Name(name.to_owned()))
+    )
 );
 
-// TODO: Allow commands to take hex prefixed with '$'
 named!(command<Command>,
     alt_complete!(
+        backtrace   |
         breakpoint  |
+        watch       |
         list        |
+        catch       |
         clear_bp    |
+        ignore      |
+        symbols     |
+        source      |
+        search      |
+        set         |
+        flag        |
+        ppu         |
+        oam         |
+        palette     |
+        nametable   |
+        view        |
+        events      |
         step        |
         run         |
-        print       |
+        next        |
+        finish      |
+        until       |
+        frame       |
+        profile     |
+        poke        |
+        fill        |
         print_range |
+        print       |
+        disassemble |
+        trace       |
+        hex         |
         help        |
         quit
     )
@@ -54,9 +187,10 @@ named!(command<Command>,
 
 named!(step<Command>,
     do_parse!(
-        alt!(
-            tag!("s") | tag!("step")
+        alt_complete!(
+            tag!("step") | tag!("s")
         ) >>
+        word_end >>
         num_steps: opt!(complete!(ws!(usize_parser))) >>
         (Command::Step(num_steps.unwrap_or(1)))
     )
@@ -64,37 +198,287 @@ named!(step<Command>,
 
 named!(run<Command>,
     do_parse!(
-        alt!(
-            tag!("r") | tag!("run")
+        alt_complete!(
+            tag!("run") | tag!("r") | tag!("continue") | tag!("c")
         ) >>
+        word_end >>
         (Command::Run)
     )
 );
 
+named!(next<Command>,
+    do_parse!(
+        alt_complete!(
+            tag!("next") | tag!("n")
+        ) >>
+        word_end >>
+        (Command::Next)
+    )
+);
+
+named!(finish<Command>,
+    do_parse!(
+        alt_complete!(
+            tag!("finish") | tag!("fin")
+        ) >>
+        word_end >>
+        (Command::Finish)
+    )
+);
+
+named!(until<Command>,
+    do_parse!(
+        alt_complete!(
+            tag!("until") | tag!("u")
+        ) >>
+        word_end >>
+        addr: ws!(address) >>
+        (Command::Until(addr))
+    )
+);
+
+named!(frame<Command>,
+    do_parse!(
+        tag!("frame") >>
+        word_end >>
+        (Command::Frame)
+    )
+);
+
+named!(backtrace<Command>,
+    do_parse!(
+        alt_complete!(
+            tag!("backtrace") | tag!("bt")
+        ) >>
+        word_end >>
+        (Command::Backtrace)
+    )
+);
+
 named!(breakpoint<Command>,
     do_parse!(
-        alt!(
-            tag!("b") | tag!("break")
+        alt_complete!(
+            tag!("break") | tag!("b")
         ) >>
-        addr: ws!(hex_primary) >>
-        (Command::Breakpoint(addr))
+        word_end >>
+        addr: ws!(address) >>
+        condition: opt!(complete!(preceded!(ws!(tag!("if")), expr))) >>
+        (Command::Breakpoint(addr, condition))
+    )
+);
+
+named!(ignore<Command>,
+    do_parse!(
+        tag!("ignore") >>
+        word_end >>
+        num: ws!(usize_parser) >>
+        count: ws!(usize_parser) >>
+        (Command::Ignore(num, count))
+    )
+);
+
+named!(catch<Command>,
+    do_parse!(
+        tag!("catch") >>
+        word_end >>
+        command: alt_complete!(
+            do_parse!(
+                ws!(tag!("write")) >>
+                addr: address >>
+                condition: opt!(complete!(preceded!(ws!(tag!("if")), expr))) >>
+                (Command::CatchWrite(addr, condition))
+            ) |
+            do_parse!(
+                kind: ws!(break_kind) >>
+                condition: opt!(complete!(preceded!(ws!(tag!("if")), expr))) >>
+                (Command::Catch(kind, condition))
+            )
+        ) >>
+        (command)
+    )
+);
+
+named!(break_kind<BreakKind>,
+    alt_complete!(
+        value!(BreakKind::Interrupt(Interrupt::Nmi), tag!("nmi")) |
+        value!(BreakKind::Interrupt(Interrupt::Irq), tag!("irq")) |
+        value!(BreakKind::Interrupt(Interrupt::Brk), tag!("brk")) |
+        value!(BreakKind::VBlank, tag!("vblank")) |
+        do_parse!(
+            tag!("scanline") >>
+            scanline: ws!(usize_parser) >>
+            dot: opt!(complete!(ws!(usize_parser))) >>
+            (BreakKind::Scanline(scanline as u16, dot.unwrap_or(0) as u16))
+        ) |
+        preceded!(ws!(tag!("op")), opcode_kind)
+    )
+);
+
+// Bare hex that isn't a mnemonic is an opcode, so op ADC and op 69 both work
+named!(opcode_kind<BreakKind>,
+    alt_complete!(
+        value!(BreakKind::Unofficial, tag!("unofficial")) |
+        map_opt!(map_res!(take_while1!(is_alphabetic), str::from_utf8), |name: &str| {
+            let name = name.to_uppercase();
+            if disasm::is_mnemonic(&name) { Some(BreakKind::Mnemonic(name)) } else { None }
+        }) |
+        map!(hex_byte, BreakKind::Opcode)
+    )
+);
+
+named!(watch<Command>,
+    do_parse!(
+        alt_complete!(
+            tag!("watch") | tag!("w")
+        ) >>
+        word_end >>
+        kind: ws!(watch_kind) >>
+        low_addr: address >>
+        high_addr: opt!(complete!(preceded!(alt!(char!(',') | char!(':')), address))) >>
+        (Command::Watch(kind, low_addr.clone(), high_addr.unwrap_or(low_addr)))
+    )
+);
+
+named!(watch_kind<WatchKind>,
+    alt_complete!(
+        value!(WatchKind::ReadWrite, tag!("rw")) |
+        value!(WatchKind::Read, tag!("r")) |
+        value!(WatchKind::Write, tag!("w"))
+    )
+);
+
+named!(symbols<Command>,
+    do_parse!(
+        tag!("symbols") >>
+        word_end >>
+        blank >>
+        path: map_res!(take_while1!(|c| c != b'\n'), str::from_utf8) >>
+        (Command::LoadSymbols(path.trim().to_owned()))
+    )
+);
+
+named!(search<Command>,
+    do_parse!(
+        tag!("search") >>
+        word_end >>
+        blank >>
+        command: alt_complete!(
+            value!(Command::SearchStart, tag!("start")) |
+            value!(Command::SearchList, tag!("list")) |
+            value!(Command::Search(Comparison::Ne, None), tag!("changed")) |
+            value!(Command::Search(Comparison::Eq, None), tag!("unchanged")) |
+            do_parse!(
+                comparison: search_comparison >>
+                val: opt!(complete!(ws!(hex_byte))) >>
+                (Command::Search(comparison, val))
+            )
+        ) >>
+        (command)
+    )
+);
+
+named!(search_comparison<Comparison>,
+    alt_complete!(
+        value!(Comparison::Eq, tag!("eq")) |
+        value!(Comparison::Ne, tag!("ne")) |
+        value!(Comparison::Gt, tag!("gt")) |
+        value!(Comparison::Lt, tag!("lt"))
+    )
+);
+
+named!(source<Command>,
+    do_parse!(
+        tag!("source") >>
+        word_end >>
+        blank >>
+        path: map_res!(take_while1!(|c| c != b'\n'), str::from_utf8) >>
+        (Command::Source(path.trim().to_owned()))
+    )
+);
+
+named!(set<Command>,
+    do_parse!(
+        tag!("set") >>
+        word_end >>
+        register: ws!(register) >>
+        val: address >>
+        (Command::Set(register, val))
+    )
+);
+
+named!(register<Register>,
+    alt_complete!(
+        value!(Register::Pc, tag!("pc")) |
+        value!(Register::A, tag!("a")) |
+        value!(Register::X, tag!("x")) |
+        value!(Register::Y, tag!("y")) |
+        value!(Register::S, tag!("sp")) |
+        value!(Register::S, tag!("s")) |
+        value!(Register::P, tag!("p"))
+    )
+);
+
+named!(flag<Command>,
+    do_parse!(
+        tag!("flag") >>
+        word_end >>
+        flag: ws!(flag_name) >>
+        on: alt_complete!(
+            value!(true, tag!("on")) | value!(false, tag!("off"))
+        ) >>
+        (Command::Flag(flag, on))
+    )
+);
+
+named!(flag_name<Flag>,
+    alt_complete!(
+        value!(Flag::Carry, tag!("c")) |
+        value!(Flag::Zero, tag!("z")) |
+        value!(Flag::Interrupt, tag!("i")) |
+        value!(Flag::Decimal, tag!("d")) |
+        value!(Flag::Overflow, tag!("v")) |
+        value!(Flag::Negative, tag!("n"))
+    )
+);
+
+named!(poke<Command>,
+    do_parse!(
+        tag!("poke") >>
+        word_end >>
+        addr: ws!(address) >>
+        val: hex_byte >>
+        (Command::Poke(addr, val))
+    )
+);
+
+named!(fill<Command>,
+    do_parse!(
+        tag!("fill") >>
+        word_end >>
+        low_addr: ws!(address) >>
+        alt!(char!(',') | char!(':')) >>
+        high_addr: ws!(address) >>
+        val: hex_byte >>
+        (Command::Fill(low_addr, high_addr, val))
     )
 );
 
 named!(list<Command>,
     do_parse!(
-        alt!(
-            tag!("l") | tag!("list")
+        alt_complete!(
+            tag!("list") | tag!("l")
         ) >>
+        word_end >>
         (Command::ListBreakPoints)
     )
 );
 
 named!(clear_bp<Command>,
     do_parse!(
-        alt!(
-            tag!("cb") | tag!("clear")
+        alt_complete!(
+            tag!("clear") | tag!("cb")
         ) >>
+        word_end >>
         num: ws!(usize_parser) >>
         (Command::ClearBreakpoint(num))
     )
@@ -102,10 +486,11 @@ named!(clear_bp<Command>,
 
 named!(print<Command>,
     do_parse!(
-        alt!(
-            tag!("p") | tag!("print")
+        alt_complete!(
+            tag!("print") | tag!("p")
         ) >>
-        addr: ws!(hex_primary) >>
+        word_end >>
+        addr: ws!(address) >>
         (Command::Print(addr))
     )
 );
@@ -113,28 +498,180 @@ named!(print<Command>,
 named!(print_range<Command>,
     do_parse!(
         tag!("pr") >>
-        low_addr: ws!(hex_primary) >>
+        word_end >>
+        low_addr: ws!(address) >>
         alt!(char!(',') | char!(':')) >>
-        high_addr: ws!(hex_primary) >>
+        high_addr: ws!(address) >>
         (Command::PrintRange(low_addr, high_addr))
     )
 );
 
+named!(disassemble<Command>,
+    do_parse!(
+        alt_complete!(
+            tag!("disasm") | tag!("d")
+        ) >>
+        word_end >>
+        addr: opt!(complete!(ws!(address))) >>
+        count: opt!(complete!(ws!(usize_parser))) >>
+        (Command::Disassemble(addr, count))
+    )
+);
+
+named!(trace<Command>,
+    do_parse!(
+        tag!("trace") >>
+        word_end >>
+        on: ws!(alt_complete!(
+            value!(true, tag!("on")) | value!(false, tag!("off"))
+        )) >>
+        (Command::Trace(on))
+    )
+);
+
+named!(events<Command>,
+    do_parse!(
+        tag!("events") >>
+        word_end >>
+        command: alt_complete!(
+            do_parse!(
+                ws!(tag!("save")) >>
+                path: map_res!(take_while1!(|c| c != b'\n'), str::from_utf8) >>
+                (Command::SaveEvents(path.trim().to_owned()))
+            ) |
+            value!(Command::Events)
+        ) >>
+        (command)
+    )
+);
+
+named!(view<Command>,
+    do_parse!(
+        tag!("view") >>
+        word_end >>
+        command: ws!(alt_complete!(
+            map!(preceded!(tag!("chr"), ws!(usize_parser)), Command::ChrPalette) |
+            value!(Command::View(View::Chr), tag!("chr")) |
+            value!(Command::View(View::Nametables), alt_complete!(tag!("nametables") | tag!("nt"))) |
+            value!(Command::View(View::Oam), tag!("oam")) |
+            value!(Command::View(View::Palette), alt_complete!(tag!("palette") | tag!("pal"))) |
+            value!(Command::View(View::Hex), tag!("hex")) |
+            value!(Command::View(View::Events), alt_complete!(tag!("events") | tag!("ev")))
+        )) >>
+        (command)
+    )
+);
+
+named!(hex<Command>,
+    do_parse!(
+        tag!("hex") >>
+        word_end >>
+        command: ws!(alt_complete!(
+            do_parse!(
+                tag!("write") >>
+                offset: ws!(hex_offset) >>
+                bytes: many1!(complete!(ws!(hex_byte))) >>
+                (Command::HexWrite(offset, bytes))
+            ) |
+            do_parse!(
+                space: memory_space >>
+                offset: opt!(complete!(ws!(hex_offset))) >>
+                (Command::Hex(space, offset))
+            )
+        )) >>
+        (command)
+    )
+);
+
+named!(memory_space<Space>,
+    alt_complete!(
+        value!(Space::Cpu, tag!("cpu")) |
+        value!(Space::Ppu, tag!("ppu")) |
+        value!(Space::Oam, tag!("oam")) |
+        value!(Space::Prg, tag!("prg")) |
+        value!(Space::Chr, tag!("chr"))
+    )
+);
+
+// ROM offsets can be past $FFFF
+named!(hex_offset<usize>,
+    map_res!(
+        map_res!(
+            preceded!(opt!(alt_complete!(tag!("$") | tag!("0x"))), take_while1!(is_hex_digit)),
+            str::from_utf8
+        ),
+        |s| usize::from_str_radix(s, 16)
+    )
+);
+
+named!(profile<Command>,
+    do_parse!(
+        tag!("profile") >>
+        word_end >>
+        command: ws!(alt_complete!(
+            value!(Command::Profile(true), tag!("on")) |
+            value!(Command::Profile(false), tag!("off")) |
+            value!(Command::ProfileReset, tag!("reset")) |
+            value!(Command::ProfileDump, tag!("dump"))
+        )) >>
+        (command)
+    )
+);
+
+named!(ppu<Command>,
+    do_parse!(
+        tag!("ppu") >>
+        word_end >>
+        (Command::PpuRegisters)
+    )
+);
+
+named!(oam<Command>,
+    do_parse!(
+        tag!("oam") >>
+        word_end >>
+        (Command::Oam)
+    )
+);
+
+named!(palette<Command>,
+    do_parse!(
+        alt_complete!(
+            tag!("palette") | tag!("pal")
+        ) >>
+        word_end >>
+        (Command::Palette)
+    )
+);
+
+named!(nametable<Command>,
+    do_parse!(
+        alt_complete!(
+            tag!("nametable") | tag!("nt")
+        ) >>
+        word_end >>
+        index: ws!(usize_parser) >>
+        (Command::Nametable(index))
+    )
+);
+
 // TODO: Allow help for specific commands
 named!(help<Command>,
     do_parse!(
-        alt!(
-            tag!("h") | tag!("help")
+        alt_complete!(
+            tag!("help") | tag!("h")
         ) >>
+        word_end >>
         (Command::Help)
     )
 );
 
 named!(quit<Command>,
     do_parse!(
-        alt!(
-            tag!("q") | tag!("quit")
+        alt_complete!(
+            tag!("quit") | tag!("q")
         ) >>
+        word_end >>
         (Command::Quit)
     )
 );
@@ -148,3 +685,254 @@ named!(usize_parser<usize>,
         FromStr::from_str
     )
 );
+
+// Expressions for conditional breakpoints. Precedence from lowest to highest:
+// ||, &&, comparisons, bitwise &|^, + and -, unary ! and -
+named!(blank, take_while!(is_space));
+
+named!(pub expr<Expr>,
+    do_parse!(
+        blank >>
+        first: and_expr >>
+        result: fold_many0!(
+            complete!(preceded!(delimited!(blank, tag!("||"), blank), and_expr)),
+            first,
+            |lhs, rhs| Expr::Binary(BinaryOp::Or, Box::new(lhs), Box::new(rhs))
+        ) >>
+        blank >>
+        (result)
+    )
+);
+
+named!(and_expr<Expr>,
+    do_parse!(
+        first: cmp_expr >>
+        result: fold_many0!(
+            complete!(preceded!(delimited!(blank, tag!("&&"), blank), cmp_expr)),
+            first,
+            |lhs, rhs| Expr::Binary(BinaryOp::And, Box::new(lhs), Box::new(rhs))
+        ) >>
+        (result)
+    )
+);
+
+named!(cmp_expr<Expr>,
+    do_parse!(
+        lhs: bit_expr >>
+        rhs: opt!(complete!(pair!(delimited!(blank, cmp_op, blank), bit_expr))) >>
+        (match rhs {
+            Some((op, rhs)) => Expr::Binary(op, Box::new(lhs), Box::new(rhs)),
+            None => lhs,
+        })
+    )
+);
+
+named!(cmp_op<BinaryOp>,
+    alt_complete!(
+        value!(BinaryOp::Eq, tag!("==")) |
+        value!(BinaryOp::Ne, tag!("!=")) |
+        value!(BinaryOp::Le, tag!("<=")) |
+        value!(BinaryOp::Ge, tag!(">=")) |
+        value!(BinaryOp::Lt, tag!("<"))  |
+        value!(BinaryOp::Gt, tag!(">"))  |
+        value!(BinaryOp::Eq, tag!("="))
+    )
+);
+
+named!(bit_expr<Expr>,
+    do_parse!(
+        first: sum_expr >>
+        result: fold_many0!(
+            complete!(pair!(delimited!(blank, bit_op, blank), sum_expr)),
+            first,
+            |lhs, (op, rhs)| Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+        ) >>
+        (result)
+    )
+);
+
+// Single & and | must not eat the first character of && and ||
+named!(bit_op<BinaryOp>,
+    alt_complete!(
+        value!(BinaryOp::BitAnd, terminated!(tag!("&"), not!(complete!(tag!("&"))))) |
+        value!(BinaryOp::BitOr, terminated!(tag!("|"), not!(complete!(tag!("|"))))) |
+        value!(BinaryOp::BitXor, tag!("^"))
+    )
+);
+
+named!(sum_expr<Expr>,
+    do_parse!(
+        first: unary_expr >>
+        result: fold_many0!(
+            complete!(pair!(delimited!(blank, sum_op, blank), unary_expr)),
+            first,
+            |lhs, (op, rhs)| Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+        ) >>
+        (result)
+    )
+);
+
+named!(sum_op<BinaryOp>,
+    alt_complete!(
+        value!(BinaryOp::Add, tag!("+")) |
+        value!(BinaryOp::Sub, tag!("-"))
+    )
+);
+
+named!(unary_expr<Expr>,
+    alt_complete!(
+        map!(preceded!(terminated!(tag!("!"), blank), unary_expr), |e| Expr::Unary(UnaryOp::Not, Box::new(e))) |
+        map!(preceded!(terminated!(tag!("-"), blank), unary_expr), |e| Expr::Unary(UnaryOp::Neg, Box::new(e))) |
+        primary_expr
+    )
+);
+
+named!(primary_expr<Expr>,
+    alt_complete!(
+        map!(number, Expr::Number) |
+        map!(delimited!(tag!("["), expr, tag!("]")), |addr| Expr::Memory(Box::new(addr))) |
+        delimited!(tag!("("), expr, tag!(")")) |
+        map!(variable, Expr::Variable) |
+        map!(map_res!(take_while1!(is_symbol_char), str::from_utf8), |name: &str| Expr::Label(name.to_owned()))
+    )
+);
+
+// Numbers are hex, the same as addresses and values in commands. Without a '$' or
+// '0x' prefix they have to start with a digit, otherwise A is the register and
+// names like FF are labels, which fall back to hex.
+named!(number<i64>,
+    map_res!(
+        alt_complete!(
+            preceded!(alt_complete!(tag!("$") | tag!("0x")), take_while1!(is_hex_digit)) |
+            bare_hex
+        ),
+        from_hex_i64
+    )
+);
+
+fn bare_hex(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    match input.first() {
+        Some(c) if c.is_ascii_digit() => take_while1!(input, is_hex_digit),
+        _ => IResult::Error(error_position!(ErrorKind::Custom(0), input)),
+    }
+}
+
+named!(variable<Variable>,
+    map_opt!(
+        map_res!(take_while1!(is_symbol_char), str::from_utf8),
+        Variable::from_name
+    )
+);
+
+fn from_hex_i64(input: &[u8]) -> Result<i64, num::ParseIntError> {
+    let hex_string = str::from_utf8(input).unwrap_or("0");
+    i64::from_str_radix(hex_string, 16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Command {
+        s.parse().unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    fn parse_expr(s: &str) -> String {
+        match expr(s.as_bytes()) {
+            IResult::Done(&[], e) => e.to_string(),
+            err => panic!("Unable to parse expression {}: {:?}", s, err),
+        }
+    }
+
+    #[test]
+    fn expression_precedence() {
+        assert_eq!(parse_expr("A == $10 && [$0300] != 0"), "((A == $10) && ([$300] != $0))");
+        assert_eq!(parse_expr("x + 1 & $F0 == 0 || c"), "((((X + $1) & $F0) == $0) || Carry)");
+        assert_eq!(parse_expr("!z && -1 < y"), "(!Zero && (-$1 < Y))");
+        assert_eq!(parse_expr("(a | 1) ^ 2"), "((A | $1) ^ $2)");
+        assert_eq!(parse_expr("sl >= $F1 && pc = nmi_handler"), "((Scanline >= $F1) && (Pc == nmi_handler))");
+    }
+
+    #[test]
+    fn conditional_breakpoints() {
+        match parse("b C000 if a == 0") {
+            Command::Breakpoint(Address::Name(ref name), Some(ref e)) if name == "C000" => {
+                assert_eq!(e.to_string(), "(A == $0)");
+            }
+            c => panic!("{:?}", c),
+        }
+        match parse("catch nmi if frame > 10") {
+            Command::Catch(BreakKind::Interrupt(Interrupt::Nmi), Some(ref e)) => {
+                assert_eq!(e.to_string(), "(Frame > $10)");
+            }
+            c => panic!("{:?}", c),
+        }
+        match parse("catch write $2001 if [$2001] & $18") {
+            Command::CatchWrite(Address::Absolute(0x2001), Some(_)) => {}
+            c => panic!("{:?}", c),
+        }
+        assert!("break C000 if".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn numbers_are_hex() {
+        // Bare numbers in expressions are hex like everywhere else, names fall back to hex too
+        assert_eq!(parse_expr("[0300] == 10"), "([$300] == $10)");
+        assert_eq!(parse_expr("[$0300] + 0x1F"), "([$300] + $1F)");
+        assert_eq!(parse_expr("a == FF"), "(A == FF)");
+        assert!(Expr::Label("FF".to_owned()).unknown_label(&Symbols::default()).is_none());
+
+        for s in &["poke $10 $FF", "poke 0x10 0xff", "poke 10 FF"] {
+            match parse(s) { Command::Poke(_, 0xFF) => {}, c => panic!("{:?}", c) }
+        }
+        match parse("fill 0:F $20") { Command::Fill(_, _, 0x20) => {}, c => panic!("{:?}", c) }
+        match parse("search eq $FF") { Command::Search(Comparison::Eq, Some(0xFF)) => {}, c => panic!("{:?}", c) }
+        match parse("hex write 0 $01 0x02 3") { Command::HexWrite(0, ref bytes) if *bytes == [1, 2, 3] => {}, c => panic!("{:?}", c) }
+        match parse("set a $FF") { Command::Set(Register::A, Address::Absolute(0xFF)) => {}, c => panic!("{:?}", c) }
+        for s in &["catch op $69", "catch op 0x69", "catch op 69"] {
+            match parse(s) { Command::Catch(BreakKind::Opcode(0x69), None) => {}, c => panic!("{:?}", c) }
+        }
+        match parse("catch op adc") { Command::Catch(BreakKind::Mnemonic(ref m), None) if m == "ADC" => {}, c => panic!("{:?}", c) }
+        assert!("poke 0 100".parse::<Command>().is_err());
+        assert!("poke 0 $".parse::<Command>().is_err());
+    }
+
+    #[test]
+    fn abbreviations() {
+        match parse("s") { Command::Step(1) => {}, c => panic!("{:?}", c) }
+        match parse("step 5") { Command::Step(5) => {}, c => panic!("{:?}", c) }
+        match parse("r") { Command::Run => {}, c => panic!("{:?}", c) }
+        match parse("c") { Command::Run => {}, c => panic!("{:?}", c) }
+        match parse("n") { Command::Next => {}, c => panic!("{:?}", c) }
+        match parse("fin") { Command::Finish => {}, c => panic!("{:?}", c) }
+        match parse("bt") { Command::Backtrace => {}, c => panic!("{:?}", c) }
+        match parse("l") { Command::ListBreakPoints => {}, c => panic!("{:?}", c) }
+        match parse("cb 2") { Command::ClearBreakpoint(2) => {}, c => panic!("{:?}", c) }
+        match parse("p $10") { Command::Print(Address::Absolute(0x10)) => {}, c => panic!("{:?}", c) }
+        match parse("pr 0:F") { Command::PrintRange(..) => {}, c => panic!("{:?}", c) }
+        match parse("d") { Command::Disassemble(None, None) => {}, c => panic!("{:?}", c) }
+        match parse("pal") { Command::Palette => {}, c => panic!("{:?}", c) }
+        match parse("nt 1") { Command::Nametable(1) => {}, c => panic!("{:?}", c) }
+        match parse("h") { Command::Help => {}, c => panic!("{:?}", c) }
+        match parse("hex cpu 2000") { Command::Hex(Space::Cpu, Some(0x2000)) => {}, c => panic!("{:?}", c) }
+        match parse("q") { Command::Quit => {}, c => panic!("{:?}", c) }
+    }
+
+    #[test]
+    fn abbreviations_need_a_separator() {
+        for s in &["pc", "nmi", "bread", "sx", "cat", "helpme", "quitx"] {
+            assert!(s.parse::<Command>().is_err(), "{} parsed", s);
+        }
+        match parse("p c") { Command::Print(Address::Name(ref name)) if name == "c" => {}, c => panic!("{:?}", c) }
+    }
+
+    #[test]
+    fn only_safe_commands_repeat() {
+        for s in &["s", "n", "c", "fin", "frame", "p 0", "pr 0:F", "d"] {
+            assert!(parse(s).is_repeatable(), "{} doesn't repeat", s);
+        }
+        for s in &["poke 0 1", "fill 0:F 1", "hex write 0 1", "set a 1", "source x", "b C000"] {
+            assert!(!parse(s).is_repeatable(), "{} repeats", s);
+        }
+    }
+}
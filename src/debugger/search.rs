@@ -0,0 +1,49 @@
+//! RAM search for finding where games keep lives, health, positions etc.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Comparison {
+    Eq,
+    Ne,
+    Gt,
+    Lt,
+}
+
+// Candidate addresses in CPU RAM, narrowed by comparing against a value or
+// against what the address held at the previous search
+pub struct RamSearch {
+    candidates: Vec<u16>,
+    snapshot: Vec<u8>,
+}
+
+impl RamSearch {
+    pub fn start(ram: &[u8]) -> Self {
+        RamSearch {
+            candidates: (0..ram.len() as u16).collect(),
+            snapshot: ram.to_vec(),
+        }
+    }
+
+    // Without a value, compares each address to its previous value, so gt means increased
+    pub fn filter(&mut self, ram: &[u8], comparison: Comparison, value: Option<u8>) {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|&addr| {
+            let current = ram[addr as usize];
+            let other = value.unwrap_or(snapshot[addr as usize]);
+            match comparison {
+                Comparison::Eq => current == other,
+                Comparison::Ne => current != other,
+                Comparison::Gt => current > other,
+                Comparison::Lt => current < other,
+            }
+        });
+        self.snapshot.copy_from_slice(ram);
+    }
+
+    pub fn candidates(&self) -> &[u16] {
+        &self.candidates
+    }
+
+    pub fn previous(&self, addr: u16) -> u8 {
+        self.snapshot[addr as usize]
+    }
+}
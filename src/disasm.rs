@@ -0,0 +1,413 @@
+//! 6502 disassembler, including the unofficial opcodes
+
+use std::fmt;
+
+use super::symbols::Symbols;
+
+use self::Mode::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Imp,  // Implied
+    Acc,  // Accumulator
+    Imm,  // #$nn
+    Zp,   // $nn
+    ZpX,  // $nn,X
+    ZpY,  // $nn,Y
+    Abs,  // $nnnn
+    AbsX, // $nnnn,X
+    AbsY, // $nnnn,Y
+    Ind,  // ($nnnn)
+    IndX, // ($nn,X)
+    IndY, // ($nn),Y
+    Rel,  // Branch offset
+}
+
+// Unofficial opcodes are prefixed with '*', as in nestest.log
+const OPCODES: [(&str, Mode); 256] = [
+    // 0
+    ("BRK", Imp), ("ORA", IndX), ("*KIL", Imp), ("*SLO", IndX),
+    ("*NOP", Zp), ("ORA", Zp), ("ASL", Zp), ("*SLO", Zp),
+    ("PHP", Imp), ("ORA", Imm), ("ASL", Acc), ("*ANC", Imm),
+    ("*NOP", Abs), ("ORA", Abs), ("ASL", Abs), ("*SLO", Abs),
+    // 1
+    ("BPL", Rel), ("ORA", IndY), ("*KIL", Imp), ("*SLO", IndY),
+    ("*NOP", ZpX), ("ORA", ZpX), ("ASL", ZpX), ("*SLO", ZpX),
+    ("CLC", Imp), ("ORA", AbsY), ("*NOP", Imp), ("*SLO", AbsY),
+    ("*NOP", AbsX), ("ORA", AbsX), ("ASL", AbsX), ("*SLO", AbsX),
+    // 2
+    ("JSR", Abs), ("AND", IndX), ("*KIL", Imp), ("*RLA", IndX),
+    ("BIT", Zp), ("AND", Zp), ("ROL", Zp), ("*RLA", Zp),
+    ("PLP", Imp), ("AND", Imm), ("ROL", Acc), ("*ANC", Imm),
+    ("BIT", Abs), ("AND", Abs), ("ROL", Abs), ("*RLA", Abs),
+    // 3
+    ("BMI", Rel), ("AND", IndY), ("*KIL", Imp), ("*RLA", IndY),
+    ("*NOP", ZpX), ("AND", ZpX), ("ROL", ZpX), ("*RLA", ZpX),
+    ("SEC", Imp), ("AND", AbsY), ("*NOP", Imp), ("*RLA", AbsY),
+    ("*NOP", AbsX), ("AND", AbsX), ("ROL", AbsX), ("*RLA", AbsX),
+    // 4
+    ("RTI", Imp), ("EOR", IndX), ("*KIL", Imp), ("*SRE", IndX),
+    ("*NOP", Zp), ("EOR", Zp), ("LSR", Zp), ("*SRE", Zp),
+    ("PHA", Imp), ("EOR", Imm), ("LSR", Acc), ("*ALR", Imm),
+    ("JMP", Abs), ("EOR", Abs), ("LSR", Abs), ("*SRE", Abs),
+    // 5
+    ("BVC", Rel), ("EOR", IndY), ("*KIL", Imp), ("*SRE", IndY),
+    ("*NOP", ZpX), ("EOR", ZpX), ("LSR", ZpX), ("*SRE", ZpX),
+    ("CLI", Imp), ("EOR", AbsY), ("*NOP", Imp), ("*SRE", AbsY),
+    ("*NOP", AbsX), ("EOR", AbsX), ("LSR", AbsX), ("*SRE", AbsX),
+    // 6
+    ("RTS", Imp), ("ADC", IndX), ("*KIL", Imp), ("*RRA", IndX),
+    ("*NOP", Zp), ("ADC", Zp), ("ROR", Zp), ("*RRA", Zp),
+    ("PLA", Imp), ("ADC", Imm), ("ROR", Acc), ("*ARR", Imm),
+    ("JMP", Ind), ("ADC", Abs), ("ROR", Abs), ("*RRA", Abs),
+    // 7
+    ("BVS", Rel), ("ADC", IndY), ("*KIL", Imp), ("*RRA", IndY),
+    ("*NOP", ZpX), ("ADC", ZpX), ("ROR", ZpX), ("*RRA", ZpX),
+    ("SEI", Imp), ("ADC", AbsY), ("*NOP", Imp), ("*RRA", AbsY),
+    ("*NOP", AbsX), ("ADC", AbsX), ("ROR", AbsX), ("*RRA", AbsX),
+    // 8
+    ("*NOP", Imm), ("STA", IndX), ("*NOP", Imm), ("*SAX", IndX),
+    ("STY", Zp), ("STA", Zp), ("STX", Zp), ("*SAX", Zp),
+    ("DEY", Imp), ("*NOP", Imm), ("TXA", Imp), ("*XAA", Imm),
+    ("STY", Abs), ("STA", Abs), ("STX", Abs), ("*SAX", Abs),
+    // 9
+    ("BCC", Rel), ("STA", IndY), ("*KIL", Imp), ("*AHX", IndY),
+    ("STY", ZpX), ("STA", ZpX), ("STX", ZpY), ("*SAX", ZpY),
+    ("TYA", Imp), ("STA", AbsY), ("TXS", Imp), ("*TAS", AbsY),
+    ("*SHY", AbsX), ("STA", AbsX), ("*SHX", AbsY), ("*AHX", AbsY),
+    // A
+    ("LDY", Imm), ("LDA", IndX), ("LDX", Imm), ("*LAX", IndX),
+    ("LDY", Zp), ("LDA", Zp), ("LDX", Zp), ("*LAX", Zp),
+    ("TAY", Imp), ("LDA", Imm), ("TAX", Imp), ("*LAX", Imm),
+    ("LDY", Abs), ("LDA", Abs), ("LDX", Abs), ("*LAX", Abs),
+    // B
+    ("BCS", Rel), ("LDA", IndY), ("*KIL", Imp), ("*LAX", IndY),
+    ("LDY", ZpX), ("LDA", ZpX), ("LDX", ZpY), ("*LAX", ZpY),
+    ("CLV", Imp), ("LDA", AbsY), ("TSX", Imp), ("*LAS", AbsY),
+    ("LDY", AbsX), ("LDA", AbsX), ("LDX", AbsY), ("*LAX", AbsY),
+    // C
+    ("CPY", Imm), ("CMP", IndX), ("*NOP", Imm), ("*DCP", IndX),
+    ("CPY", Zp), ("CMP", Zp), ("DEC", Zp), ("*DCP", Zp),
+    ("INY", Imp), ("CMP", Imm), ("DEX", Imp), ("*AXS", Imm),
+    ("CPY", Abs), ("CMP", Abs), ("DEC", Abs), ("*DCP", Abs),
+    // D
+    ("BNE", Rel), ("CMP", IndY), ("*KIL", Imp), ("*DCP", IndY),
+    ("*NOP", ZpX), ("CMP", ZpX), ("DEC", ZpX), ("*DCP", ZpX),
+    ("CLD", Imp), ("CMP", AbsY), ("*NOP", Imp), ("*DCP", AbsY),
+    ("*NOP", AbsX), ("CMP", AbsX), ("DEC", AbsX), ("*DCP", AbsX),
+    // E
+    ("CPX", Imm), ("SBC", IndX), ("*NOP", Imm), ("*ISB", IndX),
+    ("CPX", Zp), ("SBC", Zp), ("INC", Zp), ("*ISB", Zp),
+    ("INX", Imp), ("SBC", Imm), ("NOP", Imp), ("*SBC", Imm),
+    ("CPX", Abs), ("SBC", Abs), ("INC", Abs), ("*ISB", Abs),
+    // F
+    ("BEQ", Rel), ("SBC", IndY), ("*KIL", Imp), ("*ISB", IndY),
+    ("*NOP", ZpX), ("SBC", ZpX), ("INC", ZpX), ("*ISB", ZpX),
+    ("SED", Imp), ("SBC", AbsY), ("*NOP", Imp), ("*ISB", AbsY),
+    ("*NOP", AbsX), ("SBC", AbsX), ("INC", AbsX), ("*ISB", AbsX),
+];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Instruction {
+    pub addr: u16,
+    pub opcode: u8,
+    pub operand: u16,
+    pub mnemonic: &'static str,
+    pub mode: Mode,
+}
+
+impl Mode {
+    pub fn operand_len(&self) -> u16 {
+        match *self {
+            Imp | Acc => 0,
+            Imm | Zp | ZpX | ZpY | IndX | IndY | Rel => 1,
+            Abs | AbsX | AbsY | Ind => 2,
+        }
+    }
+}
+
+// Decodes the instruction at addr, reading its bytes with read
+pub fn decode<F: FnMut(u16) -> u8>(addr: u16, mut read: F) -> Instruction {
+    let opcode = read(addr);
+    let (mnemonic, mode) = OPCODES[opcode as usize];
+    let operand = match mode.operand_len() {
+        0 => 0,
+        1 => read(addr.wrapping_add(1)) as u16,
+        _ => read(addr.wrapping_add(1)) as u16 | (read(addr.wrapping_add(2)) as u16) << 8,
+    };
+    Instruction {
+        addr,
+        opcode,
+        operand,
+        mnemonic,
+        mode,
+    }
+}
+
+// Mnemonics are matched without the * marking unofficial opcodes
+pub fn is_mnemonic(name: &str) -> bool {
+    OPCODES.iter().any(|&(mnemonic, _)| mnemonic.trim_start_matches('*') == name)
+}
+
+pub fn is_official(opcode: u8) -> bool {
+    !OPCODES[opcode as usize].0.starts_with('*')
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        1 + self.mode.operand_len()
+    }
+
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.len())
+    }
+
+    // Stores and read-modify-writes, official or not
+    pub fn writes_memory(&self) -> bool {
+        match self.mnemonic.trim_start_matches('*') {
+            "STA" | "STX" | "STY" | "SAX" | "AHX" | "SHX" | "SHY" | "TAS" => true,
+            "INC" | "DEC" | "SLO" | "RLA" | "SRE" | "RRA" | "DCP" | "ISB" => true,
+            "ASL" | "LSR" | "ROL" | "ROR" => self.mode != Acc,
+            _ => false,
+        }
+    }
+
+    pub fn bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![self.opcode];
+        if self.mode.operand_len() >= 1 { bytes.push(self.operand as u8); }
+        if self.mode.operand_len() == 2 { bytes.push((self.operand >> 8) as u8); }
+        bytes
+    }
+
+    pub fn branch_target(&self) -> Option<u16> {
+        match self.mode {
+            Rel => Some(self.next_addr().wrapping_add(self.operand as u8 as i8 as u16)),
+            _ => None,
+        }
+    }
+
+    // JMP and JSR operands are code addresses, not memory being accessed
+    fn is_jump(&self) -> bool {
+        self.opcode == 0x4C || self.opcode == 0x20
+    }
+
+    // Addresses with a label are shown by name
+    pub fn operand_string(&self, symbols: &Symbols) -> String {
+        let addr = |addr: u16, digits: usize| match symbols.label(addr) {
+            Some(label) => label.to_owned(),
+            None => format!("${:01$X}", addr, digits),
+        };
+        match self.mode {
+            Imp => String::new(),
+            Acc => "A".to_owned(),
+            Imm => format!("#${:02X}", self.operand),
+            Zp => addr(self.operand, 2),
+            ZpX => format!("{},X", addr(self.operand, 2)),
+            ZpY => format!("{},Y", addr(self.operand, 2)),
+            Abs => addr(self.operand, 4),
+            AbsX => format!("{},X", addr(self.operand, 4)),
+            AbsY => format!("{},Y", addr(self.operand, 4)),
+            Ind => format!("({})", addr(self.operand, 4)),
+            IndX => format!("({},X)", addr(self.operand, 2)),
+            IndY => format!("({}),Y", addr(self.operand, 2)),
+            Rel => addr(self.branch_target().unwrap(), 4),
+        }
+    }
+
+    // Address of the memory the instruction accesses given the index registers
+    pub fn effective_addr<F: FnMut(u16) -> u8>(&self, x: u8, y: u8, mut read: F) -> Option<u16> {
+        let mut zero_page_word = |ptr: u8| read(ptr as u16) as u16 | (read(ptr.wrapping_add(1) as u16) as u16) << 8;
+        match self.mode {
+            Zp => Some(self.operand),
+            ZpX => Some((self.operand as u8).wrapping_add(x) as u16),
+            ZpY => Some((self.operand as u8).wrapping_add(y) as u16),
+            Abs if !self.is_jump() => Some(self.operand),
+            AbsX => Some(self.operand.wrapping_add(x as u16)),
+            AbsY => Some(self.operand.wrapping_add(y as u16)),
+            IndX => Some(zero_page_word((self.operand as u8).wrapping_add(x))),
+            IndY => Some(zero_page_word(self.operand as u8).wrapping_add(y as u16)),
+            _ => None,
+        }
+    }
+
+    // Operand with the addresses and values it resolves to, in the style of nestest.log
+    pub fn annotated_operand<F>(&self, x: u8, y: u8, mut read: F, symbols: &Symbols) -> String
+        where F: FnMut(u16) -> u8
+    {
+        let operand = self.operand_string(symbols);
+        let effective = self.effective_addr(x, y, &mut read);
+        match (self.mode, effective) {
+            (Zp, Some(addr)) | (Abs, Some(addr)) => format!("{} = {:02X}", operand, read(addr)),
+            (ZpX, Some(addr)) | (ZpY, Some(addr)) => {
+                format!("{} @ {:02X} = {:02X}", operand, addr, read(addr))
+            }
+            (AbsX, Some(addr)) | (AbsY, Some(addr)) => {
+                format!("{} @ {:04X} = {:02X}", operand, addr, read(addr))
+            }
+            (IndX, Some(addr)) => {
+                let ptr = (self.operand as u8).wrapping_add(x);
+                format!("{} @ {:02X} = {:04X} = {:02X}", operand, ptr, addr, read(addr))
+            }
+            (IndY, Some(addr)) => {
+                let base = addr.wrapping_sub(y as u16);
+                format!("{} = {:04X} @ {:04X} = {:02X}", operand, base, addr, read(addr))
+            }
+            (Ind, _) => {
+                // JMP ($xxFF) wraps within the page
+                let hi_addr = (self.operand & 0xFF00) | (self.operand as u8).wrapping_add(1) as u16;
+                let target = read(self.operand) as u16 | (read(hi_addr) as u16) << 8;
+                format!("{} = {:04X}", operand, target)
+            }
+            _ => operand,
+        }
+    }
+
+    // Full line with the annotated operand, e.g. "C72A  B1 89     LDA ($89),Y = 0300 @ 0300 = 89"
+    pub fn annotated<F: FnMut(u16) -> u8>(&self, x: u8, y: u8, read: F, symbols: &Symbols) -> String {
+        let operand = self.annotated_operand(x, y, read, symbols);
+        self.format_line(&operand)
+    }
+
+    pub fn line(&self, symbols: &Symbols) -> String {
+        self.format_line(&self.operand_string(symbols))
+    }
+
+    fn format_line(&self, operand: &str) -> String {
+        let bytes: Vec<String> = self.bytes().iter().map(|b| format!("{:02X}", b)).collect();
+        let line = format!("{:04X}  {:<8} {:>4}", self.addr, bytes.join(" "), self.mnemonic);
+        if operand.is_empty() { line } else { format!("{} {}", line, operand) }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.line(&Symbols::default()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Memory for the operand lookups, zero page $FF/$00 points at $1234 for the wraparound cases
+    fn memory() -> Vec<u8> {
+        let mut mem = vec![0; 0x10000];
+        mem[0x0000] = 0x12;
+        mem[0x00FF] = 0x34;
+        mem[0x0010] = 0x00;
+        mem[0x0011] = 0x03;
+        mem[0x0200] = 0x03; // High byte of JMP ($02FF)
+        mem[0x02FF] = 0x00;
+        mem[0x0300] = 0x89;
+        mem[0x1234] = 0x55;
+        mem[0x1244] = 0x66;
+        mem
+    }
+
+    fn decode_bytes(addr: u16, bytes: &[u8]) -> Instruction {
+        decode(addr, |a| bytes.get(a.wrapping_sub(addr) as usize).cloned().unwrap_or(0))
+    }
+
+    #[test]
+    fn opcode_table() {
+        assert_eq!((0..=255).filter(|&op| is_official(op)).count(), 151);
+        for &(opcode, mnemonic, mode) in &[
+            (0x00, "BRK", Imp), (0x0A, "ASL", Acc), (0xA9, "LDA", Imm), (0xA5, "LDA", Zp),
+            (0xB5, "LDA", ZpX), (0xB6, "LDX", ZpY), (0xAD, "LDA", Abs), (0xBD, "LDA", AbsX),
+            (0xB9, "LDA", AbsY), (0x6C, "JMP", Ind), (0xA1, "LDA", IndX), (0xB1, "LDA", IndY),
+            (0xF0, "BEQ", Rel), (0x20, "JSR", Abs), (0xEA, "NOP", Imp), (0x1A, "*NOP", Imp),
+            (0xA7, "*LAX", Zp), (0xEB, "*SBC", Imm), (0x02, "*KIL", Imp),
+        ] {
+            assert_eq!(OPCODES[opcode as usize], (mnemonic, mode), "opcode {:02X}", opcode);
+        }
+        assert!(is_mnemonic("LDA") && is_mnemonic("LAX"));
+        assert!(!is_mnemonic("*LAX") && !is_mnemonic("FOO"));
+    }
+
+    #[test]
+    fn decode_and_format() {
+        for &(bytes, line) in &[
+            (&[0xEA][..], "C000  EA        NOP"),
+            (&[0x0A][..], "C000  0A        ASL A"),
+            (&[0xA9, 0x10][..], "C000  A9 10     LDA #$10"),
+            (&[0xA5, 0x10][..], "C000  A5 10     LDA $10"),
+            (&[0xB5, 0x10][..], "C000  B5 10     LDA $10,X"),
+            (&[0xB6, 0x10][..], "C000  B6 10     LDX $10,Y"),
+            (&[0xAD, 0x34, 0x12][..], "C000  AD 34 12  LDA $1234"),
+            (&[0xBD, 0x34, 0x12][..], "C000  BD 34 12  LDA $1234,X"),
+            (&[0xB9, 0x34, 0x12][..], "C000  B9 34 12  LDA $1234,Y"),
+            (&[0x6C, 0xFF, 0x02][..], "C000  6C FF 02  JMP ($02FF)"),
+            (&[0xA1, 0x10][..], "C000  A1 10     LDA ($10,X)"),
+            (&[0xB1, 0xFF][..], "C000  B1 FF     LDA ($FF),Y"),
+            (&[0xD0, 0x05][..], "C000  D0 05     BNE $C007"),
+            (&[0xD0, 0xFC][..], "C000  D0 FC     BNE $BFFE"),
+            (&[0x04, 0x10][..], "C000  04 10    *NOP $10"),
+        ] {
+            let instruction = decode_bytes(0xC000, bytes);
+            assert_eq!(instruction.to_string(), line);
+            assert_eq!(instruction.bytes(), bytes);
+            assert_eq!(instruction.len() as usize, bytes.len());
+            assert_eq!(instruction.next_addr(), 0xC000 + bytes.len() as u16);
+        }
+    }
+
+    #[test]
+    fn branch_targets() {
+        for &(addr, offset, target) in &[
+            (0xC000, 0x00, Some(0xC002)),
+            (0xC000, 0x7F, Some(0xC081)),
+            (0xC000, 0x80, Some(0xBF82)), // -128
+            (0xC010, 0xFE, Some(0xC010)), // Branch to itself
+            (0xFFFE, 0x01, Some(0x0001)),
+            (0x0000, 0xF0, Some(0xFFF2)),
+        ] {
+            assert_eq!(decode_bytes(addr, &[0xD0, offset]).branch_target(), target, "{:04X} {:02X}", addr, offset);
+        }
+        assert_eq!(decode_bytes(0xC000, &[0x4C, 0x00, 0xC0]).branch_target(), None);
+    }
+
+    #[test]
+    fn effective_addresses() {
+        let mem = memory();
+        for &(bytes, x, y, addr) in &[
+            (&[0xA5, 0x10][..], 0, 0, Some(0x0010)),
+            (&[0xB5, 0xFF][..], 2, 0, Some(0x0001)), // Zero page indexing wraps
+            (&[0xB6, 0xF0][..], 0, 0x20, Some(0x0010)),
+            (&[0xAD, 0x34, 0x12][..], 0, 0, Some(0x1234)),
+            (&[0x4C, 0x34, 0x12][..], 0, 0, None), // JMP and JSR don't access their operand
+            (&[0x20, 0x34, 0x12][..], 0, 0, None),
+            (&[0xBD, 0xFF, 0x12][..], 1, 0, Some(0x1300)),
+            (&[0xB9, 0xFF, 0xFF][..], 0, 2, Some(0x0001)),
+            (&[0xA1, 0xFE][..], 1, 0, Some(0x1234)), // Pointer at $FF/$00
+            (&[0xB1, 0xFF][..], 0, 0x10, Some(0x1244)),
+            (&[0xB1, 0x10][..], 0, 0x10, Some(0x0310)),
+            (&[0xA9, 0x10][..], 0, 0, None),
+            (&[0x0A][..], 0, 0, None),
+            (&[0xD0, 0x10][..], 0, 0, None),
+            (&[0x6C, 0xFF, 0x02][..], 0, 0, None),
+        ] {
+            let instruction = decode_bytes(0xC000, bytes);
+            assert_eq!(instruction.effective_addr(x, y, |a| mem[a as usize]), addr, "{}", instruction);
+        }
+    }
+
+    #[test]
+    fn annotated_operands() {
+        let mem = memory();
+        let symbols = Symbols::default();
+        for &(bytes, x, y, operand) in &[
+            (&[0xA5, 0xFF][..], 0, 0, "$FF = 34"),
+            (&[0xB5, 0xFF][..], 1, 0, "$FF,X @ 00 = 12"),
+            (&[0xAD, 0x34, 0x12][..], 0, 0, "$1234 = 55"),
+            (&[0xBD, 0x34, 0x12][..], 0x10, 0, "$1234,X @ 1244 = 66"),
+            (&[0xA1, 0x0F][..], 1, 0, "($0F,X) @ 10 = 0300 = 89"),
+            (&[0xB1, 0xFF][..], 0, 0x10, "($FF),Y = 1234 @ 1244 = 66"),
+            (&[0x6C, 0xFF, 0x02][..], 0, 0, "($02FF) = 0300"), // High byte comes from $0200
+            (&[0x4C, 0x34, 0x12][..], 0, 0, "$1234"),
+            (&[0xA9, 0x10][..], 0, 0, "#$10"),
+        ] {
+            let instruction = decode_bytes(0xC000, bytes);
+            assert_eq!(instruction.annotated_operand(x, y, |a| mem[a as usize], &symbols), operand);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Where on the 341x262 dot grid register writes and interrupts happened, for
+//! debugging raster effects. Only the CPU's own stores are logged, not debugger pokes.
+
+use std::mem;
+
+use super::ppu::Ppu;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventKind {
+    PpuWrite, // Including OAM DMA
+    ApuWrite,
+    MapperWrite,
+    Nmi,
+    Irq,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Event {
+    pub kind: EventKind,
+    pub addr: u16, // The handler for interrupts
+    pub value: u8,
+    pub scanline: u16,
+    pub dot: u16,
+}
+
+// Events of the frame the PPU is drawing and of the one before it
+pub struct EventLog {
+    frame: usize,
+    current: Vec<Event>,
+    last: Vec<Event>,
+}
+
+impl EventLog {
+    pub fn new() -> Self {
+        EventLog {
+            frame: 0,
+            current: Vec::new(),
+            last: Vec::new(),
+        }
+    }
+
+    // The PPU catches up after each instruction, so writes are placed where the
+    // instruction started
+    pub fn log_write(&mut self, ppu: &Ppu, addr: u16, value: u8) {
+        let kind = match addr {
+            0x2000...0x3FFF | 0x4014 => EventKind::PpuWrite,
+            0x4000...0x4013 | 0x4015 | 0x4017 => EventKind::ApuWrite,
+            0x4020...0x5FFF | 0x8000...0xFFFF => EventKind::MapperWrite, // $6000-$7FFF is PRG RAM
+            _ => return,
+        };
+        self.log(ppu, kind, addr, value);
+    }
+
+    pub fn log_interrupt(&mut self, ppu: &Ppu, kind: EventKind, handler: u16) {
+        self.log(ppu, kind, handler, 0);
+    }
+
+    fn log(&mut self, ppu: &Ppu, kind: EventKind, addr: u16, value: u8) {
+        if ppu.frame() != self.frame {
+            mem::swap(&mut self.last, &mut self.current);
+            self.current.clear();
+            if ppu.frame() != self.frame + 1 {
+                self.last.clear();
+            }
+            self.frame = ppu.frame();
+        }
+        self.current.push(Event {
+            kind: kind,
+            addr: addr,
+            value: value,
+            scanline: ppu.scanline(),
+            dot: ppu.dot(),
+        });
+    }
+
+    // The previous frame's events and the current frame's so far, as of frame.
+    // Frames without any events leave the log behind, so it is checked here.
+    pub fn frames(&self, frame: usize) -> (&[Event], &[Event]) {
+        if frame == self.frame {
+            (&self.last, &self.current)
+        } else if frame == self.frame + 1 {
+            (&self.current, &[])
+        } else {
+            (&[], &[])
+        }
+    }
+}
@@ -0,0 +1,771 @@
+//! Famicom Disk System RAM adapter, disk drive and wavetable expansion audio
+
+use super::cart::Mapper;
+use super::nes::KILOBYTE;
+use super::rom::{Rom, Mirroring, FDS_SIDE_SIZE};
+
+// Gap lengths are given in bits on the real disk
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const BLOCK_START_MARK: u8 = 0x80;
+
+// The drive transfers roughly 96.4 kbit/s, or one byte every ~150 CPU cycles
+const BYTE_TRANSFER_CYCLES: usize = 150;
+const HEAD_RETURN_CYCLES: usize = 50000;
+// The BIOS needs to see the disk ejected for a while before a new side goes in
+const SIDE_SWAP_CYCLES: usize = 1789773;
+
+pub struct Fds {
+    prg_ram: Vec<u8>,
+    chr_ram: Vec<u8>,
+    bios: Vec<u8>,
+
+    sides: Vec<Vec<u8>>,
+    current_side: Option<usize>,
+    pending_side: Option<usize>,
+    swap_delay: usize,
+
+    timer: Timer,
+    drive: Drive,
+    audio: FdsAudio,
+
+    disk_io_enabled: bool,
+    sound_io_enabled: bool,
+    mirroring: Mirroring,
+    ext_connector: u8,
+}
+
+#[derive(Default)]
+struct Timer {
+    reload: u16,
+    counter: u16,
+    repeat: bool,
+    enabled: bool,
+    irq: bool,
+}
+
+#[derive(Default)]
+struct Drive {
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    previous_crc_control: bool,
+    ready: bool,
+    irq_enabled: bool,
+
+    read_data: u8,
+    write_data: u8,
+    transfer_complete: bool,
+    irq: bool,
+
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    position: usize,
+    delay: usize,
+    crc: u16,
+}
+
+impl Fds {
+    pub fn new(rom: Rom, bios: Vec<u8>) -> Self {
+        let sides: Vec<Vec<u8>> = rom.prg_rom.chunks(FDS_SIDE_SIZE).map(build_raw_side).collect();
+
+        Fds {
+            prg_ram: vec![0; 32 * KILOBYTE],
+            chr_ram: vec![0; 8 * KILOBYTE],
+            bios: bios,
+
+            current_side: if sides.is_empty() { None } else { Some(0) },
+            sides: sides,
+            pending_side: None,
+            swap_delay: 0,
+
+            timer: Timer::default(),
+            drive: Drive { end_of_head: true, ..Drive::default() },
+            audio: FdsAudio::new(),
+
+            disk_io_enabled: false,
+            sound_io_enabled: false,
+            mirroring: Mirroring::Vertical,
+            ext_connector: 0,
+        }
+    }
+
+    fn read_status(&mut self) -> u8 {
+        let status = self.status();
+        // Reading the status acknowledges both IRQ sources
+        self.timer.irq = false;
+        self.drive.irq = false;
+        self.drive.transfer_complete = false;
+        status
+    }
+
+    fn status(&self) -> u8 {
+        let mut status = 0;
+        if self.timer.irq { status |= 1 << 0; }
+        if self.drive.transfer_complete { status |= 1 << 1; }
+        if self.drive.read_mode && self.drive.crc_control && self.drive.crc != 0 { status |= 1 << 4; }
+        if self.drive.end_of_head { status |= 1 << 6; }
+        status
+    }
+
+    fn read_drive_status(&self) -> u8 {
+        let mut status = 0x40;
+        match self.current_side {
+            None => status |= 0b111, // Not inserted, not ready, write protected
+            Some(_) if !self.drive.scanning => status |= 1 << 1,
+            Some(_) => {}
+        }
+        status
+    }
+
+    fn write_control(&mut self, val: u8) {
+        self.drive.motor_on = val & (1 << 0) != 0;
+        self.drive.reset_transfer = val & (1 << 1) != 0;
+        self.drive.read_mode = val & (1 << 2) != 0;
+        self.mirroring = if val & (1 << 3) != 0 { Mirroring::Horizontal } else { Mirroring::Vertical };
+        self.drive.crc_control = val & (1 << 4) != 0;
+        self.drive.ready = val & (1 << 6) != 0;
+        self.drive.irq_enabled = val & (1 << 7) != 0;
+        self.drive.irq = false;
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer.enabled || !self.disk_io_enabled {
+            return;
+        }
+        if self.timer.counter == 0 {
+            self.timer.irq = true;
+            self.timer.counter = self.timer.reload;
+            if !self.timer.repeat {
+                self.timer.enabled = false;
+            }
+        } else {
+            self.timer.counter -= 1;
+        }
+    }
+
+    fn clock_drive(&mut self) {
+        let side = match self.current_side {
+            Some(side) if self.drive.motor_on => side,
+            _ => {
+                self.drive.end_of_head = true;
+                self.drive.scanning = false;
+                return;
+            }
+        };
+
+        if self.drive.reset_transfer && !self.drive.scanning {
+            return;
+        }
+
+        if self.drive.end_of_head {
+            self.drive.delay = HEAD_RETURN_CYCLES;
+            self.drive.end_of_head = false;
+            self.drive.position = 0;
+            self.drive.gap_ended = false;
+            return;
+        }
+
+        if self.drive.delay > 0 {
+            self.drive.delay -= 1;
+            return;
+        }
+
+        self.drive.scanning = true;
+        let mut need_irq = self.drive.irq_enabled;
+
+        if self.drive.read_mode {
+            let data = self.sides[side][self.drive.position];
+            if !self.drive.previous_crc_control {
+                self.drive.update_crc(data);
+            }
+            if !self.drive.ready {
+                self.drive.gap_ended = false;
+                self.drive.crc = 0;
+            } else if data != 0 && !self.drive.gap_ended {
+                // The block start mark ends the gap but isn't handed to the CPU
+                self.drive.gap_ended = true;
+                need_irq = false;
+            }
+
+            if self.drive.gap_ended {
+                self.drive.transfer_complete = true;
+                self.drive.read_data = data;
+                if need_irq {
+                    self.drive.irq = true;
+                }
+            }
+        } else {
+            let mut data = 0;
+            if !self.drive.crc_control {
+                self.drive.transfer_complete = true;
+                data = self.drive.write_data;
+                if need_irq {
+                    self.drive.irq = true;
+                }
+            }
+            if !self.drive.ready {
+                data = 0;
+            }
+            if !self.drive.crc_control {
+                self.drive.update_crc(data);
+            } else {
+                if !self.drive.previous_crc_control {
+                    self.drive.update_crc(0);
+                    self.drive.update_crc(0);
+                }
+                data = (self.drive.crc & 0xFF) as u8;
+                self.drive.crc >>= 8;
+            }
+            self.sides[side][self.drive.position] = data;
+            self.drive.gap_ended = false;
+        }
+
+        self.drive.previous_crc_control = self.drive.crc_control;
+        self.drive.position += 1;
+        if self.drive.position >= self.sides[side].len() {
+            self.drive.motor_on = false;
+            if need_irq {
+                self.drive.irq = true;
+            }
+        } else {
+            self.drive.delay = BYTE_TRANSFER_CYCLES;
+        }
+    }
+
+    fn clock_side_swap(&mut self) {
+        if self.swap_delay > 0 {
+            self.swap_delay -= 1;
+            if self.swap_delay == 0 {
+                self.current_side = self.pending_side.take();
+            }
+        }
+    }
+}
+
+impl Mapper for Fds {
+    fn prg_read(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4030 => self.read_status(),
+            0x4031 => {
+                self.drive.transfer_complete = false;
+                self.drive.irq = false;
+                self.drive.read_data
+            }
+            _ => self.prg_peek(addr),
+        }
+    }
+
+    fn prg_peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x4030 => self.status(),
+            0x4031 => self.drive.read_data,
+            0x4032 => self.read_drive_status(),
+            0x4033 => 0x80 | (self.ext_connector & 0x7F), // Bit 7 reports a good battery
+            0x4040...0x4097 if self.sound_io_enabled => self.audio.read(addr),
+            0x6000...0xDFFF => self.prg_ram[(addr - 0x6000) as usize],
+            0xE000...0xFFFF => self.bios[(addr - 0xE000) as usize],
+            _ => (addr >> 8) as u8, // Open bus
+        }
+    }
+
+    fn prg_write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4020 => self.timer.reload = (self.timer.reload & 0xFF00) | val as u16,
+            0x4021 => self.timer.reload = (self.timer.reload & 0x00FF) | (val as u16) << 8,
+            0x4022 => {
+                self.timer.repeat = val & (1 << 0) != 0;
+                self.timer.enabled = val & (1 << 1) != 0 && self.disk_io_enabled;
+                if self.timer.enabled {
+                    self.timer.counter = self.timer.reload;
+                } else {
+                    self.timer.irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_io_enabled = val & (1 << 0) != 0;
+                self.sound_io_enabled = val & (1 << 1) != 0;
+                if !self.disk_io_enabled {
+                    self.timer.enabled = false;
+                    self.timer.irq = false;
+                    self.drive.irq = false;
+                }
+            }
+            0x4024 if self.disk_io_enabled => {
+                self.drive.write_data = val;
+                self.drive.transfer_complete = false;
+                self.drive.irq = false;
+            }
+            0x4025 if self.disk_io_enabled => self.write_control(val),
+            0x4026 if self.disk_io_enabled => self.ext_connector = val,
+            0x4040...0x408A if self.sound_io_enabled => self.audio.write(addr, val),
+            0x6000...0xDFFF => self.prg_ram[(addr - 0x6000) as usize] = val,
+            _ => {} // BIOS ROM and unmapped registers ignore writes
+        }
+    }
+
+    fn prg_poke(&mut self, addr: u16, val: u8) {
+        if let 0x6000...0xDFFF = addr {
+            self.prg_ram[(addr - 0x6000) as usize] = val;
+        }
+    }
+
+    fn chr_read(&self, addr: u16) -> u8 {
+        if addr < 0x2000 {
+            self.chr_ram[addr as usize]
+        } else {
+            panic!("Unrecognized CHR address: {:#X}", addr);
+        }
+    }
+
+    fn chr_write(&mut self, addr: u16, val: u8) {
+        if addr < 0x2000 {
+            self.chr_ram[addr as usize] = val;
+        } else {
+            panic!("Unrecognized CHR address: {:#X}", addr);
+        }
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn clock(&mut self, cpu_cycles: usize) {
+        for _ in 0..cpu_cycles {
+            self.clock_timer();
+            self.clock_drive();
+            self.clock_side_swap();
+            self.audio.clock();
+        }
+    }
+
+    fn irq_pending(&self) -> bool {
+        self.timer.irq || self.drive.irq
+    }
+
+    fn audio_output(&self) -> f32 {
+        if self.sound_io_enabled { self.audio.output() } else { 0.0 }
+    }
+
+    fn switch_disk_side(&mut self) -> Option<usize> {
+        if self.sides.is_empty() {
+            return None;
+        }
+        let next = match self.current_side.or(self.pending_side) {
+            Some(side) => (side + 1) % self.sides.len(),
+            None => 0,
+        };
+        self.current_side = None;
+        self.pending_side = Some(next);
+        self.swap_delay = SIDE_SWAP_CYCLES;
+        Some(next)
+    }
+
+    fn eject_disk(&mut self) -> bool {
+        self.current_side = None;
+        self.pending_side = None;
+        self.swap_delay = 0;
+        true
+    }
+}
+
+impl Drive {
+    fn update_crc(&mut self, val: u8) {
+        for bit in 0..8 {
+            let carry = self.crc & 1;
+            self.crc >>= 1;
+            if carry != 0 {
+                self.crc ^= 0x8408;
+            }
+            if val & (1 << bit) != 0 {
+                self.crc ^= 0x8000;
+            }
+        }
+    }
+}
+
+// .fds images strip the gaps, block start marks and CRCs that are on the real
+// disk, so they are added back to get the byte stream the drive would see.
+fn build_raw_side(side: &[u8]) -> Vec<u8> {
+    let mut raw = vec![0; LEAD_IN_GAP];
+    let mut i = 0;
+    while i < side.len() {
+        let block_len = match side[i] {
+            1 => 56, // Disk info
+            2 => 2,  // File amount
+            3 => 16, // File header
+            4 => {
+                // File data, size comes from the preceding file header
+                if i < 3 { break; }
+                1 + (side[i - 3] as usize | (side[i - 2] as usize) << 8)
+            }
+            _ => break, // Unused space at the end of the side
+        };
+        let end = (i + block_len).min(side.len());
+
+        let mut block = vec![BLOCK_START_MARK];
+        block.extend_from_slice(&side[i..end]);
+        let crc = block_crc(&block);
+        raw.extend(block);
+        raw.push((crc & 0xFF) as u8);
+        raw.push((crc >> 8) as u8);
+        raw.extend(vec![0; BLOCK_GAP]);
+
+        i = end;
+    }
+    // Pad to at least the size of the original side so the head has somewhere to run out
+    if raw.len() < side.len() {
+        raw.resize(side.len(), 0);
+    }
+    raw
+}
+
+fn block_crc(block: &[u8]) -> u16 {
+    let mut drive = Drive::default();
+    for &byte in block {
+        drive.update_crc(byte);
+    }
+    drive.update_crc(0);
+    drive.update_crc(0);
+    drive.crc
+}
+
+// Expansion audio: a single 64 step wavetable channel with a frequency modulator
+pub struct FdsAudio {
+    wave_table: [u8; 64],
+    wave_write_enabled: bool,
+    wave_halted: bool,
+    wave_accumulator: u32,
+    frequency: u16,
+    output_level: u8,
+
+    mod_table: [u8; 64],
+    mod_position: usize,
+    mod_counter: i8, // 7 bit signed
+    mod_frequency: u16,
+    mod_halted: bool,
+    mod_accumulator: u32,
+
+    volume: Envelope,
+    mod_envelope: Envelope,
+    envelopes_disabled: bool,
+    envelope_speed: u8,
+    master_volume: u8,
+}
+
+#[derive(Default)]
+struct Envelope {
+    disabled: bool,
+    increase: bool,
+    speed: u8,
+    gain: u8,
+    timer: u32,
+}
+
+impl Envelope {
+    fn write(&mut self, val: u8, master_speed: u8) {
+        self.disabled = val & (1 << 7) != 0;
+        self.increase = val & (1 << 6) != 0;
+        self.speed = val & 0x3F;
+        if self.disabled {
+            self.gain = self.speed;
+        }
+        self.timer = self.period(master_speed);
+    }
+
+    fn period(&self, master_speed: u8) -> u32 {
+        8 * (self.speed as u32 + 1) * master_speed as u32
+    }
+
+    fn clock(&mut self, master_speed: u8) {
+        if self.disabled || master_speed == 0 {
+            return;
+        }
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period(master_speed);
+        if self.increase && self.gain < 32 {
+            self.gain += 1;
+        } else if !self.increase && self.gain > 0 {
+            self.gain -= 1;
+        }
+    }
+}
+
+impl FdsAudio {
+    pub fn new() -> Self {
+        FdsAudio {
+            wave_table: [0; 64],
+            wave_write_enabled: false,
+            wave_halted: true,
+            wave_accumulator: 0,
+            frequency: 0,
+            output_level: 0,
+
+            mod_table: [0; 64],
+            mod_position: 0,
+            mod_counter: 0,
+            mod_frequency: 0,
+            mod_halted: true,
+            mod_accumulator: 0,
+
+            volume: Envelope::default(),
+            mod_envelope: Envelope::default(),
+            envelopes_disabled: false,
+            envelope_speed: 0xE8,
+            master_volume: 0,
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0x4040...0x407F => self.wave_table[(addr - 0x4040) as usize] | 0x40,
+            0x4090 => self.volume.gain | 0x40,
+            0x4092 => self.mod_envelope.gain | 0x40,
+            _ => (addr >> 8) as u8, // Open bus
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x4040...0x407F if self.wave_write_enabled => {
+                self.wave_table[(addr - 0x4040) as usize] = val & 0x3F;
+            }
+            0x4080 => self.volume.write(val, self.envelope_speed),
+            0x4082 => self.frequency = (self.frequency & 0x0F00) | val as u16,
+            0x4083 => {
+                self.frequency = (self.frequency & 0x00FF) | ((val as u16 & 0x0F) << 8);
+                self.wave_halted = val & (1 << 7) != 0;
+                self.envelopes_disabled = val & (1 << 6) != 0;
+                if self.wave_halted {
+                    self.wave_accumulator = 0;
+                }
+            }
+            0x4084 => self.mod_envelope.write(val, self.envelope_speed),
+            0x4085 => self.mod_counter = ((val << 1) as i8) >> 1,
+            0x4086 => self.mod_frequency = (self.mod_frequency & 0x0F00) | val as u16,
+            0x4087 => {
+                self.mod_frequency = (self.mod_frequency & 0x00FF) | ((val as u16 & 0x0F) << 8);
+                self.mod_halted = val & (1 << 7) != 0;
+                if self.mod_halted {
+                    self.mod_accumulator = 0;
+                }
+            }
+            // Each write fills two consecutive entries of the table
+            0x4088 if self.mod_halted => {
+                self.mod_table[self.mod_position] = val & 0b111;
+                self.mod_table[(self.mod_position + 1) & 0x3F] = val & 0b111;
+                self.mod_position = (self.mod_position + 2) & 0x3F;
+            }
+            0x4089 => {
+                self.wave_write_enabled = val & (1 << 7) != 0;
+                self.master_volume = val & 0b11;
+            }
+            0x408A => self.envelope_speed = val,
+            _ => {}
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.envelopes_disabled && !self.wave_halted {
+            self.volume.clock(self.envelope_speed);
+            self.mod_envelope.clock(self.envelope_speed);
+        }
+
+        if !self.mod_halted && self.mod_frequency > 0 {
+            self.mod_accumulator += self.mod_frequency as u32;
+            if self.mod_accumulator >= 0x10000 {
+                self.mod_accumulator -= 0x10000;
+                self.step_modulator();
+            }
+        }
+
+        if !self.wave_halted && !self.wave_write_enabled {
+            let pitch = self.modulated_pitch();
+            self.wave_accumulator = (self.wave_accumulator + pitch) & 0x3F_FFFF;
+            self.output_level = self.wave_table[(self.wave_accumulator >> 16) as usize & 0x3F];
+        }
+    }
+
+    fn step_modulator(&mut self) {
+        let counter = self.mod_counter as i16;
+        let counter = match self.mod_table[self.mod_position] {
+            0 => counter,
+            1 => counter + 1,
+            2 => counter + 2,
+            3 => counter + 4,
+            4 => 0,
+            5 => counter - 4,
+            6 => counter - 2,
+            _ => counter - 1,
+        };
+        // Wrap back into 7 bit signed range
+        self.mod_counter = (((counter + 64) & 0x7F) - 64) as i8;
+        self.mod_position = (self.mod_position + 1) & 0x3F;
+    }
+
+    // Pitch after modulation, following the rounding the hardware does
+    fn modulated_pitch(&self) -> u32 {
+        let pitch = self.frequency as i32;
+        let counter = self.mod_counter as i32;
+
+        let mut temp = counter * self.mod_envelope.gain as i32;
+        let remainder = temp & 0x0F;
+        temp >>= 4;
+        if remainder > 0 && temp & 0x80 == 0 {
+            if counter < 0 { temp -= 1; } else { temp += 2; }
+        }
+
+        if temp >= 192 {
+            temp -= 256;
+        } else if temp < -64 {
+            temp += 256;
+        }
+
+        temp *= pitch;
+        let remainder = temp & 0x3F;
+        temp >>= 6;
+        if remainder >= 32 {
+            temp += 1;
+        }
+
+        let result = pitch + temp;
+        if result < 0 { 0 } else { result as u32 }
+    }
+
+    pub fn output(&self) -> f32 {
+        let gain = if self.volume.gain > 32 { 32 } else { self.volume.gain };
+        let level = self.output_level as f32 * gain as f32 / (63.0 * 32.0);
+        let master = match self.master_volume {
+            0 => 1.0,
+            1 => 2.0 / 3.0,
+            2 => 2.0 / 4.0,
+            _ => 2.0 / 5.0,
+        };
+        level * master
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rom::Header;
+    use super::*;
+
+    // A side holding one two byte file
+    fn test_side() -> Vec<u8> {
+        let mut side = vec![0x01];
+        side.extend_from_slice(b"*NINTENDO-HVC*");
+        side.resize(56, 0);
+        side.extend_from_slice(&[0x02, 1]);
+        side.extend_from_slice(&[0x03, 0, 0]);
+        side.extend_from_slice(b"FILENAME");
+        side.extend_from_slice(&[0x00, 0x60, 2, 0, 0]); // Load address, size and type
+        side.extend_from_slice(&[0x04, 0xAB, 0xCD]);
+        side.resize(FDS_SIDE_SIZE, 0);
+        side
+    }
+
+    fn test_fds(sides: usize) -> Fds {
+        let mut header = b"NES\x1A".to_vec();
+        header.resize(16, 0);
+        let rom = Rom {
+            mapper_number: 0,
+            prg_rom: test_side().repeat(sides),
+            chr_rom: Vec::new(),
+            header: Header::new(header).unwrap(),
+        };
+        Fds::new(rom, vec![0; 8 * KILOBYTE])
+    }
+
+    #[test]
+    fn block_crc_check() {
+        // The same as CRC-16/KERMIT
+        assert_eq!(block_crc(b"123456789"), 0x2189);
+
+        // Reading a block followed by its CRC leaves zero, which is how the RAM adapter checks it
+        let block = [BLOCK_START_MARK, 0x02, 0x01];
+        let crc = block_crc(&block);
+        let mut drive = Drive::default();
+        for &byte in block.iter().chain(&[(crc & 0xFF) as u8, (crc >> 8) as u8]) {
+            drive.update_crc(byte);
+        }
+        assert_eq!(drive.crc, 0);
+    }
+
+    #[test]
+    fn raw_side_layout() {
+        let side = test_side();
+        let raw = build_raw_side(&side);
+
+        assert!(raw[..LEAD_IN_GAP].iter().all(|&b| b == 0));
+        let mut pos = LEAD_IN_GAP;
+        // Disk info, file amount, file header and file data blocks
+        for &(start, len) in &[(0, 56), (56, 2), (58, 16), (74, 3)] {
+            assert_eq!(raw[pos], BLOCK_START_MARK);
+            assert_eq!(&raw[pos + 1..pos + 1 + len], &side[start..start + len]);
+            let crc = block_crc(&raw[pos..pos + 1 + len]);
+            assert_eq!(&raw[pos + 1 + len..pos + 3 + len], &[(crc & 0xFF) as u8, (crc >> 8) as u8]);
+            pos += len + 3;
+            assert!(raw[pos..pos + BLOCK_GAP].iter().all(|&b| b == 0));
+            pos += BLOCK_GAP;
+        }
+        assert!(raw[pos..].iter().all(|&b| b == 0));
+        assert_eq!(raw.len(), side.len());
+    }
+
+    #[test]
+    fn timer_irq() {
+        let mut fds = test_fds(1);
+        fds.prg_write(0x4023, 0x01); // Disk I/O enabled
+        fds.prg_write(0x4020, 100);
+        fds.prg_write(0x4021, 0);
+        fds.prg_write(0x4022, 0x02); // Enabled, one shot
+
+        fds.clock(100);
+        assert!(!fds.irq_pending());
+        fds.clock(1);
+        assert!(fds.irq_pending());
+        // Reading $4030 acknowledges it
+        assert_eq!(fds.prg_read(0x4030) & 0x01, 0x01);
+        assert!(!fds.irq_pending());
+        fds.clock(1000);
+        assert!(!fds.irq_pending());
+
+        fds.prg_write(0x4022, 0x03); // Enabled, repeating
+        for _ in 0..3 {
+            fds.clock(101);
+            assert!(fds.irq_pending());
+            fds.prg_read(0x4030);
+        }
+
+        // Turning disk I/O off stops the timer
+        fds.prg_write(0x4023, 0x00);
+        fds.clock(1000);
+        assert!(!fds.irq_pending());
+    }
+
+    #[test]
+    fn side_switching() {
+        let mut fds = test_fds(2);
+        assert_eq!(fds.prg_peek(0x4032) & 0x01, 0x00);
+
+        // The disk reads as ejected until the BIOS has had time to notice the swap
+        assert_eq!(fds.switch_disk_side(), Some(1));
+        assert_eq!(fds.prg_peek(0x4032) & 0x01, 0x01);
+        fds.clock(SIDE_SWAP_CYCLES - 1);
+        assert_eq!(fds.prg_peek(0x4032) & 0x01, 0x01);
+        fds.clock(1);
+        assert_eq!(fds.prg_peek(0x4032) & 0x01, 0x00);
+        assert_eq!(fds.current_side, Some(1));
+
+        assert_eq!(fds.switch_disk_side(), Some(0));
+        assert!(fds.eject_disk());
+        fds.clock(SIDE_SWAP_CYCLES);
+        assert_eq!(fds.current_side, None);
+        assert_eq!(fds.prg_peek(0x4032) & 0x01, 0x01);
+    }
+}
@@ -0,0 +1,138 @@
+//! Prints a summary of a rom's header and hashes for the --info mode
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use super::hash::{crc32, sha1};
+use super::nes::KILOBYTE;
+use super::rom::{Rom, RomFormat};
+
+pub fn print_info(rom: &Rom, db_path: Option<&Path>) {
+    let header = &rom.header;
+
+    println!("Format:      {}", match header.format() {
+        RomFormat::INes => "iNES",
+        RomFormat::Nes2 => "NES 2.0",
+        RomFormat::Unif => "UNIF",
+        RomFormat::Fds => "Famicom Disk System",
+    });
+    if let Some(board) = header.board() {
+        println!("Board:       {}", board);
+    }
+    if header.format() == RomFormat::Fds {
+        println!("Disk sides:  {}", header.disk_sides());
+    } else {
+        println!("Mapper:      {} (submapper {})", header.mapper_no(), header.submapper());
+        println!("PRG ROM:     {} KB", header.prg_size() / KILOBYTE);
+        if header.chr_size() == 0 {
+            println!("CHR ROM:     none (CHR RAM)");
+        } else {
+            println!("CHR ROM:     {} KB", header.chr_size() / KILOBYTE);
+        }
+    }
+    println!("PRG RAM:     {}", ram_size(header.ram_size()));
+    if header.nvram_size() > 0 {
+        println!("PRG NVRAM:   {}", ram_size(header.nvram_size()));
+    }
+    println!("Mirroring:   {:?}", header.mirroring());
+    println!("Battery:     {}", yes_no(header.has_battery()));
+    println!("Trainer:     {}", yes_no(header.has_trainer()));
+    println!("Region:      {:?}", header.region());
+
+    // Hashes are of the rom data without any header, as used by No-Intro and NesCartDB
+    let mut data = rom.prg_rom.clone();
+    data.extend(rom.chr_rom.iter());
+    let crc = crc32(&data);
+    let sha = sha1_string(&data);
+    println!("CRC32:       {:08X}", crc);
+    println!("SHA-1:       {}", sha);
+
+    match db_path {
+        Some(db_path) => match find_in_database(db_path, crc, &sha) {
+            Ok(Some(name)) => println!("Database:    {}", name),
+            Ok(None) => println!("Database:    no match"),
+            Err(e) => println!("Database:    {}", e),
+        },
+        None => println!("Database:    none given (use --db)"),
+    }
+}
+
+// NES 2.0 RAM can be as small as 128 bytes
+fn ram_size(size: usize) -> String {
+    if size.is_multiple_of(KILOBYTE) { format!("{} KB", size / KILOBYTE) } else { format!("{} bytes", size) }
+}
+
+fn yes_no(val: bool) -> &'static str {
+    if val { "yes" } else { "no" }
+}
+
+fn sha1_string(data: &[u8]) -> String {
+    sha1(data).iter().map(|b| format!("{:02X}", b)).collect()
+}
+
+// Searches a No-Intro style XML DAT for a <rom> entry with a matching CRC32 or SHA-1
+fn find_in_database(db_path: &Path, crc: u32, sha: &str) -> Result<Option<String>, &'static str> {
+    let mut db = String::new();
+    match File::open(db_path) {
+        Ok(mut file) => {
+            if file.read_to_string(&mut db).is_err() {
+                return Err("failed to read database file");
+            }
+        }
+        Err(_) => return Err("failed to open database file"),
+    }
+
+    let crc = format!("{:08X}", crc);
+    for entry in db.split("<rom ").skip(1) {
+        let entry = &entry[..entry.find('>').unwrap_or(entry.len())];
+        let matches = attribute(entry, "crc").is_some_and(|c| c.eq_ignore_ascii_case(&crc)) ||
+                      attribute(entry, "sha1").is_some_and(|s| s.eq_ignore_ascii_case(sha));
+        if matches {
+            return Ok(attribute(entry, "name").map(|name| name.to_owned()));
+        }
+    }
+    Ok(None)
+}
+
+fn attribute<'a>(entry: &'a str, name: &str) -> Option<&'a str> {
+    let key = format!(" {}=\"", name);
+    let start = format!(" {}", entry).find(&key)? + key.len() - 1;
+    let end = entry[start..].find('"')?;
+    Some(&entry[start..start + end])
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::*;
+
+    const DAT: &str = r#"<?xml version="1.0"?>
+<datafile>
+	<header>
+		<name>Nintendo - Nintendo Entertainment System</name>
+	</header>
+	<game name="Test Game (USA)">
+		<description>Test Game (USA)</description>
+		<rom name="Test Game (USA).nes" size="24576" crc="1A2B3C4D" sha1="0123456789ABCDEF0123456789ABCDEF01234567" status="verified"/>
+	</game>
+</datafile>
+"#;
+
+    #[test]
+    fn database_lookup() {
+        let db_path = env::temp_dir().join(format!("gadget_nes_test_{}.dat", process::id()));
+        fs::write(&db_path, DAT).unwrap();
+
+        let name = Some("Test Game (USA).nes".to_owned());
+        assert_eq!(find_in_database(&db_path, 0x1A2B3C4D, ""), Ok(name.clone()));
+        assert_eq!(find_in_database(&db_path, 0, "0123456789abcdef0123456789abcdef01234567"), Ok(name));
+        assert_eq!(find_in_database(&db_path, 0x1A2B3C4E, "0123456789ABCDEF0123456789ABCDEF01234568"), Ok(None));
+
+        fs::remove_file(&db_path).unwrap();
+        assert!(find_in_database(&db_path, 0x1A2B3C4D, "").is_err());
+    }
+}
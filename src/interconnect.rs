@@ -2,10 +2,13 @@
 
 use super::ppu::Ppu;
 use super::cart::Cartridge;
+use super::events::EventLog;
 
 pub struct Interconnect<'a> {
     pub ppu: Ppu,
-    pub cart: &'a mut Cartridge
+    pub cart: &'a mut Cartridge,
+    pub events: EventLog,
+    apu_registers: [u8; 0x18], // $4000-$4017 as last written, there's no APU yet
 }
 
 impl<'a> Interconnect<'a> {
@@ -13,6 +16,8 @@ impl<'a> Interconnect<'a> {
         Interconnect {
             ppu: Ppu::new(),
             cart: cart,
+            events: EventLog::new(),
+            apu_registers: [0; 0x18],
         }
     }
 
@@ -20,22 +25,62 @@ impl<'a> Interconnect<'a> {
         self.ppu.reset();
     }
 
-    pub fn read_byte(&self, addr: u16) -> u8 {
+    pub fn read_byte(&mut self, addr: u16) -> u8 {
         match addr {
-            0x2000...0x2007 => unimplemented!(), // PPU registers
-            0x2008...0x3FFF => unimplemented!(), // PPU register mirrors
-            0x4000...0x4017 => unimplemented!(), // APU registers
+            0x2000...0x3FFF => self.ppu.read_register(self.cart, addr),
+            0x4000...0x4017 => (addr >> 8) as u8, // TODO: APU and controllers, open bus for now
             0x4018...0x401F => panic!("These registers are disabled during normal operation"),
             0x4020...0xFFFF => self.cart.prg_read(addr),
             _ => panic!("Unrecognized interconnect address: {:04X}", addr),
         }
     }
 
+    // Instruction fetches, told apart from data reads for code/data logging
+    pub fn read_code(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4020...0xFFFF => self.cart.prg_fetch(addr),
+            _ => self.read_byte(addr),
+        }
+    }
+
+    pub fn log_cdl(&mut self, addr: u16, flags: u8) {
+        if addr >= 0x4020 {
+            self.cart.log_prg(addr, flags);
+        }
+    }
+
+    // Reads for the debugger that leave PPU, APU and mapper state untouched
+    pub fn peek_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x2000...0x3FFF => self.ppu.peek_register(addr),
+            0x4014 => self.ppu.oam_dma(),
+            0x4000...0x4017 => self.apu_registers[addr as usize - 0x4000],
+            0x4018...0x401F => (addr >> 8) as u8, // Open bus
+            0x4020...0xFFFF => self.cart.prg_peek(addr),
+            _ => panic!("Unrecognized interconnect address: {:04X}", addr),
+        }
+    }
+
+    // Writes for the debugger. Registers only have their latched value changed, so
+    // pokes don't scroll the PPU or start a DMA.
+    pub fn poke_byte(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x2000...0x3FFF => self.ppu.poke_register(addr, val),
+            0x4014 => self.ppu.write_oam_dma(val),
+            0x4000...0x4017 => self.apu_registers[addr as usize - 0x4000] = val,
+            0x4018...0x401F => {}
+            0x4020...0xFFFF => self.cart.prg_poke(addr, val),
+            _ => panic!("Unrecognized interconnect address: {:04X}", addr),
+        }
+    }
+
+    // OAM DMA only latches the page here, the CPU does the copy
     pub fn write_byte(&mut self, addr: u16, val: u8) {
+        self.events.log_write(&self.ppu, addr, val);
         match addr {
-            0x2000...0x2007 => unimplemented!(), // PPU registers
-            0x2008...0x3FFF => unimplemented!(), // PPU register mirrors
-            0x4000...0x4017 => unimplemented!(), // APU registers
+            0x2000...0x3FFF => self.ppu.write_register(self.cart, addr, val),
+            0x4014 => self.ppu.write_oam_dma(val),
+            0x4000...0x4017 => self.apu_registers[addr as usize - 0x4000] = val, // TODO: APU and controllers
             0x4018...0x401F => panic!("These registers are disabled during normal operation"),
             0x4020...0xFFFF => self.cart.prg_write(addr, val),
             _ => panic!("Unrecognized interconnect address: {:04X}", addr),
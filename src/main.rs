@@ -3,29 +3,47 @@ extern crate bitflags;
 #[macro_use]
 extern crate nom;
 extern crate clap;
+extern crate rustyline;
 extern crate sdl2;
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use clap::{Arg, App};
 
 use sdl2::pixels::PixelFormatEnum::RGB24;
 use sdl2::render::{Texture, TextureCreator};
 
+mod audio;
 mod cart;
+mod cdl;
 mod cpu;
 mod debugger;
+mod disasm;
+mod events;
+mod fds;
+mod hash;
+mod info;
 mod interconnect;
+mod memory;
 mod nes;
+mod patch;
 mod ppu;
+mod profile;
 mod rom;
 mod screen;
+mod symbols;
+mod trace;
+mod views;
 
+use audio::Audio;
 use cart::Cartridge;
-use debugger::Debugger;
+use debugger::{Debugger, GdbStub};
 use nes::Nes;
+use profile::Profiler;
+use rom::RomFormat;
 use screen::Screen;
 use screen::{NES_WIDTH, NES_HEIGHT};
+use trace::Trace;
 
 fn main() {
     let matches = App::new("GadgetNES")
@@ -39,16 +57,108 @@ fn main() {
                             .help("Path to the rom")
                             .takes_value(true)
                             .required(true))
+                        .arg(Arg::with_name("PATCH")
+                            .short("p")
+                            .long("patch")
+                            .value_name("PATCH")
+                            .help("IPS/BPS/UPS patch to apply to the rom (defaults to one named after the rom)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("FDS_BIOS")
+                            .long("fds-bios")
+                            .value_name("BIOS")
+                            .help("Path to the Famicom Disk System BIOS, required for .fds images")
+                            .takes_value(true))
+                        .arg(Arg::with_name("SAVE_DIR")
+                            .long("save-dir")
+                            .value_name("DIR")
+                            .help("Directory for battery save files (defaults to next to the rom)")
+                            .takes_value(true))
+                        .arg(Arg::with_name("INFO")
+                            .short("i")
+                            .long("info")
+                            .help("Prints information about the rom and exits"))
+                        .arg(Arg::with_name("DB")
+                            .long("db")
+                            .value_name("DAT")
+                            .help("No-Intro XML DAT file to look the rom up in with --info")
+                            .takes_value(true))
+                        .arg(Arg::with_name("TRACE")
+                            .long("trace")
+                            .value_name("FILE")
+                            .help("Logs every instruction executed to a file in the nestest.log format")
+                            .takes_value(true))
+                        .arg(Arg::with_name("CDL")
+                            .long("cdl")
+                            .value_name("FILE")
+                            .help("Logs which PRG ROM bytes are code or data, and which CHR ROM bytes are read through PPUDATA, to an FCEUX .cdl file, merging with it if it exists")
+                            .takes_value(true))
+                        .arg(Arg::with_name("PROFILE")
+                            .long("profile")
+                            .value_name("FILE")
+                            .help("Writes instruction hits and cycles per address and routine to a CSV file")
+                            .takes_value(true))
+                        .arg(Arg::with_name("SYMBOLS")
+                            .short("s")
+                            .long("symbols")
+                            .value_name("FILE")
+                            .help("ca65 .dbg, FCEUX .nl or Mesen .mlb labels for the debugger and trace (may be repeated)")
+                            .takes_value(true)
+                            .multiple(true)
+                            .number_of_values(1))
                         .arg(Arg::with_name("DEBUGGER")
                             .short("d")
                             .long("debug")
                             .help("Runs the emulator with the internal debugger"))
+                        .arg(Arg::with_name("GDB")
+                            .long("gdb")
+                            .value_name("PORT")
+                            .help("Waits for a GDB remote protocol client on localhost instead of running")
+                            .takes_value(true))
+                        .arg(Arg::with_name("DEBUG_SCRIPT")
+                            .long("debug-script")
+                            .value_name("FILE")
+                            .help("Debugger commands to run at startup, implies --debug")
+                            .takes_value(true))
                         .get_matches();
 
     let rom_path = Path::new(matches.value_of("ROM").unwrap());
-    match rom::read_rom(rom_path) {
+    let patch_path = matches.value_of("PATCH").map(Path::new);
+    match rom::read_rom(rom_path, patch_path) {
         Ok(rom) => {
-            let mut cart = Cartridge::new(rom);
+            if matches.is_present("INFO") {
+                info::print_info(&rom, matches.value_of("DB").map(Path::new));
+                return;
+            }
+
+            let mut cart = if rom.header.format() == RomFormat::Fds {
+                let bios = match matches.value_of("FDS_BIOS") {
+                    Some(bios_path) => rom::read_fds_bios(Path::new(bios_path)),
+                    None => Err("FDS images require a BIOS, pass one with --fds-bios"),
+                };
+                match bios {
+                    Ok(bios) => Cartridge::new_fds(rom, bios),
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                }
+            } else {
+                match Cartridge::new(rom) {
+                    Ok(cart) => cart,
+                    Err(e) => {
+                        println!("{}", e);
+                        return;
+                    }
+                }
+            };
+            if let Err(e) = cart.attach_save_file(save_path(rom_path, matches.value_of("SAVE_DIR"))) {
+                println!("Failed to load save file: {}", e);
+            }
+            if let Some(cdl_path) = matches.value_of("CDL") {
+                if let Err(e) = cart.attach_cdl_file(PathBuf::from(cdl_path)) {
+                    println!("Failed to load CDL file: {}", e);
+                }
+            }
             let mut sdl = sdl2::init().unwrap();
 
             // Setup SDL here because the lifetime crap for lib is ridiculous
@@ -63,11 +173,44 @@ fn main() {
 
             let mut screen = Screen::new(canvas, &texture_creator);
 
-            let mut nes = Nes::new(&mut cart, screen);
+            let mut nes = Nes::new(&mut cart, Some(screen));
+            if let Some(symbol_paths) = matches.values_of("SYMBOLS") {
+                for symbol_path in symbol_paths {
+                    if let Err(e) = nes.symbols.load(Path::new(symbol_path)) {
+                        println!("Failed to load {}: {}", symbol_path, e);
+                    }
+                }
+            }
+            if let Some(trace_path) = matches.value_of("TRACE") {
+                match Trace::to_file(Path::new(trace_path)) {
+                    Ok(trace) => nes.trace = Some(trace),
+                    Err(e) => println!("Failed to create trace file: {}", e),
+                }
+            }
+            if let Some(profile_path) = matches.value_of("PROFILE") {
+                nes.profiler = Some(Profiler::new(Some(PathBuf::from(profile_path))));
+            }
+            match sdl.audio().and_then(|audio| Audio::new(&audio)) {
+                Ok(audio) => nes.audio = Some(audio),
+                Err(e) => println!("Failed to open audio: {}", e),
+            }
 
-            if matches.is_present("DEBUGGER") {
+            if let Some(port) = matches.value_of("GDB") {
+                match port.parse() {
+                    Ok(port) => if let Err(e) = GdbStub::new(nes).listen(port) {
+                        println!("GDB server failed: {}", e);
+                    },
+                    Err(_) => println!("Invalid GDB port: {}", port),
+                }
+            } else if matches.is_present("DEBUGGER") || matches.is_present("DEBUG_SCRIPT") {
                 let mut debugger = Debugger::init(nes);
-                debugger.run();
+                let quit = match matches.value_of("DEBUG_SCRIPT") {
+                    Some(script_path) => !debugger.source(Path::new(script_path)),
+                    None => false,
+                };
+                if !quit {
+                    debugger.run();
+                }
             } else {
                 nes.reset();
                 nes.run(None);
@@ -76,3 +219,12 @@ fn main() {
         Err(e) => println!("{}", e),
     }
 }
+
+// Battery saves are named after the rom, e.g. game.nes -> game.sav
+fn save_path(rom_path: &Path, save_dir: Option<&str>) -> PathBuf {
+    let save_file = rom_path.with_extension("sav");
+    match save_dir {
+        Some(dir) => Path::new(dir).join(save_file.file_name().unwrap()),
+        None => save_file,
+    }
+}
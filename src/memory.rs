@@ -0,0 +1,51 @@
+//! Address spaces the hex editor can show, read and written without side effects
+
+use super::cpu::Cpu;
+use super::interconnect::Interconnect;
+
+const CPU_SPACE_SIZE: usize = 0x10000;
+const PPU_SPACE_SIZE: usize = 0x4000;
+const OAM_SIZE: usize = 0x100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Space {
+    Cpu,
+    Ppu,
+    Oam,
+    Prg, // Offsets into PRG ROM rather than CPU addresses
+    Chr,
+}
+
+impl Space {
+    pub fn size(&self, interconnect: &Interconnect) -> usize {
+        match *self {
+            Space::Cpu => CPU_SPACE_SIZE,
+            Space::Ppu => PPU_SPACE_SIZE,
+            Space::Oam => OAM_SIZE,
+            Space::Prg => interconnect.cart.prg_rom().len(),
+            Space::Chr => interconnect.cart.chr_rom().len(),
+        }
+    }
+
+    // Callers keep addr below size
+    pub fn peek(&self, cpu: &Cpu, interconnect: &Interconnect, addr: usize) -> u8 {
+        match *self {
+            Space::Cpu => cpu.peek_byte(interconnect, addr as u16),
+            Space::Ppu => interconnect.ppu.peek_vram(interconnect.cart, addr as u16),
+            Space::Oam => interconnect.ppu.oam()[addr],
+            Space::Prg => interconnect.cart.prg_rom()[addr],
+            Space::Chr => interconnect.cart.chr_rom()[addr],
+        }
+    }
+
+    // CPU writes take the debugger's poke path, so registers are only latched
+    pub fn poke(&self, cpu: &mut Cpu, interconnect: &mut Interconnect, addr: usize, val: u8) {
+        match *self {
+            Space::Cpu => cpu.poke_byte(interconnect, addr as u16, val),
+            Space::Ppu => interconnect.ppu.poke_vram(interconnect.cart, addr as u16, val),
+            Space::Oam => interconnect.ppu.poke_oam(addr as u8, val),
+            Space::Prg => interconnect.cart.poke_prg_rom(addr, val),
+            Space::Chr => interconnect.cart.poke_chr_rom(addr, val),
+        }
+    }
+}
@@ -1,75 +1,248 @@
 use std::collections::HashMap;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+use super::audio::Audio;
 use super::cart::Cartridge;
-use super::cpu::Cpu;
+use super::debugger::{Breakpoint, PpuState};
+use super::cpu::{Cpu, Interrupt};
+use super::events::EventKind;
 use super::interconnect::Interconnect;
+use super::profile::Profiler;
 use super::screen::Screen;
+use super::symbols::Symbols;
+use super::trace::Trace;
+use super::views::{self, View, Views};
 
-use super::sdl2::event::Event;
+use super::sdl2::event::{Event, WindowEvent};
 use super::sdl2::keyboard::Keycode;
 use super::sdl2::Sdl;
 
 pub const KILOBYTE: usize = 1024;
 
+// How often battery backed RAM is flushed to disk while running
+const SAVE_INTERVAL_SECS: u64 = 10;
+
+// Why Nes::run returned
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    Exited,             // Window closed or escape pressed
+    Interrupted,        // Ctrl-C or Pause pressed while debugging
+    Breakpoint(usize),  // Key of the breakpoint that was hit
+    Watchpoint,         // Hits are collected in the Cpu
+    Finished,           // The run_until condition was met
+}
+
 // Fields are public for debugger
 pub struct Nes<'a> {
     // apu: Apu
     pub cpu: Cpu,
     pub interconnect: Interconnect<'a>,
 
-    pub screen: Screen<'a>,
+    pub screen: Option<Screen<'a>>, // None when running without a window, as in tests
+    pub audio: Option<Audio>,
+
+    pub views: Views,
+    pub trace: Option<Trace>,
+    pub profiler: Option<Profiler>,
+    pub symbols: Symbols,
 }
 
 impl<'a> Nes<'a> {
-    pub fn new(cart: &'a mut Cartridge, screen: Screen<'a>) -> Self {
+    pub fn new(cart: &'a mut Cartridge, screen: Option<Screen<'a>>) -> Self {
+        let prg_size = cart.prg_size();
+        let views = Views::new(screen.as_ref().map(|screen| screen.sdl().video().unwrap()));
         Nes {
             cpu: Cpu::new(),
             interconnect: Interconnect::new(cart),
 
             screen: screen,
+            audio: None,
+            views: views,
+
+            trace: None,
+            profiler: None,
+            symbols: Symbols::new(prg_size),
         }
     }
 
     pub fn reset(&mut self) {
+        let start_cycles = self.cpu.cycles();
         self.cpu.reset(&mut self.interconnect);
         self.interconnect.reset();
+        // The PPU keeps running during the reset sequence, nestest.log starts at dot 21
+        self.interconnect.ppu.step(self.interconnect.cart, (self.cpu.cycles() - start_cycles) / 3);
+    }
+
+    pub fn save(&mut self) {
+        if let Err(e) = self.interconnect.cart.flush_save_file() {
+            println!("Failed to write save file: {}", e);
+        }
+        if let Err(e) = self.interconnect.cart.flush_cdl_file() {
+            println!("Failed to write CDL file: {}", e);
+        }
+        if let Some(ref profiler) = self.profiler {
+            if let Err(e) = profiler.save(&self.symbols) {
+                println!("Failed to write profile: {}", e);
+            }
+        }
+    }
+
+    pub fn toggle_view(&mut self, view: View) {
+        match self.views.toggle(view) {
+            Ok(()) => self.refresh_views(),
+            Err(e) => println!("Failed to open {:?} view: {}", view, e),
+        }
+    }
+
+    pub fn refresh_views(&mut self) {
+        if self.views.is_open() {
+            self.views.refresh(&self.cpu, &self.interconnect);
+        }
     }
 
-    pub fn run(&mut self, breakpoints: Option<&HashMap<usize, usize>>) {
-        let mut event_pump = self.screen.sdl().event_pump().unwrap();
+    // Reads memory for the debugger without side effects
+    pub fn peek_byte(&self, addr: u16) -> u8 {
+        self.cpu.peek_byte(&self.interconnect, addr)
+    }
+
+    pub fn run(&mut self, breakpoints: Option<&mut HashMap<usize, Breakpoint>>) -> StopReason {
+        self.run_until(breakpoints, |_| false)
+    }
+
+    // Runs until done returns true before an instruction executes. Like breakpoints,
+    // it isn't checked before the first instruction.
+    pub fn run_until<F>(&mut self, mut breakpoints: Option<&mut HashMap<usize, Breakpoint>>, mut done: F) -> StopReason
+        where F: FnMut(&Nes<'a>) -> bool
+    {
+        let debugging = breakpoints.is_some();
+        let mut event_pump = self.screen.as_ref().map(|screen| screen.sdl().event_pump().unwrap());
+        let mut last_save = Instant::now();
+        let mut resuming = true;
+        let mut before = PpuState::new(self);
+        let mut frame = self.interconnect.ppu.frame();
         let mut i = 0;
-        'running: loop {
+        let reason = 'running: loop {
             i = (i+1) % 255;
-            for event in event_pump.poll_iter() {
+            if last_save.elapsed() >= Duration::from_secs(SAVE_INTERVAL_SECS) {
+                self.save();
+                last_save = Instant::now();
+            }
+            for event in event_pump.iter_mut().flat_map(|event_pump| event_pump.poll_iter()) {
                 match event {
+                    // SDL turns Ctrl-C in the terminal into a quit event
+                    Event::Quit {..} |
+                    Event::KeyDown { keycode: Some(Keycode::Pause), .. } if debugging => {
+                        break 'running StopReason::Interrupted
+                    },
                     Event::Quit {..} |
                     Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
-                        break 'running
+                        break 'running StopReason::Exited
+                    },
+                    // Disk System side switching
+                    Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                        if let Some(side) = self.interconnect.cart.switch_disk_side() {
+                            println!("Inserting disk {} side {}", side / 2 + 1, if side % 2 == 0 { 'A' } else { 'B' });
+                        }
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::F6), .. } => {
+                        if self.interconnect.cart.eject_disk() {
+                            println!("Ejecting disk");
+                        }
+                    },
+                    // Debug views
+                    Event::KeyDown { keycode: Some(Keycode::F1), .. } => self.toggle_view(View::Chr),
+                    Event::KeyDown { keycode: Some(Keycode::F2), .. } => self.toggle_view(View::Nametables),
+                    Event::KeyDown { keycode: Some(Keycode::F3), .. } => self.toggle_view(View::Oam),
+                    Event::KeyDown { keycode: Some(Keycode::F4), .. } => self.toggle_view(View::Palette),
+                    Event::KeyDown { keycode: Some(Keycode::F7), .. } => {
+                        self.views.chr_palette = (self.views.chr_palette + 1) % views::CHR_PALETTES;
+                        self.refresh_views();
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::F8), .. } => self.toggle_view(View::Hex),
+                    Event::KeyDown { keycode: Some(Keycode::F9), .. } => self.toggle_view(View::Events),
+                    // SDL only sends Quit once every window is closed
+                    Event::Window { window_id, win_event: WindowEvent::Close, .. } => {
+                        if !self.views.close(window_id) && self.views.is_open() {
+                            break 'running StopReason::Exited
+                        }
                     },
                     _ => {}
                 }
             }
-            if breakpoints.is_some() {
-                // TODO: Fix this
-                /*{
-                    if breakpoints.unwrap().values().any(|&bp| (bp as u16) == self.cpu.pc()) {
-                        println!("Encountered breakpoint @ {:04X}", self.cpu.pc());
-                        break;
-                    };
-                }*/
-                self.step();
+            if let Some(ref mut breakpoints) = breakpoints {
+                // Don't stop again on the breakpoint we are resuming from
+                if !resuming {
+                    let mut hit = None;
+                    // Every matching breakpoint counts the hit, the lowest numbered one is reported
+                    for (&key, breakpoint) in breakpoints.iter_mut() {
+                        if breakpoint.triggered(self, &before) && breakpoint.hit(self) && hit.is_none_or(|k| key < k) {
+                            hit = Some(key);
+                        }
+                    }
+                    if let Some(key) = hit {
+                        break 'running StopReason::Breakpoint(key);
+                    }
+                }
+            }
+            if !resuming && done(self) {
+                break 'running StopReason::Finished;
             }
-            else {
-                self.step();
+            resuming = false;
+            if debugging {
+                before = PpuState::new(self);
             }
-            self.screen.refresh();
+            self.step();
+            if let Some(ref mut screen) = self.screen {
+                screen.refresh();
+            }
+            if self.interconnect.ppu.frame() != frame {
+                frame = self.interconnect.ppu.frame();
+                self.refresh_views();
+            }
+            if debugging && self.cpu.has_watch_hits() {
+                break 'running StopReason::Watchpoint;
+            }
+        };
+        self.save();
+        if let Some(ref mut trace) = self.trace {
+            trace.flush();
         }
+        reason
     }
 
     pub fn step(&mut self) -> (u8) {
+        if let Some(mut trace) = self.trace.take() {
+            trace.log(self);
+            self.trace = Some(trace);
+        }
+        // Profiled before the step so JSRs count towards the caller
+        let pc = self.cpu.pc();
+        let routine = self.cpu.call_stack().last().map(|frame| frame.target);
+        let start_cycles = self.cpu.cycles();
         let opcode = self.cpu.step(&mut self.interconnect);
-        self.interconnect.ppu.step(&mut self.interconnect.cart, self.cpu.cycles()); 
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.record(pc, routine, (self.cpu.cycles() - start_cycles) / 3);
+        }
+        // Cpu counts in PPU cycles, mappers want CPU cycles
+        self.interconnect.cart.clock((self.cpu.cycles() - start_cycles) / 3);
+        if let Some(ref mut audio) = self.audio {
+            audio.push(self.interconnect.cart.audio_output(), (self.cpu.cycles() - start_cycles) / 3);
+        }
+        if self.interconnect.cart.irq_pending() {
+            self.cpu.irq(&mut self.interconnect);
+            if self.cpu.entered_interrupt() == Some(Interrupt::Irq) {
+                self.interconnect.events.log_interrupt(&self.interconnect.ppu, EventKind::Irq, self.cpu.pc());
+            }
+        }
+        // Including any interrupt entered above
+        self.interconnect.ppu.step(self.interconnect.cart, (self.cpu.cycles() - start_cycles) / 3);
+        if self.interconnect.ppu.take_nmi() {
+            let nmi_cycles = self.cpu.cycles();
+            self.cpu.nmi(&mut self.interconnect);
+            self.interconnect.events.log_interrupt(&self.interconnect.ppu, EventKind::Nmi, self.cpu.pc());
+            // The PPU keeps running while the CPU enters the handler
+            self.interconnect.ppu.step(self.interconnect.cart, (self.cpu.cycles() - nmi_cycles) / 3);
+        }
         (opcode)
     }
 
@@ -0,0 +1,407 @@
+//! Soft-patching of rom files with IPS, BPS and UPS patches
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+
+use super::hash::crc32;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: usize = 0x454F46; // "EOF"
+const BPS_MAGIC: &[u8] = b"BPS1";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const FOOTER_SIZE: usize = 12; // Source, target and patch CRC32s
+const MAX_TARGET_SIZE: usize = 64 * 1024 * 1024; // Far beyond any real rom, so bigger means a bad patch
+const CORRUPT: &str = "Patch is corrupt";
+
+const PATCH_EXTENSIONS: [&str; 3] = ["ips", "bps", "ups"];
+
+// Looks for a patch with the same name as the rom, e.g. game.nes -> game.ips
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    PATCH_EXTENSIONS.iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+pub fn apply_patch_file(rom: &[u8], patch_path: &Path) -> Result<Vec<u8>, &'static str> {
+    let mut patch = Vec::new();
+    match File::open(patch_path) {
+        Ok(mut file) => {
+            if file.read_to_end(&mut patch).is_err() {
+                return Err("Failed to read from patch file");
+            }
+        }
+        Err(_) => return Err("Failed to open patch file"),
+    }
+    apply_patch(rom, &patch)
+}
+
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, &'static str> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(rom, patch)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(rom, patch)
+    } else {
+        Err("Unrecognized patch format")
+    }
+}
+
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let mut target = rom.to_vec();
+    let mut pos = IPS_MAGIC.len();
+    loop {
+        if pos + 3 > patch.len() {
+            return Err("IPS patch is truncated");
+        }
+        let offset = read_be(&patch[pos..pos + 3]);
+        pos += 3;
+        if offset == IPS_EOF {
+            break;
+        }
+
+        if pos + 2 > patch.len() {
+            return Err("IPS patch is truncated");
+        }
+        let size = read_be(&patch[pos..pos + 2]);
+        pos += 2;
+
+        if size == 0 {
+            // Run length encoded record
+            if pos + 3 > patch.len() {
+                return Err("IPS patch is truncated");
+            }
+            let run = read_be(&patch[pos..pos + 2]);
+            let val = patch[pos + 2];
+            pos += 3;
+            if target.len() < offset + run {
+                target.resize(offset + run, 0);
+            }
+            for byte in &mut target[offset..offset + run] {
+                *byte = val;
+            }
+        } else {
+            if pos + size > patch.len() {
+                return Err("IPS patch is truncated");
+            }
+            if target.len() < offset + size {
+                target.resize(offset + size, 0);
+            }
+            target[offset..offset + size].copy_from_slice(&patch[pos..pos + size]);
+            pos += size;
+        }
+    }
+
+    // Optional truncation extension
+    if pos + 3 <= patch.len() {
+        let new_len = read_be(&patch[pos..pos + 3]);
+        target.truncate(new_len);
+    }
+    Ok(target)
+}
+
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let (source_crc, target_crc) = check_footer(patch)?;
+    if crc32(rom) != source_crc {
+        return Err("BPS patch was made for a different rom (source checksum mismatch)");
+    }
+
+    let end = patch.len() - FOOTER_SIZE;
+    let mut pos = BPS_MAGIC.len();
+    let source_size = read_varint(patch, &mut pos, end)?;
+    let target_size = read_varint(patch, &mut pos, end)?;
+    let metadata_size = read_varint(patch, &mut pos, end)?;
+    pos = pos.checked_add(metadata_size).ok_or(CORRUPT)?;
+    if source_size != rom.len() {
+        return Err("BPS patch was made for a different rom (source size mismatch)");
+    }
+    if target_size > MAX_TARGET_SIZE {
+        return Err(CORRUPT);
+    }
+
+    let mut target: Vec<u8> = Vec::with_capacity(target_size);
+    let mut source_relative: isize = 0;
+    let mut target_relative: isize = 0;
+    while pos < end {
+        let data = read_varint(patch, &mut pos, end)?;
+        let command = data & 0b11;
+        let length = (data >> 2) + 1;
+        if target.len() + length > target_size {
+            return Err("BPS patch writes past the end of the patched rom");
+        }
+
+        match command {
+            // SourceRead
+            0 => {
+                let start = target.len();
+                match rom.get(start..start + length) {
+                    Some(bytes) => target.extend_from_slice(bytes),
+                    None => return Err("BPS patch reads past the end of the source rom"),
+                }
+            }
+            // TargetRead
+            1 => {
+                if pos.checked_add(length).is_none_or(|next| next > end) {
+                    return Err("BPS patch is truncated");
+                }
+                target.extend_from_slice(&patch[pos..pos + length]);
+                pos += length;
+            }
+            // SourceCopy
+            2 => {
+                let offset = read_signed_varint(patch, &mut pos, end)?;
+                source_relative = source_relative.checked_add(offset).ok_or(CORRUPT)?;
+                let in_source = (source_relative as usize).checked_add(length).is_some_and(|end| end <= rom.len());
+                if source_relative < 0 || !in_source {
+                    return Err("BPS patch copies from outside the source rom");
+                }
+                let start = source_relative as usize;
+                target.extend_from_slice(&rom[start..start + length]);
+                source_relative += length as isize;
+            }
+            // TargetCopy, byte by byte since the ranges may overlap
+            _ => {
+                let offset = read_signed_varint(patch, &mut pos, end)?;
+                target_relative = target_relative.checked_add(offset).ok_or(CORRUPT)?;
+                if target_relative < 0 || target_relative as usize >= target.len() {
+                    return Err("BPS patch copies from outside the patched rom");
+                }
+                for _ in 0..length {
+                    let byte = target[target_relative as usize];
+                    target.push(byte);
+                    target_relative += 1;
+                }
+            }
+        }
+    }
+
+    if target.len() != target_size {
+        return Err("BPS patch produced a rom of the wrong size");
+    }
+    if crc32(&target) != target_crc {
+        return Err("Patched rom checksum mismatch");
+    }
+    Ok(target)
+}
+
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, &'static str> {
+    let (source_crc, target_crc) = check_footer(patch)?;
+
+    let end = patch.len() - FOOTER_SIZE;
+    let mut pos = UPS_MAGIC.len();
+    let mut source_size = read_varint(patch, &mut pos, end)?;
+    let mut target_size = read_varint(patch, &mut pos, end)?;
+
+    // UPS patches are XOR based, so they can be applied in either direction
+    let rom_crc = crc32(rom);
+    let expected_crc = if rom_crc == source_crc {
+        target_crc
+    } else if rom_crc == target_crc {
+        ::std::mem::swap(&mut source_size, &mut target_size);
+        source_crc
+    } else {
+        return Err("UPS patch was made for a different rom (source checksum mismatch)");
+    };
+    if source_size != rom.len() {
+        return Err("UPS patch was made for a different rom (source size mismatch)");
+    }
+    if target_size > MAX_TARGET_SIZE {
+        return Err(CORRUPT);
+    }
+
+    let mut target = rom.to_vec();
+    target.resize(target_size, 0);
+    let mut offset = 0usize;
+    while pos < end {
+        offset = offset.checked_add(read_varint(patch, &mut pos, end)?).ok_or(CORRUPT)?;
+        loop {
+            if pos >= end {
+                return Err("UPS patch is truncated");
+            }
+            let xor = patch[pos];
+            pos += 1;
+            if offset < target.len() {
+                target[offset] ^= xor;
+            }
+            offset = offset.checked_add(1).ok_or(CORRUPT)?;
+            if xor == 0 {
+                break;
+            }
+        }
+    }
+
+    if crc32(&target) != expected_crc {
+        return Err("Patched rom checksum mismatch");
+    }
+    Ok(target)
+}
+
+// Validates the patch's own checksum and returns the source and target checksums
+fn check_footer(patch: &[u8]) -> Result<(u32, u32), &'static str> {
+    if patch.len() < BPS_MAGIC.len() + FOOTER_SIZE {
+        return Err("Patch is truncated");
+    }
+    let footer = &patch[patch.len() - FOOTER_SIZE..];
+    let source_crc = read_le32(&footer[0..4]);
+    let target_crc = read_le32(&footer[4..8]);
+    let patch_crc = read_le32(&footer[8..12]);
+    if crc32(&patch[..patch.len() - 4]) != patch_crc {
+        return Err("Patch file is corrupt (patch checksum mismatch)");
+    }
+    Ok((source_crc, target_crc))
+}
+
+fn read_be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |acc, &b| (acc << 8) | b as usize)
+}
+
+fn read_le32(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+// Variable length integers shared by BPS and UPS. Ones too big for a usize
+// only come from corrupt patches.
+fn read_varint(patch: &[u8], pos: &mut usize, end: usize) -> Result<usize, &'static str> {
+    let mut result = 0usize;
+    let mut shift = 1usize;
+    loop {
+        if *pos >= end {
+            return Err("Patch is truncated");
+        }
+        let byte = patch[*pos];
+        *pos += 1;
+        let bits = ((byte & 0x7F) as usize).checked_mul(shift).ok_or(CORRUPT)?;
+        result = result.checked_add(bits).ok_or(CORRUPT)?;
+        if byte & 0x80 != 0 {
+            break;
+        }
+        shift = shift.checked_mul(0x80).ok_or(CORRUPT)?;
+        result = result.checked_add(shift).ok_or(CORRUPT)?;
+    }
+    Ok(result)
+}
+
+fn read_signed_varint(patch: &[u8], pos: &mut usize, end: usize) -> Result<isize, &'static str> {
+    let data = read_varint(patch, pos, end)?;
+    let magnitude = (data >> 1) as isize;
+    Ok(if data & 1 != 0 { -magnitude } else { magnitude })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn varint(mut val: usize) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        loop {
+            let bits = (val & 0x7F) as u8;
+            val >>= 7;
+            if val == 0 {
+                bytes.push(0x80 | bits);
+                return bytes;
+            }
+            bytes.push(bits);
+            val -= 1;
+        }
+    }
+
+    fn le32(val: u32) -> Vec<u8> {
+        vec![val as u8, (val >> 8) as u8, (val >> 16) as u8, (val >> 24) as u8]
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend(le32(crc32(source)));
+        patch.extend(le32(crc32(target)));
+        let patch_crc = crc32(&patch);
+        patch.extend(le32(patch_crc));
+        patch
+    }
+
+    #[test]
+    fn ips_records() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0, 0, 2, 0, 2, 0xAA, 0xBB]); // Two bytes at 2
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 4, 0xCC]); // RLE run of 4 at 6, past the end
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply_patch(&[0; 8], &patch).unwrap(), vec![0, 0, 0xAA, 0xBB, 0, 0, 0xCC, 0xCC, 0xCC, 0xCC]);
+
+        patch.extend_from_slice(&[0, 0, 3]); // Truncate to 3 bytes
+        assert_eq!(apply_patch(&[0; 8], &patch).unwrap(), vec![0, 0, 0xAA]);
+    }
+
+    #[test]
+    fn ips_truncated() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0, 0, 2, 0, 4, 0xAA]);
+        assert!(apply_patch(&[0; 8], &patch).is_err());
+        assert!(apply_patch(&[0; 8], IPS_MAGIC).is_err());
+    }
+
+    fn bps(source: &[u8], target: &[u8]) -> Vec<u8> {
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        patch.extend(varint(0));
+        patch.extend(varint((3 - 1) << 2)); // SourceRead "abc", action 0
+        patch.extend(varint((2 - 1) << 2 | 1)); // TargetRead "XY"
+        patch.extend_from_slice(b"XY");
+        patch.extend(varint((3 - 1) << 2 | 2)); // SourceCopy "abc" from 0
+        patch.extend(varint(0));
+        patch.extend(varint((2 - 1) << 2 | 3)); // TargetCopy "ab" from 0
+        patch.extend(varint(0));
+        with_footer(patch, source, target)
+    }
+
+    #[test]
+    fn bps_commands() {
+        let patch = bps(b"abcdef", b"abcXYabcab");
+        assert_eq!(apply_patch(b"abcdef", &patch).unwrap(), b"abcXYabcab".to_vec());
+        assert!(apply_patch(b"abcdeg", &patch).is_err());
+    }
+
+    #[test]
+    fn bps_bad_crc() {
+        // Claims a different target, so the result fails the target checksum
+        let patch = bps(b"abcdef", b"abcXYabcac");
+        assert_eq!(apply_patch(b"abcdef", &patch).unwrap_err(), "Patched rom checksum mismatch");
+
+        let mut patch = bps(b"abcdef", b"abcXYabcab");
+        let last = patch.len() - 1;
+        patch[last] ^= 1;
+        assert_eq!(apply_patch(b"abcdef", &patch).unwrap_err(), "Patch file is corrupt (patch checksum mismatch)");
+    }
+
+    #[test]
+    fn bps_overflows_are_corrupt() {
+        // A varint that never ends within a usize
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x7F; 12]);
+        let patch = with_footer(patch, b"abc", b"");
+        assert_eq!(apply_patch(b"abc", &patch).unwrap_err(), CORRUPT);
+
+        // A huge target size
+        let mut patch = BPS_MAGIC.to_vec();
+        patch.extend(varint(3));
+        patch.extend(varint(usize::MAX / 2));
+        patch.extend(varint(0));
+        let patch = with_footer(patch, b"abc", b"");
+        assert_eq!(apply_patch(b"abc", &patch).unwrap_err(), CORRUPT);
+    }
+
+    #[test]
+    fn ups_both_directions() {
+        let (source, target) = (b"abcdef", b"abXdefGH");
+        let mut patch = UPS_MAGIC.to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        patch.extend(varint(2));
+        patch.extend_from_slice(&[b'c' ^ b'X', 0]);
+        patch.extend(varint(2)); // Skips "de"
+        patch.extend_from_slice(&[b'G', b'H', 0]);
+        let patch = with_footer(patch, source, target);
+        assert_eq!(apply_patch(source, &patch).unwrap(), target.to_vec());
+        assert_eq!(apply_patch(target, &patch).unwrap(), source.to_vec());
+        assert!(apply_patch(b"abcdeg", &patch).is_err());
+    }
+}
@@ -2,18 +2,31 @@ use std::fmt;
 
 use super::nes::KILOBYTE;
 use super::cart::Cartridge;
+use super::rom::Mirroring;
 
 const LAST_VISIBLE: u8 = 239; // 240 Total, 0 indexed
-const LAST_VBLANK: u8 = 20;
+const LAST_VBLANK: u8 = 19; // 20 Total, 0 indexed
+const DOTS_PER_SCANLINE: usize = 341;
 
 pub struct Ppu {
     registers: Registers,
     cycles: usize,
+    frame: usize,
     current_scanline: Scanline,
     pixel_shift_register: [u8; 2],
     ram: [u8; 2 * KILOBYTE],
     oam: [u8; 256],
+    palette: [u8; 32],
     position: Position,
+    nmi_pending: bool,
+    read_buffer: u8, // PPUDATA reads return the byte fetched by the previous one
+    io_bus: u8,      // Last value written to a register, read back from write-only ones
+
+    // Internal scroll registers, named as in "PPU scrolling" on NESDevWiki
+    v: u16,     // Current VRAM address
+    t: u16,     // Temporary VRAM address
+    fine_x: u8, // 3 bits
+    w: bool,    // First or second write toggle
 }
 
 // Register names match what's listed on NESDevWiki
@@ -88,7 +101,7 @@ impl Scanline {
             Visible(line) => Visible(line + 1),
             PostRender => VBlank(0),
             VBlank(LAST_VBLANK) => PreRender,
-            VBlank(line) => Visible(line + 1),
+            VBlank(line) => VBlank(line + 1),
         }
     }
 }
@@ -103,11 +116,21 @@ impl Ppu {
         Ppu {
             registers: Registers::default(),
             cycles: 0,
+            frame: 0,
             current_scanline: Scanline::Visible(0),
             pixel_shift_register: [0; 2],
             ram: [0u8; 2*KILOBYTE],
             oam: [0u8; 256],
+            palette: [0u8; 32],
             position: Position::default(),
+            nmi_pending: false,
+            read_buffer: 0,
+            io_bus: 0,
+
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
         }
     }
 
@@ -116,50 +139,296 @@ impl Ppu {
         self.registers.ppumask = PpuMask::empty();
         self.registers.ppuscroll = 0;
         self.registers.ppudata = 0;
+        self.read_buffer = 0;
+        self.w = false;
     }
 
-    pub fn step(&mut self, cart: &mut Cartridge, cpu_cycles: u8) {
-        use self::Scanline::*;
-        println!("{:?}", self);
+    // CPU reads of $2000-$2007 and their mirrors
+    pub fn read_register(&mut self, cart: &mut Cartridge, addr: u16) -> u8 {
+        match addr % 8 {
+            2 => {
+                let status = (self.registers.ppustatus.bits() & !PpuStatus::LAST_WRITTEN_BITS.bits())
+                    | (self.io_bus & PpuStatus::LAST_WRITTEN_BITS.bits());
+                self.registers.ppustatus.remove(PpuStatus::VBLANK);
+                self.w = false;
+                status
+            }
+            4 => self.oam[self.registers.oamaddr as usize],
+            7 => {
+                let addr = self.v & 0x3FFF;
+                let val = match addr {
+                    0x0000...0x1FFF => cart.chr_read(addr),
+                    _ => self.peek_vram(cart, addr),
+                };
+                self.increment_v();
+                if addr >= 0x3F00 {
+                    // Palette reads skip the buffer, which gets the nametable byte underneath
+                    self.read_buffer = self.peek_vram(cart, addr - 0x1000);
+                    val
+                } else {
+                    let buffered = self.read_buffer;
+                    self.read_buffer = val;
+                    buffered
+                }
+            }
+            _ => self.io_bus,
+        }
+    }
 
-        match self.current_scanline {
-            PreRender => self.prerender(),
-            Visible(line) => self.visible(line),
-            PostRender => self.postrender(),
-            VBlank(line) => self.vblank(line)
+    // CPU writes to $2000-$2007 and their mirrors. Scroll and address writes go through
+    // t, v, fine x and w as described in "PPU scrolling" on NESDevWiki.
+    pub fn write_register(&mut self, cart: &mut Cartridge, addr: u16, val: u8) {
+        self.io_bus = val;
+        match addr % 8 {
+            0 => {
+                let ctrl = PpuCtrl::from_bits_truncate(val);
+                // Enabling NMI during vblank raises one right away
+                if ctrl.contains(PpuCtrl::NMI) && !self.registers.ppuctrl.contains(PpuCtrl::NMI)
+                    && self.registers.ppustatus.contains(PpuStatus::VBLANK) {
+                    self.nmi_pending = true;
+                }
+                self.registers.ppuctrl = ctrl;
+                self.t = (self.t & !0x0C00) | ((val as u16 & 0x03) << 10);
+            }
+            1 => self.registers.ppumask = PpuMask::from_bits_truncate(val),
+            2 => {} // Read only
+            3 => self.registers.oamaddr = val,
+            4 => self.write_oam_data(val),
+            5 => {
+                self.registers.ppuscroll = val;
+                if !self.w {
+                    self.t = (self.t & !0x001F) | (val as u16 >> 3);
+                    self.fine_x = val & 0x07;
+                } else {
+                    self.t = (self.t & !0x73E0) | ((val as u16 & 0x07) << 12) | ((val as u16 & 0xF8) << 2);
+                }
+                self.w = !self.w;
+            }
+            6 => {
+                self.registers.ppuaddr = val;
+                if !self.w {
+                    self.t = (self.t & 0x00FF) | ((val as u16 & 0x3F) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | val as u16;
+                    self.v = self.t;
+                }
+                self.w = !self.w;
+            }
+            7 => {
+                self.registers.ppudata = val;
+                let addr = self.v;
+                self.poke_vram(cart, addr, val);
+                self.increment_v();
+            }
+            _ => unreachable!(),
         }
-        self.cycles += 3;
     }
 
-    fn prerender(&mut self) {
-        if self.cycles >= 341 {
-            self.cycles = 0;
+    // Through OAMDATA or OAM DMA
+    pub fn write_oam_data(&mut self, val: u8) {
+        self.oam[self.registers.oamaddr as usize] = val;
+        self.registers.oamaddr = self.registers.oamaddr.wrapping_add(1);
+    }
+
+    pub fn write_oam_dma(&mut self, page: u8) {
+        self.registers.oamdma = page;
+    }
+
+    fn increment_v(&mut self) {
+        let increment = if self.registers.ppuctrl.contains(PpuCtrl::VRAM_ADDR_INCREMENT) { 32 } else { 1 };
+        self.v = (self.v + increment) & 0x7FFF;
+    }
+
+    // Runs the three dots per CPU cycle. Dots past the end of a scanline carry into the next one.
+    pub fn step(&mut self, cart: &mut Cartridge, cpu_cycles: usize) {
+        use self::Scanline::*;
+
+        self.cycles += 3 * cpu_cycles;
+        while self.cycles >= DOTS_PER_SCANLINE {
+            self.cycles -= DOTS_PER_SCANLINE;
+            match self.current_scanline {
+                PreRender => self.prerender(),
+                Visible(line) => self.visible(line),
+                PostRender => self.postrender(),
+                VBlank(line) => self.vblank(line)
+            }
             self.current_scanline = self.current_scanline.next();
         }
     }
 
+    // The scanline handlers run as each line ends
+    fn prerender(&mut self) {
+        self.frame += 1;
+    }
+
     fn visible(&mut self, line: u8) {
-        if self.cycles >= 341 {
-            self.cycles = 0;
-            self.current_scanline = self.current_scanline.next();
-        }
     }
 
     fn postrender(&mut self) {
-        if self.cycles >= 341 {
-            self.cycles = 0;
-            self.current_scanline = self.current_scanline.next();
+        self.registers.ppustatus.insert(PpuStatus::VBLANK);
+        if self.registers.ppuctrl.contains(PpuCtrl::NMI) {
+            self.nmi_pending = true;
         }
     }
 
     fn vblank(&mut self, line: u8) {
-        if self.cycles >= 341 {
-            self.cycles = 0;
-            self.current_scanline = self.current_scanline.next();
+        if line == LAST_VBLANK {
+            self.registers.ppustatus.remove(PpuStatus::VBLANK | PpuStatus::SPRITE_0_HIT);
         }
     }
 
+    // True once per NMI, the CPU should service it right away
+    pub fn take_nmi(&mut self) -> bool {
+        let nmi = self.nmi_pending;
+        self.nmi_pending = false;
+        nmi
+    }
+
     // Methods for debugger
+    // Scanline numbered 0-261 as on NESDevWiki, with 261 being the pre-render line
+    pub fn scanline(&self) -> u16 {
+        use self::Scanline::*;
+        match self.current_scanline {
+            Visible(line) => line as u16,
+            PostRender => LAST_VISIBLE as u16 + 1,
+            VBlank(line) => LAST_VISIBLE as u16 + 2 + line as u16,
+            PreRender => LAST_VISIBLE as u16 + 3 + LAST_VBLANK as u16,
+        }
+    }
+
+    // Register contents as last written or latched, without the side effects of a CPU read
+    pub fn peek_register(&self, addr: u16) -> u8 {
+        match addr % 8 {
+            0 => self.registers.ppuctrl.bits(),
+            1 => self.registers.ppumask.bits(),
+            2 => self.registers.ppustatus.bits(),
+            3 => self.registers.oamaddr,
+            4 => self.oam[self.registers.oamaddr as usize],
+            5 => self.registers.ppuscroll,
+            6 => self.registers.ppuaddr,
+            7 => self.registers.ppudata,
+            _ => unreachable!(),
+        }
+    }
+
+    // Sets what peek_register returns, without the side effects of a CPU write
+    pub fn poke_register(&mut self, addr: u16, val: u8) {
+        match addr % 8 {
+            0 => self.registers.ppuctrl = PpuCtrl::from_bits_truncate(val),
+            1 => self.registers.ppumask = PpuMask::from_bits_truncate(val),
+            2 => self.registers.ppustatus = PpuStatus::from_bits_truncate(val),
+            3 => self.registers.oamaddr = val,
+            4 => self.oam[self.registers.oamaddr as usize] = val,
+            5 => self.registers.ppuscroll = val,
+            6 => self.registers.ppuaddr = val,
+            7 => self.registers.ppudata = val,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn ctrl(&self) -> u8 {
+        self.registers.ppuctrl.bits()
+    }
+
+    pub fn mask(&self) -> u8 {
+        self.registers.ppumask.bits()
+    }
+
+    pub fn status(&self) -> u8 {
+        self.registers.ppustatus.bits()
+    }
+
+    pub fn oam_addr(&self) -> u8 {
+        self.registers.oamaddr
+    }
+
+    pub fn oam_dma(&self) -> u8 {
+        self.registers.oamdma
+    }
+
+    pub fn v(&self) -> u16 {
+        self.v
+    }
+
+    pub fn t(&self) -> u16 {
+        self.t
+    }
+
+    pub fn fine_x(&self) -> u8 {
+        self.fine_x
+    }
+
+    pub fn w(&self) -> bool {
+        self.w
+    }
+
+    pub fn oam(&self) -> &[u8] {
+        &self.oam
+    }
+
+    pub fn palette(&self) -> &[u8] {
+        &self.palette
+    }
+
+    // One of the two physical 1KB nametables in PPU RAM
+    pub fn nametable(&self, index: usize) -> &[u8] {
+        &self.ram[index * KILOBYTE..(index + 1) * KILOBYTE]
+    }
+
+    // PPU address space as seen through PPUDATA, without the read buffer
+    pub fn peek_vram(&self, cart: &Cartridge, addr: u16) -> u8 {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000...0x1FFF => cart.chr_peek(addr),
+            0x2000...0x3EFF => vram_index(cart, addr).map_or(0, |i| self.ram[i]),
+            _ => self.palette[palette_index(addr)],
+        }
+    }
+
+    pub fn poke_vram(&mut self, cart: &mut Cartridge, addr: u16, val: u8) {
+        let addr = addr & 0x3FFF;
+        match addr {
+            0x0000...0x1FFF => cart.chr_write(addr, val),
+            0x2000...0x3EFF => if let Some(i) = vram_index(cart, addr) { self.ram[i] = val },
+            _ => self.palette[palette_index(addr)] = val,
+        }
+    }
+
+    pub fn poke_oam(&mut self, addr: u8, val: u8) {
+        self.oam[addr as usize] = val;
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.cycles as u16
+    }
+
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+}
+
+// Which of the PPU's two 1KB nametables each of the four logical ones maps to
+pub fn physical_nametable(mirroring: Mirroring, index: usize) -> Option<usize> {
+    match mirroring {
+        Mirroring::Horizontal => Some(index / 2),
+        Mirroring::Vertical => Some(index % 2),
+        Mirroring::SingleScreenLower => Some(0),
+        Mirroring::SingleScreenUpper => Some(1),
+        // The extra 2KB of four screen carts isn't emulated
+        Mirroring::FourScreen | Mirroring::MapperControlled => if index < 2 { Some(index) } else { None },
+    }
+}
+
+// $3000-$3EFF mirrors the nametables
+fn vram_index(cart: &Cartridge, addr: u16) -> Option<usize> {
+    let offset = (addr as usize - 0x2000) % (4 * KILOBYTE);
+    physical_nametable(cart.mirroring(), offset / KILOBYTE).map(|index| index * KILOBYTE + offset % KILOBYTE)
+}
+
+// $3F10/$3F14/$3F18/$3F1C mirror the background entries below them
+fn palette_index(addr: u16) -> usize {
+    let index = addr as usize % 32;
+    if index >= 16 && index.is_multiple_of(4) { index - 16 } else { index }
 }
 
 impl fmt::Debug for Ppu {
@@ -200,3 +469,120 @@ impl fmt::Debug for Registers {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rom::test_rom;
+    use super::*;
+
+    #[test]
+    fn frame_timing() {
+        let mut cart = Cartridge::new(test_rom(&[])).unwrap();
+        let mut ppu = Ppu::new();
+        // 262 scanlines of 341 dots are 29780 2/3 CPU cycles, so the frame ends one dot into the next cycle
+        for _ in 0..29780 {
+            ppu.step(&mut cart, 1);
+        }
+        assert_eq!((ppu.frame(), ppu.scanline(), ppu.dot()), (0, 261, 339));
+        ppu.step(&mut cart, 1);
+        assert_eq!((ppu.frame(), ppu.scanline(), ppu.dot()), (1, 0, 1));
+
+        // Dots left at the end of a scanline carry over, so longer steps keep the same pace
+        let mut ppu = Ppu::new();
+        for _ in 0..262 * 341 {
+            ppu.step(&mut cart, 3);
+        }
+        assert_eq!((ppu.frame(), ppu.scanline(), ppu.dot()), (9, 0, 0));
+    }
+
+    #[test]
+    fn vblank_starts_on_scanline_241() {
+        let mut cart = Cartridge::new(test_rom(&[])).unwrap();
+        let mut ppu = Ppu::new();
+        ppu.poke_register(0x2000, PpuCtrl::NMI.bits());
+        ppu.step(&mut cart, 241 * 341 / 3);
+        assert_eq!((ppu.scanline(), ppu.dot()), (240, 339));
+        assert!(!ppu.take_nmi() && ppu.status() & PpuStatus::VBLANK.bits() == 0);
+        ppu.step(&mut cart, 1);
+        assert_eq!(ppu.scanline(), 241);
+        assert!(ppu.take_nmi() && ppu.status() & PpuStatus::VBLANK.bits() != 0);
+
+        // Cleared again for the pre-render scanline
+        ppu.step(&mut cart, 20 * 341 / 3 + 1);
+        assert_eq!(ppu.scanline(), 261);
+        assert_eq!(ppu.status() & PpuStatus::VBLANK.bits(), 0);
+    }
+
+    #[test]
+    fn scroll_registers() {
+        let mut cart = Cartridge::new(test_rom(&[])).unwrap();
+        let mut ppu = Ppu::new();
+        // The example sequence from "PPU scrolling" on NESDevWiki
+        ppu.write_register(&mut cart, 0x2000, 0x00);
+        ppu.read_register(&mut cart, 0x2002);
+        ppu.write_register(&mut cart, 0x2005, 0x7D);
+        assert_eq!((ppu.t(), ppu.fine_x(), ppu.w()), (0x000F, 0x05, true));
+        ppu.write_register(&mut cart, 0x2005, 0x5E);
+        assert_eq!((ppu.t(), ppu.w()), (0x616F, false));
+        ppu.write_register(&mut cart, 0x2006, 0x3D);
+        assert_eq!((ppu.t(), ppu.w()), (0x3D6F, true));
+        ppu.write_register(&mut cart, 0x2006, 0xF0);
+        assert_eq!((ppu.t(), ppu.v(), ppu.w()), (0x3DF0, 0x3DF0, false));
+
+        // Nametable select lands in t, reading PPUSTATUS resets the toggle
+        ppu.write_register(&mut cart, 0x2000, 0x01);
+        assert_eq!(ppu.t(), 0x35F0);
+        ppu.write_register(&mut cart, 0x2005, 0x00);
+        ppu.read_register(&mut cart, 0x200A); // Mirror of $2002
+        assert!(!ppu.w());
+    }
+
+    #[test]
+    fn vram_access() {
+        let mut cart = Cartridge::new(test_rom(&[])).unwrap();
+        let mut ppu = Ppu::new();
+        ppu.write_register(&mut cart, 0x2006, 0x21);
+        ppu.write_register(&mut cart, 0x2006, 0x08);
+        ppu.write_register(&mut cart, 0x2007, 0x55);
+        ppu.write_register(&mut cart, 0x2007, 0x66);
+        assert_eq!(ppu.v(), 0x210A);
+
+        // Reads come through a buffer, so the first one returns stale data
+        ppu.write_register(&mut cart, 0x2006, 0x21);
+        ppu.write_register(&mut cart, 0x2006, 0x08);
+        ppu.read_register(&mut cart, 0x2007);
+        assert_eq!(ppu.read_register(&mut cart, 0x2007), 0x55);
+        assert_eq!(ppu.read_register(&mut cart, 0x2007), 0x66);
+
+        // Except palette reads, and +32 goes down a column
+        ppu.write_register(&mut cart, 0x2000, PpuCtrl::VRAM_ADDR_INCREMENT.bits());
+        ppu.write_register(&mut cart, 0x2006, 0x3F);
+        ppu.write_register(&mut cart, 0x2006, 0x01);
+        ppu.write_register(&mut cart, 0x2007, 0x2A);
+        assert_eq!(ppu.v(), 0x3F21);
+        ppu.write_register(&mut cart, 0x2006, 0x3F);
+        ppu.write_register(&mut cart, 0x2006, 0x01);
+        assert_eq!(ppu.read_register(&mut cart, 0x2007), 0x2A);
+
+        // OAMDATA writes bump OAMADDR
+        ppu.write_register(&mut cart, 0x2003, 0xFF);
+        ppu.write_register(&mut cart, 0x2004, 0x12);
+        ppu.write_register(&mut cart, 0x2004, 0x34);
+        assert_eq!((ppu.oam()[0xFF], ppu.oam()[0x00], ppu.oam_addr()), (0x12, 0x34, 0x01));
+    }
+
+    #[test]
+    fn status_read_clears_vblank() {
+        let mut cart = Cartridge::new(test_rom(&[])).unwrap();
+        let mut ppu = Ppu::new();
+        ppu.step(&mut cart, 241 * 341 / 3 + 1);
+        ppu.write_register(&mut cart, 0x2001, 0x1E);
+        assert_eq!(ppu.read_register(&mut cart, 0x2002), 0x9E);
+        assert_eq!(ppu.read_register(&mut cart, 0x2002), 0x1E);
+
+        // Enabling NMI in vblank raises one at once
+        ppu.poke_register(0x2002, PpuStatus::VBLANK.bits());
+        ppu.write_register(&mut cart, 0x2000, PpuCtrl::NMI.bits());
+        assert!(ppu.take_nmi());
+    }
+}
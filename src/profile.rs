@@ -0,0 +1,101 @@
+//! Instruction hit counts and CPU cycles per address, and per routine as tracked
+//! by the CPU's shadow call stack
+
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use super::symbols::Symbols;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Counts {
+    pub hits: u64,
+    pub cycles: u64,
+}
+
+impl Counts {
+    fn add(&mut self, cycles: usize) {
+        self.hits += 1;
+        self.cycles += cycles as u64;
+    }
+}
+
+pub struct Profiler {
+    pub enabled: bool,
+    by_pc: Vec<Counts>,
+    by_routine: HashMap<Option<u16>, Counts>, // Keyed by entry point, None outside any call
+    csv_path: Option<PathBuf>,
+}
+
+impl Profiler {
+    pub fn new(csv_path: Option<PathBuf>) -> Self {
+        Profiler {
+            enabled: true,
+            by_pc: vec![Counts::default(); 0x10000],
+            by_routine: HashMap::new(),
+            csv_path,
+        }
+    }
+
+    pub fn record(&mut self, pc: u16, routine: Option<u16>, cycles: usize) {
+        if !self.enabled {
+            return;
+        }
+        self.by_pc[pc as usize].add(cycles);
+        self.by_routine.entry(routine).or_default().add(cycles);
+    }
+
+    pub fn reset(&mut self) {
+        for counts in self.by_pc.iter_mut() {
+            *counts = Counts::default();
+        }
+        self.by_routine.clear();
+    }
+
+    pub fn total(&self) -> Counts {
+        self.by_routine.values().fold(Counts::default(), |total, counts| Counts {
+            hits: total.hits + counts.hits,
+            cycles: total.cycles + counts.cycles,
+        })
+    }
+
+    // Most expensive first
+    pub fn addresses(&self) -> Vec<(u16, Counts)> {
+        let mut addresses: Vec<(u16, Counts)> = self.by_pc.iter().enumerate()
+            .filter(|&(_, counts)| counts.hits > 0)
+            .map(|(pc, &counts)| (pc as u16, counts))
+            .collect();
+        addresses.sort_by_key(|&(_, counts)| Reverse(counts.cycles));
+        addresses
+    }
+
+    pub fn routines(&self) -> Vec<(Option<u16>, Counts)> {
+        let mut routines: Vec<(Option<u16>, Counts)> = self.by_routine.iter()
+            .map(|(&routine, &counts)| (routine, counts))
+            .collect();
+        routines.sort_by_key(|&(_, counts)| Reverse(counts.cycles));
+        routines
+    }
+
+    // Rewrites the --profile file if there is one
+    pub fn save(&self, symbols: &Symbols) -> io::Result<()> {
+        let path = match self.csv_path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(out, "kind,address,label,hits,cycles")?;
+        for (pc, counts) in self.addresses() {
+            writeln!(out, "pc,${:04X},{},{},{}", pc, symbols.label(pc).unwrap_or(""), counts.hits, counts.cycles)?;
+        }
+        for (routine, counts) in self.routines() {
+            match routine {
+                Some(addr) => writeln!(out, "routine,${:04X},{},{},{}", addr, symbols.label(addr).unwrap_or(""), counts.hits, counts.cycles)?,
+                None => writeln!(out, "routine,,top level,{},{}", counts.hits, counts.cycles)?,
+            }
+        }
+        out.flush()
+    }
+}
@@ -1,11 +1,27 @@
-//! Provides an abstraction for iNES Rom format
+//! Provides an abstraction for iNES and UNIF Rom formats
 #![allow(dead_code)]
 
 use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 
-pub fn read_rom(path: &Path) -> Result<Rom, &str> {
+use super::nes::KILOBYTE;
+use super::patch;
+
+const INES_MAGIC: [u8; 4] = [0x4E, 0x45, 0x53, 0x1A]; // "NES\x1A"
+const UNIF_MAGIC: [u8; 4] = [0x55, 0x4E, 0x49, 0x46]; // "UNIF"
+const UNIF_HEADER_SIZE: usize = 32;
+const UNIF_CHUNK_HEADER_SIZE: usize = 8; // 4 byte ID + 4 byte little endian length
+const FDS_MAGIC: [u8; 4] = [0x46, 0x44, 0x53, 0x1A]; // "FDS\x1A"
+const FDS_HEADER_SIZE: usize = 16;
+const FDS_DISK_VERIFICATION: &[u8] = b"*NINTENDO-HVC*";
+const FDS_BIOS_SIZE: usize = 8192;
+
+pub const FDS_SIDE_SIZE: usize = 65500;
+pub const FDS_MAPPER_NO: u8 = 20; // Historically reserved for the FDS in iNES
+
+// Reads a rom, applying the given patch or one found next to the rom first
+pub fn read_rom(path: &Path, patch_path: Option<&Path>) -> Result<Rom, &'static str> {
     let file = File::open(path);
     match file {
         Err(_) => {
@@ -21,10 +37,38 @@ pub fn read_rom(path: &Path) -> Result<Rom, &str> {
         }
         _ => {}
     }
+
+    let patch_path = patch_path.map(|p| p.to_path_buf()).or_else(|| patch::find_patch(path));
+    if let Some(patch_path) = patch_path {
+        println!("Applying patch {}", patch_path.display());
+        file_buf = patch::apply_patch_file(&file_buf, &patch_path)?;
+    }
+
+    if file_buf.starts_with(&UNIF_MAGIC) {
+        read_unif(&file_buf)
+    } else if file_buf.starts_with(&FDS_MAGIC) {
+        read_fds(&file_buf[FDS_HEADER_SIZE.min(file_buf.len())..])
+    } else if !file_buf.is_empty() && file_buf.len().is_multiple_of(FDS_SIDE_SIZE) &&
+              file_buf[1..].starts_with(FDS_DISK_VERIFICATION) {
+        // Headerless disk image
+        read_fds(&file_buf)
+    } else {
+        read_ines(&file_buf)
+    }
+}
+
+fn read_ines(file_buf: &[u8]) -> Result<Rom, &'static str> {
+    if file_buf.len() < 16 {
+        return Err("File is too small to be a valid iNES ROM");
+    }
     let mut header_buf = vec![0; 16];
     header_buf.copy_from_slice(&file_buf[0..16]);
     let header = Header::new(header_buf)?;
 
+    if file_buf.len() < calc_prg_offset(&header) + header.prg_size() + header.chr_size() {
+        return Err("File is smaller than its iNES header claims");
+    }
+
     let mut prg_rom = vec![0; header.prg_size()];
     let offset = calc_prg_offset(&header);
     prg_rom.copy_from_slice(&file_buf[offset..header.prg_size() + offset]);
@@ -43,6 +87,195 @@ pub fn read_rom(path: &Path) -> Result<Rom, &str> {
     })
 }
 
+// UNIF stores everything in tagged chunks. PRG and CHR data are split over up to
+// 16 chunks each (PRG0-PRGF, CHR0-CHRF) which are concatenated in order.
+fn read_unif(file_buf: &[u8]) -> Result<Rom, &'static str> {
+    if file_buf.len() < UNIF_HEADER_SIZE {
+        return Err("File is too small to be a valid UNIF ROM");
+    }
+
+    let mut board: Option<String> = None;
+    let mut prg_chunks: Vec<Option<&[u8]>> = vec![None; 16];
+    let mut chr_chunks: Vec<Option<&[u8]>> = vec![None; 16];
+    let mut mirroring = Mirroring::Horizontal;
+    let mut battery = false;
+    let mut region = Region::Ntsc;
+
+    let mut offset = UNIF_HEADER_SIZE;
+    while offset < file_buf.len() {
+        if offset + UNIF_CHUNK_HEADER_SIZE > file_buf.len() {
+            return Err("Truncated UNIF chunk header");
+        }
+        let id = &file_buf[offset..offset + 4];
+        let len = read_u32_le(&file_buf[offset + 4..offset + 8]) as usize;
+        let start = offset + UNIF_CHUNK_HEADER_SIZE;
+        if start + len > file_buf.len() {
+            return Err("Truncated UNIF chunk data");
+        }
+        let data = &file_buf[start..start + len];
+
+        match id {
+            b"MAPR" => {
+                // Board name is a null terminated string
+                let name_len = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                match String::from_utf8(data[..name_len].to_vec()) {
+                    Ok(name) => board = Some(name),
+                    Err(_) => return Err("UNIF board name is not valid UTF-8"),
+                }
+            }
+            b"MIRR" => {
+                mirroring = match data.first() {
+                    Some(&0) => Mirroring::Horizontal,
+                    Some(&1) => Mirroring::Vertical,
+                    Some(&2) => Mirroring::SingleScreenLower,
+                    Some(&3) => Mirroring::SingleScreenUpper,
+                    Some(&4) => Mirroring::FourScreen,
+                    Some(&5) => Mirroring::MapperControlled,
+                    _ => return Err("Invalid UNIF mirroring value"),
+                }
+            }
+            b"BATR" => battery = true,
+            b"TVCI" => {
+                region = match data.first() {
+                    Some(&1) => Region::Pal,
+                    Some(&2) => Region::Multi,
+                    _ => Region::Ntsc,
+                }
+            }
+            _ if &id[0..3] == b"PRG" => {
+                let idx = unif_chunk_index(id[3])?;
+                prg_chunks[idx] = Some(data);
+            }
+            _ if &id[0..3] == b"CHR" => {
+                let idx = unif_chunk_index(id[3])?;
+                chr_chunks[idx] = Some(data);
+            }
+            _ => {} // Chunks we don't care about (NAME, READ, DINF, PCKx, etc.)
+        }
+        offset = start + len;
+    }
+
+    let board = match board {
+        Some(board) => board,
+        None => return Err("UNIF ROM is missing a MAPR chunk"),
+    };
+    let mapper_no = unif_board_to_mapper(&board)?;
+
+    let prg_rom: Vec<u8> = prg_chunks.iter().filter_map(|c| *c).flat_map(|c| c.iter().cloned()).collect();
+    let chr_rom: Vec<u8> = chr_chunks.iter().filter_map(|c| *c).flat_map(|c| c.iter().cloned()).collect();
+    if prg_rom.is_empty() {
+        return Err("UNIF ROM contains no PRG chunks");
+    }
+    // iNES sizes can't be anything else, but UNIF chunks can
+    let prg_ok = prg_rom.len() == 16 * KILOBYTE || prg_rom.len() == 32 * KILOBYTE;
+    let chr_ok = chr_rom.is_empty() || chr_rom.len() == 8 * KILOBYTE;
+    if mapper_no == 0 && !(prg_ok && chr_ok) {
+        return Err("NROM boards need 16KB or 32KB of PRG ROM and 8KB of CHR ROM or none");
+    }
+
+    let header = Header::from_unif(board, mapper_no, prg_rom.len(), chr_rom.len(),
+                                   mirroring, battery, region);
+
+    Ok(Rom {
+        mapper_number: header.mapper_no(),
+        prg_rom: prg_rom,
+        chr_rom: chr_rom,
+        header: header,
+    })
+}
+
+// FDS images are just the disk sides back to back. The PRG data of the Rom holds
+// the sides, which the Fds mapper splits back up.
+fn read_fds(disk_buf: &[u8]) -> Result<Rom, &'static str> {
+    if disk_buf.is_empty() || !disk_buf.len().is_multiple_of(FDS_SIDE_SIZE) {
+        return Err("FDS image is not a whole number of disk sides");
+    }
+    let sides = disk_buf.len() / FDS_SIDE_SIZE;
+    for side in 0..sides {
+        let offset = side * FDS_SIDE_SIZE;
+        if disk_buf[offset] != 0x01 || !disk_buf[offset + 1..].starts_with(FDS_DISK_VERIFICATION) {
+            return Err("FDS disk side is missing its disk info block");
+        }
+    }
+
+    let header = Header::from_fds(sides);
+    Ok(Rom {
+        mapper_number: header.mapper_no(),
+        prg_rom: disk_buf.to_vec(),
+        chr_rom: Vec::new(),
+        header: header,
+    })
+}
+
+pub fn read_fds_bios(path: &Path) -> Result<Vec<u8>, &'static str> {
+    let mut bios = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => {
+            if file.read_to_end(&mut bios).is_err() {
+                return Err("Failed to read from FDS BIOS file");
+            }
+        }
+        Err(_) => return Err("Failed to open FDS BIOS file"),
+    }
+    if bios.len() != FDS_BIOS_SIZE {
+        return Err("FDS BIOS must be exactly 8KB");
+    }
+    Ok(bios)
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    (bytes[0] as u32) | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+fn unif_chunk_index(digit: u8) -> Result<usize, &'static str> {
+    match (digit as char).to_digit(16) {
+        Some(idx) if digit.is_ascii_digit() || digit.is_ascii_uppercase() => Ok(idx as usize),
+        _ => Err("Invalid UNIF PRG/CHR chunk ID"),
+    }
+}
+
+// Maps UNIF board names to the equivalent iNES mapper number so they can share
+// the same Cartridge mapper implementations.
+fn unif_board_to_mapper(board: &str) -> Result<u8, &'static str> {
+    let name = ["NES-", "HVC-", "UNL-", "BTL-", "BMC-"].iter()
+        .find(|prefix| board.starts_with(*prefix))
+        .map_or(board, |prefix| &board[prefix.len()..]);
+
+    match name {
+        "NROM" | "NROM-128" | "NROM-256" | "RROM" | "RROM-128" => Ok(0),
+
+        "SAROM" | "SBROM" | "SCROM" | "SEROM" | "SFROM" | "SGROM" | "SHROM" | "SJROM" |
+        "SKROM" | "SLROM" | "SL1ROM" | "SL2ROM" | "SL3ROM" | "SLRROM" | "SNROM" | "SOROM" |
+        "SUROM" | "SXROM" => Ok(1),
+
+        "UNROM" | "UOROM" => Ok(2),
+
+        "CNROM" => Ok(3),
+
+        "TBROM" | "TEROM" | "TFROM" | "TGROM" | "TKROM" | "TLROM" | "TL1ROM" | "TL2ROM" |
+        "TR1ROM" | "TSROM" | "TVROM" | "B4" | "HKROM" => Ok(4),
+
+        "EKROM" | "ELROM" | "ETROM" | "EWROM" => Ok(5),
+
+        "AMROM" | "ANROM" | "AN1ROM" | "AOROM" => Ok(7),
+
+        "PEEOROM" | "PNROM" => Ok(9),
+
+        "FJROM" | "FKROM" => Ok(10),
+
+        "CPROM" => Ok(13),
+
+        "GNROM" | "MHROM" => Ok(66),
+
+        _ => Err("Unsupported UNIF board"),
+    }
+}
+
+// NES 2.0 RAM sizes are shift counts, 64 << shift bytes or none for 0
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 { 0 } else { 64 << shift }
+}
+
 fn calc_prg_offset(header: &Header) -> usize {
     let mut offset = 16; // Always at least the header
     offset += if header.flags_six.trainer_present() {
@@ -63,12 +296,44 @@ pub struct Rom {
     pub header: Header,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RomFormat {
+    INes,
+    Nes2,
+    Unif,
+    Fds,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen,
+    MapperControlled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Multi,
+    Dendy,
+}
+
 #[derive(Debug)]
 pub struct Header {
+    format: RomFormat,
+    board: Option<String>, // Only UNIF names the board
     mapper_no: u8,
+    submapper: u8, // Only NES 2.0 has submappers
     prg_size: usize,
     chr_size: usize,
     ram_size: usize,
+    nvram_size: usize, // Only NES 2.0 gives battery backed RAM a separate size
+    mirroring: Mirroring,
+    region: Region,
     flags_six: FlagsSix,
     flags_sev: FlagsSev,
 }
@@ -81,28 +346,131 @@ pub struct FlagsSev(u8);
 
 impl Header {
     pub fn new(header: Vec<u8>) -> Result<Header, &'static str> {
-        if header.get(0..4).unwrap() != &[0x4E, 0x45, 0x53, 0x1A] {
+        if header.get(0..4).unwrap() != INES_MAGIC {
             return Err("File is not a valid iNES ROM");
         }
 
-        let prg_ram_size = if header[8] == 0 {
-            8192
-        } else {
-            header[8] as usize * 8192
-        };
         let flags_six = FlagsSix(header[6]);
         let flags_sev = FlagsSev(header[7]);
 
+        // NES 2.0 reuses bytes 8-15, which are mostly padding in iNES.
+        // Mapper numbers above 255 and exponent ROM sizes are not supported.
+        let nes2 = flags_sev.ines_two_fmt();
+        let (format, submapper, prg_banks, chr_banks, prg_ram_size, prg_nvram_size, region) = if nes2 {
+            (RomFormat::Nes2,
+             header[8] >> 4,
+             header[4] as usize | ((header[9] & 0x0F) as usize) << 8,
+             header[5] as usize | ((header[9] >> 4) as usize) << 8,
+             nes2_ram_size(header[10] & 0x0F),
+             nes2_ram_size(header[10] >> 4),
+             match header[12] & 0b11 {
+                 0 => Region::Ntsc,
+                 1 => Region::Pal,
+                 2 => Region::Multi,
+                 _ => Region::Dendy,
+             })
+        } else {
+            (RomFormat::INes,
+             0,
+             header[4] as usize,
+             header[5] as usize,
+             if header[8] == 0 { 8192 } else { header[8] as usize * 8192 },
+             0,
+             if header[9] & (1 << 0) != 0 { Region::Pal } else { Region::Ntsc })
+        };
+
         Ok(Header {
+            format: format,
+            board: None,
             mapper_no: flags_sev.mapper_no_upper_nibble() | flags_six.mapper_no_lower_nibble(),
-            prg_size: header[4] as usize * 16384,
-            chr_size: header[5] as usize * 8192,
+            submapper: submapper,
+            prg_size: prg_banks * 16384,
+            chr_size: chr_banks * 8192,
             ram_size: prg_ram_size,
+            nvram_size: prg_nvram_size,
+            mirroring: flags_six.mirroring(),
+            region: region,
             flags_six: flags_six,
             flags_sev: flags_sev,
         })
     }
 
+    // UNIF has no flag bytes, so the equivalent iNES flags are synthesized from its chunks
+    fn from_unif(board: String, mapper_no: u8, prg_size: usize, chr_size: usize,
+                 mirroring: Mirroring, battery: bool, region: Region) -> Header {
+        let mut flags_six = (mapper_no & 0x0F) << 4;
+        if mirroring == Mirroring::Vertical { flags_six |= 1 << 0; }
+        if battery { flags_six |= 1 << 1; }
+        if mirroring == Mirroring::FourScreen { flags_six |= 1 << 3; }
+
+        Header {
+            format: RomFormat::Unif,
+            board: Some(board),
+            mapper_no: mapper_no,
+            submapper: 0,
+            prg_size: prg_size,
+            chr_size: chr_size,
+            ram_size: 8192,
+            nvram_size: 0,
+            mirroring: mirroring,
+            region: region,
+            flags_six: FlagsSix(flags_six),
+            flags_sev: FlagsSev(mapper_no & 0xF0),
+        }
+    }
+
+    fn from_fds(sides: usize) -> Header {
+        Header {
+            format: RomFormat::Fds,
+            board: None,
+            mapper_no: FDS_MAPPER_NO,
+            submapper: 0,
+            prg_size: sides * FDS_SIDE_SIZE,
+            chr_size: 0,
+            ram_size: 32768,
+            nvram_size: 0,
+            mirroring: Mirroring::MapperControlled,
+            region: Region::Ntsc, // The Disk System was only sold in Japan
+            flags_six: FlagsSix((FDS_MAPPER_NO & 0x0F) << 4),
+            flags_sev: FlagsSev(FDS_MAPPER_NO & 0xF0),
+        }
+    }
+
+    pub fn format(&self) -> RomFormat {
+        self.format
+    }
+
+    pub fn board(&self) -> Option<&str> {
+        self.board.as_deref()
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.flags_six.contains_batt_backed_ram()
+    }
+
+    pub fn has_trainer(&self) -> bool {
+        self.flags_six.trainer_present()
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn submapper(&self) -> u8 {
+        self.submapper
+    }
+
+    pub fn disk_sides(&self) -> usize {
+        match self.format {
+            RomFormat::Fds => self.prg_size / FDS_SIDE_SIZE,
+            _ => 0,
+        }
+    }
+
     pub fn mapper_no(&self) -> u8 {
         self.mapper_no
     }
@@ -118,20 +486,33 @@ impl Header {
     pub fn ram_size(&self) -> usize {
         self.ram_size
     }
+
+    pub fn nvram_size(&self) -> usize {
+        self.nvram_size
+    }
 }
 
 impl FlagsSix {
-    // TODO: pub fn mirroring() -> Mirroring (FROM PPU)
+    pub fn mirroring(&self) -> Mirroring {
+        if self.0 & (1 << 3) != 0 {
+            Mirroring::FourScreen
+        } else if self.0 & (1 << 0) != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+
     pub fn contains_batt_backed_ram(&self) -> bool {
-        self.0 & (1 << 1) == 1
+        self.0 & (1 << 1) != 0
     }
 
     pub fn trainer_present(&self) -> bool {
-        self.0 & (1 << 2) == 1
+        self.0 & (1 << 2) != 0
     }
 
     pub fn ignore_mirroring(&self) -> bool {
-        self.0 & (1 << 3) == 1
+        self.0 & (1 << 3) != 0
     }
 
     pub fn mapper_no_lower_nibble(&self) -> u8 {
@@ -141,18 +522,125 @@ impl FlagsSix {
 
 impl FlagsSev {
     pub fn vs_unisystem(&self) -> bool {
-        self.0 & (1 << 0) == 1
+        self.0 & (1 << 0) != 0
     }
 
     pub fn playchoice_ten(&self) -> bool {
-        self.0 & (1 << 1) == 1
+        self.0 & (1 << 1) != 0
     }
 
+    // NES 2.0 is identified by bits 2 and 3 being 0b10
     pub fn ines_two_fmt(&self) -> bool {
-        self.0 & (1 << 2) == 1
+        self.0 & 0b0000_1100 == 0b0000_1000
     }
 
     pub fn mapper_no_upper_nibble(&self) -> u8 {
         self.0 & ((1 << 7) | (1 << 6) | (1 << 5) | (1 << 4))
     }
 }
+
+// A 16KB NROM rom for tests, with the program at $C000 where the reset vector points
+#[cfg(test)]
+pub fn test_rom(program: &[u8]) -> Rom {
+    let mut header = b"NES\x1A\x01\x01".to_vec();
+    header.resize(16, 0);
+    let mut prg_rom = vec![0; 16 * KILOBYTE];
+    prg_rom[..program.len()].copy_from_slice(program);
+    prg_rom[0x3FFC] = 0x00;
+    prg_rom[0x3FFD] = 0xC0;
+    Rom {
+        mapper_number: 0,
+        prg_rom: prg_rom,
+        chr_rom: vec![0; 8 * KILOBYTE],
+        header: Header::new(header).unwrap(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::slice;
+
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let len = data.len() as u32;
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&[len as u8, (len >> 8) as u8, (len >> 16) as u8, (len >> 24) as u8]);
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn unif(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = UNIF_MAGIC.to_vec();
+        file.resize(UNIF_HEADER_SIZE, 0);
+        for c in chunks {
+            file.extend_from_slice(c);
+        }
+        file
+    }
+
+    #[test]
+    fn unif_chunks_are_concatenated_in_index_order() {
+        let file = unif(&[
+            chunk(b"MAPR", b"NES-NROM-256\0"),
+            chunk(b"PRG1", &[2; 16 * KILOBYTE]),
+            chunk(b"PRG0", &[1; 16 * KILOBYTE]),
+            chunk(b"CHR0", &[3; 8 * KILOBYTE]),
+            chunk(b"MIRR", &[1]),
+            chunk(b"BATR", &[]),
+            chunk(b"NAME", b"Test\0"),
+        ]);
+        let rom = read_unif(&file).unwrap();
+        assert_eq!(rom.header.format(), RomFormat::Unif);
+        assert_eq!(rom.header.board(), Some("NES-NROM-256"));
+        assert_eq!(rom.mapper_number, 0);
+        assert_eq!(rom.prg_rom.len(), 32 * KILOBYTE);
+        assert_eq!((rom.prg_rom[0], rom.prg_rom[16 * KILOBYTE]), (1, 2));
+        assert_eq!(rom.chr_rom, vec![3; 8 * KILOBYTE]);
+        assert_eq!(rom.header.mirroring(), Mirroring::Vertical);
+        assert!(rom.header.has_battery());
+    }
+
+    #[test]
+    fn nes2_ram_sizes() {
+        let mut header = INES_MAGIC.to_vec();
+        header.extend_from_slice(&[2, 1, 0b10, 0b1000, 0, 0, 0x70, 0, 0, 0, 0, 0]);
+        let header = Header::new(header).unwrap();
+        assert_eq!(header.format(), RomFormat::Nes2);
+        assert_eq!((header.ram_size(), header.nvram_size()), (0, 8192));
+        assert!(header.has_battery());
+    }
+
+    #[test]
+    fn unif_board_prefixes_are_stripped() {
+        assert_eq!(unif_board_to_mapper("HVC-SLROM"), Ok(1));
+        assert_eq!(unif_board_to_mapper("UNROM"), Ok(2));
+        assert!(unif_board_to_mapper("NES-NOTABOARD").is_err());
+    }
+
+    #[test]
+    fn unif_errors() {
+        let prg = chunk(b"PRG0", &[0; 16 * KILOBYTE]);
+        assert!(read_unif(&UNIF_MAGIC).is_err());
+        assert_eq!(read_unif(&unif(slice::from_ref(&prg))).unwrap_err(), "UNIF ROM is missing a MAPR chunk");
+        assert!(read_unif(&unif(&[chunk(b"MAPR", b"NROM\0")])).is_err());
+        assert!(read_unif(&unif(&[chunk(b"MAPR", b"NROM\0"), chunk(b"PRGG", &[0; 16])])).is_err());
+
+        let mut truncated = unif(&[chunk(b"MAPR", b"NROM\0"), prg]);
+        truncated.pop();
+        assert_eq!(read_unif(&truncated).unwrap_err(), "Truncated UNIF chunk data");
+        truncated.truncate(UNIF_HEADER_SIZE + 4);
+        assert_eq!(read_unif(&truncated).unwrap_err(), "Truncated UNIF chunk header");
+    }
+
+    #[test]
+    fn unif_nrom_sizes_are_checked() {
+        let file = unif(&[chunk(b"MAPR", b"NES-NROM-128\0"), chunk(b"PRG0", &[0; 8 * KILOBYTE])]);
+        assert!(read_unif(&file).is_err());
+        let file = unif(&[chunk(b"MAPR", b"NES-NROM-128\0"), chunk(b"PRG0", &[0; 16 * KILOBYTE]),
+                          chunk(b"CHR0", &[0; 4 * KILOBYTE])]);
+        assert!(read_unif(&file).is_err());
+        let file = unif(&[chunk(b"MAPR", b"NES-NROM-128\0"), chunk(b"PRG0", &[0; 16 * KILOBYTE])]);
+        assert!(read_unif(&file).is_ok());
+    }
+}
@@ -0,0 +1,133 @@
+//! Labels loaded from ca65/ld65 .dbg, FCEUX .nl and Mesen .mlb files
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+#[derive(Default)]
+pub struct Symbols {
+    by_addr: HashMap<u16, String>,
+    by_name: HashMap<String, u16>,
+    prg_size: usize, // Needed to place Mesen's PRG ROM offsets in CPU address space
+}
+
+impl Symbols {
+    pub fn new(prg_size: usize) -> Self {
+        Symbols {
+            prg_size,
+            ..Symbols::default()
+        }
+    }
+
+    // Returns the number of labels loaded, the format is picked by extension
+    pub fn load(&mut self, path: &Path) -> Result<usize, &'static str> {
+        let mut contents = String::new();
+        match File::open(path) {
+            Ok(mut file) => {
+                if file.read_to_string(&mut contents).is_err() {
+                    return Err("failed to read symbol file");
+                }
+            }
+            Err(_) => return Err("failed to open symbol file"),
+        }
+
+        let labels = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("dbg") => parse_ca65_dbg(&contents),
+            Some("nl") => parse_fceux_nl(&contents),
+            Some("mlb") => parse_mesen_mlb(&contents, self.prg_size),
+            _ => return Err("unknown symbol file type, expected .dbg, .nl or .mlb"),
+        };
+        let count = labels.len();
+        for (addr, name) in labels {
+            self.insert(addr, name);
+        }
+        Ok(count)
+    }
+
+    // Later labels for an address replace earlier ones in disassembly, but the
+    // earlier names can still be used as addresses
+    pub fn insert(&mut self, addr: u16, name: String) {
+        self.by_name.insert(name.clone(), addr);
+        self.by_addr.insert(addr, name);
+    }
+
+    pub fn label(&self, addr: u16) -> Option<&str> {
+        self.by_addr.get(&addr).map(|name| name.as_str())
+    }
+
+    pub fn addr(&self, name: &str) -> Option<u16> {
+        self.by_name.get(name).cloned()
+    }
+}
+
+// sym	id=3,name="NmiHandler",addrsize=absolute,scope=0,def=12,ref=30,val=0xC0A4,seg=1,type=lab
+fn parse_ca65_dbg(contents: &str) -> Vec<(u16, String)> {
+    let mut labels = Vec::new();
+    for line in contents.lines().filter(|line| line.starts_with("sym\t")) {
+        let mut name = None;
+        let mut val = None;
+        let mut is_label = false;
+        for field in line[4..].split(',') {
+            let mut parts = field.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some("name"), Some(n)) => name = Some(n.trim_matches('"').to_owned()),
+                (Some("val"), Some(v)) if v.starts_with("0x") => val = u16::from_str_radix(&v[2..], 16).ok(),
+                (Some("type"), Some(t)) => is_label = t == "lab",
+                _ => {}
+            }
+        }
+        if let (true, Some(name), Some(val)) = (is_label, name, val) {
+            labels.push((val, name));
+        }
+    }
+    labels
+}
+
+// $C0A4#NmiHandler#Comment, with $0300/10#Buffer# naming a 16 byte array by its start
+fn parse_fceux_nl(contents: &str) -> Vec<(u16, String)> {
+    let mut labels = Vec::new();
+    for line in contents.lines().filter(|line| line.starts_with('$')) {
+        let mut parts = line[1..].split('#');
+        let addr = parts.next().and_then(|addr| addr.split('/').next());
+        let name = parts.next();
+        if let (Some(addr), Some(name)) = (addr, name) {
+            if let (Ok(addr), false) = (u16::from_str_radix(addr, 16), name.is_empty()) {
+                labels.push((addr, name.to_owned()));
+            }
+        }
+    }
+    labels
+}
+
+// P:00A4:NmiHandler:Comment, with the older single letter or newer Mesen 2 memory types.
+// Ranges like R:0300-030F:Buffer label their start.
+fn parse_mesen_mlb(contents: &str, prg_size: usize) -> Vec<(u16, String)> {
+    let mut labels = Vec::new();
+    for line in contents.lines() {
+        let mut parts = line.trim().splitn(4, ':');
+        let (kind, offset, name) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(kind), Some(offset), Some(name)) if !name.is_empty() => (kind, offset, name),
+            _ => continue,
+        };
+        let offset = match usize::from_str_radix(offset.split('-').next().unwrap(), 16) {
+            Ok(offset) => offset,
+            Err(_) => continue,
+        };
+        let addr = match kind {
+            "R" | "NesInternalRam" => Some(offset),
+            "G" | "NesMemory" => Some(offset),
+            "S" | "W" | "NesSaveRam" | "NesWorkRam" => Some(0x6000 + offset),
+            // Only roms without bank switching put PRG at a known address. 16KB roms
+            // are mirrored, labels go in the upper copy where the vectors point.
+            "P" | "NesPrgRom" if prg_size <= 0x8000 && offset < prg_size => Some(0x10000 - prg_size + offset),
+            _ => None,
+        };
+        if let Some(addr) = addr {
+            if addr <= 0xFFFF {
+                labels.push((addr as u16, name.to_owned()));
+            }
+        }
+    }
+    labels
+}
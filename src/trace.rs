@@ -0,0 +1,107 @@
+//! Execution trace log in the Nintendulator format used by nestest.log, e.g.
+//! `C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7`
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use super::disasm;
+use super::nes::Nes;
+
+pub struct Trace {
+    out: Box<dyn Write>,
+    pub enabled: bool,
+}
+
+impl Trace {
+    pub fn to_file(path: &Path) -> io::Result<Trace> {
+        let file = File::create(path)?;
+        Ok(Trace {
+            out: Box::new(BufWriter::new(file)),
+            enabled: true,
+        })
+    }
+
+    pub fn to_stdout() -> Trace {
+        Trace {
+            out: Box::new(io::stdout()),
+            enabled: true,
+        }
+    }
+
+    // Logs the instruction about to execute
+    pub fn log(&mut self, nes: &Nes) {
+        if !self.enabled {
+            return;
+        }
+        let line = trace_line(nes);
+        if let Err(e) = writeln!(self.out, "{}", line) {
+            println!("Failed to write trace, disabling it: {}", e);
+            self.enabled = false;
+        }
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}
+
+pub fn trace_line(nes: &Nes) -> String {
+    let cpu = &nes.cpu;
+    let instruction = disasm::decode(cpu.pc(), |addr| nes.peek_byte(addr));
+    let disassembly = instruction.annotated(cpu.x(), cpu.y(), |addr| nes.peek_byte(addr), &nes.symbols);
+    // Cpu counts in PPU cycles
+    format!("{:<48}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
+        disassembly,
+        cpu.a(),
+        cpu.x(),
+        cpu.y(),
+        cpu.p(),
+        cpu.s(),
+        nes.interconnect.ppu.scanline(),
+        nes.interconnect.ppu.dot(),
+        cpu.cycles() / 3)
+}
+
+#[cfg(test)]
+mod tests {
+    use cart::Cartridge;
+    use rom::test_rom;
+    use super::*;
+
+    // The start of nestest.log, run in its automation mode from $C000
+    const NESTEST_LOG: [&str; 13] = [
+        "C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
+        "C5F5  A2 00     LDX #$00                        A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 30 CYC:10",
+        "C5F7  86 00     STX $00 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 36 CYC:12",
+        "C5F9  86 10     STX $10 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 45 CYC:15",
+        "C5FB  86 11     STX $11 = 00                    A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 54 CYC:18",
+        "C5FD  20 2D C7  JSR $C72D                       A:00 X:00 Y:00 P:26 SP:FD PPU:  0, 63 CYC:21",
+        "C72D  EA        NOP                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 81 CYC:27",
+        "C72E  38        SEC                             A:00 X:00 Y:00 P:26 SP:FB PPU:  0, 87 CYC:29",
+        "C72F  B0 04     BCS $C735                       A:00 X:00 Y:00 P:27 SP:FB PPU:  0, 93 CYC:31",
+        "C735  EA        NOP                             A:00 X:00 Y:00 P:27 SP:FB PPU:  0,102 CYC:34",
+        "C736  18        CLC                             A:00 X:00 Y:00 P:27 SP:FB PPU:  0,108 CYC:36",
+        "C737  B0 03     BCS $C73C                       A:00 X:00 Y:00 P:26 SP:FB PPU:  0,114 CYC:38",
+        "C739  4C 40 C7  JMP $C740                       A:00 X:00 Y:00 P:26 SP:FB PPU:  0,120 CYC:40",
+    ];
+
+    #[test]
+    fn nestest_format() {
+        // Just the instructions the lines above execute, at their nestest.rom addresses
+        let mut program = vec![0xEA; 0x800];
+        program[0x000..0x003].copy_from_slice(&[0x4C, 0xF5, 0xC5]);
+        program[0x5F5..0x600].copy_from_slice(&[0xA2, 0x00, 0x86, 0x00, 0x86, 0x10, 0x86, 0x11, 0x20, 0x2D, 0xC7]);
+        program[0x72D..0x73C].copy_from_slice(&[0xEA, 0x38, 0xB0, 0x04, 0x00, 0x00, 0x00, 0x00,
+                                                0xEA, 0x18, 0xB0, 0x03, 0x4C, 0x40, 0xC7]);
+        let mut cart = Cartridge::new(test_rom(&program)).unwrap();
+        let mut nes = Nes::new(&mut cart, None);
+        nes.reset();
+        nes.cpu.set_pc(0xC000);
+
+        for line in NESTEST_LOG.iter() {
+            assert_eq!(trace_line(&nes), *line);
+            nes.step();
+        }
+    }
+}
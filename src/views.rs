@@ -0,0 +1,475 @@
+//! Optional SDL windows showing the pattern tables, nametables, OAM, palettes,
+//! a hex dump of memory and a timeline of register writes
+
+use std::mem;
+
+use super::sdl2::pixels::PixelFormatEnum::RGB24;
+use super::sdl2::render::WindowCanvas;
+use super::sdl2::surface::Surface;
+use super::sdl2::VideoSubsystem;
+
+use super::cart::Cartridge;
+use super::cpu::Cpu;
+use super::events::{EventKind, EventLog};
+use super::interconnect::Interconnect;
+use super::memory::Space;
+use super::ppu::{self, Ppu};
+
+// RGB for each color a palette entry can select
+const NES_COLORS: [u32; 64] = [
+    0x7C7C7C, 0x0000FC, 0x0000BC, 0x4428BC, 0x940084, 0xA80020, 0xA81000, 0x881400,
+    0x503000, 0x007800, 0x006800, 0x005800, 0x004058, 0x000000, 0x000000, 0x000000,
+    0xBCBCBC, 0x0078F8, 0x0058F8, 0x6844FC, 0xD800CC, 0xE40058, 0xF83800, 0xE45C10,
+    0xAC7C00, 0x00B800, 0x00A800, 0x00A844, 0x008888, 0x000000, 0x000000, 0x000000,
+    0xF8F8F8, 0x3CBCFC, 0x6888FC, 0x9878F8, 0xF878F8, 0xF85898, 0xF87858, 0xFCA044,
+    0xF8B800, 0xB8F818, 0x58D854, 0x58F898, 0x00E8D8, 0x787878, 0x000000, 0x000000,
+    0xFCFCFC, 0xA4E4FC, 0xB8B8F8, 0xD8B8F8, 0xF8B8F8, 0xF8A4C0, 0xF0D0B0, 0xFCE0A8,
+    0xF8D878, 0xD8F878, 0xB8F8B8, 0xB8F8D8, 0x00FCFC, 0xF8D8F8, 0x000000, 0x000000,
+];
+
+const TILE_BYTES: u16 = 16;
+const NAMETABLE_WIDTH: usize = 256;
+const NAMETABLE_HEIGHT: usize = 240;
+const ATTRIBUTE_TABLE: usize = 0x3C0;
+
+const HEX_ADDRESS_COLOR: u32 = 0x808080;
+const HEX_COLOR: u32 = 0xF8F8F8;
+const HEX_CHANGED_COLOR: u32 = 0xF83800;
+
+// Glyphs are 3x5 pixels in 4x7 cells, one bit per pixel with the top row first
+const GLYPH_WIDTH: usize = 4;
+const GLYPH_HEIGHT: usize = 7;
+const HEX_GLYPHS: [[u8; 5]; 16] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111],
+    [0b010, 0b110, 0b010, 0b010, 0b111],
+    [0b111, 0b001, 0b111, 0b100, 0b111],
+    [0b111, 0b001, 0b011, 0b001, 0b111],
+    [0b101, 0b101, 0b111, 0b001, 0b001],
+    [0b111, 0b100, 0b111, 0b001, 0b111],
+    [0b111, 0b100, 0b111, 0b101, 0b111],
+    [0b111, 0b001, 0b010, 0b010, 0b010],
+    [0b111, 0b101, 0b111, 0b101, 0b111],
+    [0b111, 0b101, 0b111, 0b001, 0b111],
+    [0b010, 0b101, 0b111, 0b101, 0b101],
+    [0b110, 0b101, 0b110, 0b101, 0b110],
+    [0b011, 0b100, 0b100, 0b100, 0b011],
+    [0b110, 0b101, 0b101, 0b101, 0b110],
+    [0b111, 0b100, 0b110, 0b100, 0b111],
+    [0b111, 0b100, 0b110, 0b100, 0b100],
+];
+
+// 5 digit addresses cover the largest ROMs, then a space and "XX " per byte
+const HEX_ADDRESS_DIGITS: usize = 5;
+pub const HEX_ROW_BYTES: usize = 16;
+const HEX_ROWS: usize = 32;
+
+const DOTS: usize = 341;
+const SCANLINES: usize = 262;
+const RENDERING_COLOR: u32 = 0x282828; // Visible scanlines, dots 1-256
+const BLANKING_COLOR: u32 = 0x101010;
+const BEAM_COLOR: u32 = 0x505050; // The current scanline up to the current dot
+
+const VIEWPORT_COLOR: u32 = 0xF83800; // Outline of the scrolled screen over the nametables
+
+// Number of palettes the pattern tables can be drawn with, 0-3 background and 4-7 sprites
+pub const CHR_PALETTES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum View {
+    Chr,
+    Nametables,
+    Oam,
+    Palette,
+    Hex,
+    Events,
+}
+
+impl View {
+    // Texture width and height, and how much the window scales it up
+    fn size(&self) -> (u32, u32, u32) {
+        match *self {
+            View::Chr => (256, 128, 2),
+            View::Nametables => (512, 480, 1),
+            View::Oam => (64, 128, 3), // 8x8 grid of 8x16 cells
+            View::Palette => (16, 2, 24),
+            View::Hex => (((HEX_ADDRESS_DIGITS + 1 + HEX_ROW_BYTES * 3) * GLYPH_WIDTH) as u32,
+                          (HEX_ROWS * GLYPH_HEIGHT) as u32, 3),
+            View::Events => (DOTS as u32, SCANLINES as u32, 2),
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match *self {
+            View::Chr => "Pattern tables",
+            View::Nametables => "Nametables",
+            View::Oam => "OAM",
+            View::Palette => "Palettes",
+            View::Hex => "Memory",
+            View::Events => "Events",
+        }
+    }
+}
+
+// What the hex view shows, and the bytes it showed during the previous frame so
+// changes can be highlighted
+pub struct HexView {
+    pub space: Space,
+    pub addr: usize, // First byte shown, a multiple of HEX_ROW_BYTES
+    previous: Vec<u8>,
+    current: Vec<u8>,
+    frame: usize,
+}
+
+impl HexView {
+    fn new() -> Self {
+        HexView {
+            space: Space::Cpu,
+            addr: 0,
+            previous: Vec::new(),
+            current: Vec::new(),
+            frame: 0,
+        }
+    }
+
+    // Forgets the old bytes so nothing shows as changed
+    pub fn show(&mut self, space: Space, addr: usize) {
+        self.space = space;
+        self.addr = addr - addr % HEX_ROW_BYTES;
+        self.previous.clear();
+        self.current.clear();
+    }
+
+    fn update(&mut self, bytes: Vec<u8>, frame: usize) {
+        if frame != self.frame {
+            self.previous = mem::take(&mut self.current);
+            self.frame = frame;
+        }
+        self.current = bytes;
+    }
+
+    fn changed(&self, i: usize) -> bool {
+        self.previous.get(i).is_some_and(|&old| old != self.current[i])
+    }
+}
+
+pub struct Views {
+    video: Option<VideoSubsystem>, // None without a screen
+    windows: Vec<(View, WindowCanvas)>,
+    pub chr_palette: usize,
+    pub hex: HexView,
+}
+
+impl Views {
+    pub fn new(video: Option<VideoSubsystem>) -> Self {
+        Views {
+            video,
+            windows: Vec::new(),
+            chr_palette: 0,
+            hex: HexView::new(),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        !self.windows.is_empty()
+    }
+
+    pub fn is_showing(&self, view: View) -> bool {
+        self.windows.iter().any(|&(open, _)| open == view)
+    }
+
+    pub fn toggle(&mut self, view: View) -> Result<(), String> {
+        if let Some(i) = self.windows.iter().position(|&(open, _)| open == view) {
+            self.windows.remove(i);
+            return Ok(());
+        }
+        let video = self.video.as_ref().ok_or("no video without a screen")?;
+        let (width, height, scale) = view.size();
+        let window = video.window(view.title(), width * scale, height * scale)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        self.windows.push((view, canvas));
+        Ok(())
+    }
+
+    // Returns false if the window isn't one of ours
+    pub fn close(&mut self, window_id: u32) -> bool {
+        let count = self.windows.len();
+        self.windows.retain(|(_, canvas)| canvas.window().id() != window_id);
+        self.windows.len() != count
+    }
+
+    pub fn refresh(&mut self, cpu: &Cpu, interconnect: &Interconnect) {
+        let (ppu, cart) = (&interconnect.ppu, &*interconnect.cart);
+        if self.is_showing(View::Hex) {
+            let space = self.hex.space;
+            let end = space.size(interconnect).min(self.hex.addr + HEX_ROWS * HEX_ROW_BYTES);
+            let bytes = (self.hex.addr..end).map(|addr| space.peek(cpu, interconnect, addr)).collect();
+            self.hex.update(bytes, ppu.frame());
+        }
+
+        for &mut (view, ref mut canvas) in self.windows.iter_mut() {
+            let (width, height, _) = view.size();
+            let mut image = Image::new(width as usize, height as usize);
+            match view {
+                View::Chr => draw_chr(&mut image, ppu, cart, self.chr_palette),
+                View::Nametables => draw_nametables(&mut image, ppu, cart),
+                View::Oam => draw_oam(&mut image, ppu, cart),
+                View::Palette => draw_palette(&mut image, ppu),
+                View::Hex => draw_hex(&mut image, &self.hex),
+                View::Events => draw_events(&mut image, &interconnect.events, ppu),
+            }
+
+            let texture_creator = canvas.texture_creator();
+            let mut texture = match texture_creator.create_texture_streaming(RGB24, width, height) {
+                Ok(texture) => texture,
+                Err(_) => continue,
+            };
+            let _ = texture.update(None, &image.pixels, width as usize * 3);
+            canvas.clear();
+            let _ = canvas.copy(&texture, None, None);
+            canvas.present();
+        }
+    }
+}
+
+// Writes the events view to a BMP file
+pub fn save_events(path: &str, interconnect: &Interconnect) -> Result<(), String> {
+    let mut image = Image::new(DOTS, SCANLINES);
+    draw_events(&mut image, &interconnect.events, &interconnect.ppu);
+    let surface = Surface::from_data(&mut image.pixels, DOTS as u32, SCANLINES as u32, DOTS as u32 * 3, RGB24)?;
+    surface.save_bmp(path)
+}
+
+struct Image {
+    pixels: Vec<u8>, // R + G + B
+    width: usize,
+}
+
+// Pattern address of an 8x8 tile, the palette RAM values for its 2 bit pixels and
+// how it is flipped
+struct Tile {
+    addr: u16,
+    colors: [u8; 4],
+    h_flip: bool,
+    v_flip: bool,
+}
+
+impl Tile {
+    fn new(addr: u16, colors: [u8; 4]) -> Self {
+        Tile {
+            addr,
+            colors,
+            h_flip: false,
+            v_flip: false,
+        }
+    }
+}
+
+impl Image {
+    fn new(width: usize, height: usize) -> Self {
+        Image {
+            pixels: vec![0; width * height * 3],
+            width,
+        }
+    }
+
+    fn set(&mut self, x: usize, y: usize, rgb: u32) {
+        let i = (y * self.width + x) * 3;
+        self.pixels[i] = (rgb >> 16) as u8;
+        self.pixels[i + 1] = (rgb >> 8) as u8;
+        self.pixels[i + 2] = rgb as u8;
+    }
+
+    fn tile(&mut self, cart: &Cartridge, tile: &Tile, x: usize, y: usize) {
+        for row in 0..8 {
+            let low = cart.chr_peek(tile.addr + row);
+            let high = cart.chr_peek(tile.addr + row + 8);
+            for col in 0..8 {
+                let bit = 7 - col;
+                let pixel = (low >> bit) & 1 | ((high >> bit) & 1) << 1;
+                let px = if tile.h_flip { 7 - col } else { col } as usize;
+                let py = if tile.v_flip { 7 - row } else { row } as usize;
+                self.set(x + px, y + py, NES_COLORS[(tile.colors[pixel as usize] & 0x3F) as usize]);
+            }
+        }
+    }
+
+    // Draws the low <digits> hex digits of val in glyph cells starting at cell x, y
+    fn hex(&mut self, x: usize, y: usize, val: usize, digits: usize, rgb: u32) {
+        for digit in 0..digits {
+            let glyph = HEX_GLYPHS[(val >> ((digits - 1 - digit) * 4)) & 0xF];
+            let (origin_x, origin_y) = ((x + digit) * GLYPH_WIDTH, y * GLYPH_HEIGHT + 1);
+            for (row, &bits) in glyph.iter().enumerate() {
+                for col in 0..3 {
+                    if bits & (0b100 >> col) != 0 {
+                        self.set(origin_x + col, origin_y + row, rgb);
+                    }
+                }
+            }
+        }
+    }
+}
+
+// Palette 0 is shared by every palette's transparent color
+fn palette_colors(ppu: &Ppu, palette: usize) -> [u8; 4] {
+    let ram = ppu.palette();
+    [ram[0], ram[palette * 4 + 1], ram[palette * 4 + 2], ram[palette * 4 + 3]]
+}
+
+fn draw_chr(image: &mut Image, ppu: &Ppu, cart: &Cartridge, palette: usize) {
+    let colors = palette_colors(ppu, palette);
+    for table in 0..2 {
+        for tile in 0..256 {
+            let addr = table * 0x1000 + tile * TILE_BYTES;
+            let x = table as usize * 128 + (tile as usize % 16) * 8;
+            let y = (tile as usize / 16) * 8;
+            image.tile(cart, &Tile::new(addr, colors), x, y);
+        }
+    }
+}
+
+fn draw_nametables(image: &mut Image, ppu: &Ppu, cart: &Cartridge) {
+    let bg_table = if ppu.ctrl() & 0x10 != 0 { 0x1000 } else { 0 };
+    for index in 0..4 {
+        let nametable = match ppu::physical_nametable(cart.mirroring(), index) {
+            Some(physical) => ppu.nametable(physical),
+            None => continue,
+        };
+        let (origin_x, origin_y) = ((index % 2) * NAMETABLE_WIDTH, (index / 2) * NAMETABLE_HEIGHT);
+        for tile_y in 0..30 {
+            for tile_x in 0..32 {
+                let tile = nametable[tile_y * 32 + tile_x] as u16;
+                let attribute = nametable[ATTRIBUTE_TABLE + (tile_y / 4) * 8 + tile_x / 4];
+                let shift = (tile_y % 4) / 2 * 4 + (tile_x % 4) / 2 * 2;
+                let colors = palette_colors(ppu, (attribute >> shift) as usize & 0x3);
+                image.tile(cart, &Tile::new(bg_table + tile * TILE_BYTES, colors),
+                           origin_x + tile_x * 8, origin_y + tile_y * 8);
+            }
+        }
+    }
+
+    // The next frame starts rendering from t and fine x, so outline the screen they
+    // scroll to. It wraps around the edges like the PPU does.
+    let t = ppu.t() as usize;
+    let x = (t >> 10 & 1) * NAMETABLE_WIDTH + (t & 0x1F) * 8 + ppu.fine_x() as usize;
+    let y = ((t >> 11 & 1) * NAMETABLE_HEIGHT + (t >> 5 & 0x1F) * 8 + (t >> 12 & 0x7)) % (2 * NAMETABLE_HEIGHT);
+    let (width, height) = (2 * NAMETABLE_WIDTH, 2 * NAMETABLE_HEIGHT);
+    for i in 0..NAMETABLE_WIDTH {
+        image.set((x + i) % width, y, VIEWPORT_COLOR);
+        image.set((x + i) % width, (y + NAMETABLE_HEIGHT - 1) % height, VIEWPORT_COLOR);
+    }
+    for i in 0..NAMETABLE_HEIGHT {
+        image.set(x, (y + i) % height, VIEWPORT_COLOR);
+        image.set((x + NAMETABLE_WIDTH - 1) % width, (y + i) % height, VIEWPORT_COLOR);
+    }
+}
+
+fn draw_oam(image: &mut Image, ppu: &Ppu, cart: &Cartridge) {
+    let tall = ppu.ctrl() & 0x20 != 0;
+    let sprite_table = if ppu.ctrl() & 0x08 != 0 { 0x1000 } else { 0 };
+    for (i, sprite) in ppu.oam().chunks(4).enumerate() {
+        let (tile, attributes) = (sprite[1] as u16, sprite[2]);
+        let colors = palette_colors(ppu, 4 + (attributes & 0x3) as usize);
+        let (h_flip, v_flip) = (attributes & 0x40 != 0, attributes & 0x80 != 0);
+        let (x, y) = ((i % 8) * 8, (i / 8) * 16);
+        let flipped = |addr| Tile { addr, colors, h_flip, v_flip };
+        if tall {
+            // 8x16 sprites pick their table with bit 0, flipping swaps the halves
+            let top = (tile & 1) * 0x1000 + (tile & 0xFE) * TILE_BYTES;
+            let (first, second) = if v_flip { (top + TILE_BYTES, top) } else { (top, top + TILE_BYTES) };
+            image.tile(cart, &flipped(first), x, y);
+            image.tile(cart, &flipped(second), x, y + 8);
+        } else {
+            image.tile(cart, &flipped(sprite_table + tile * TILE_BYTES), x, y);
+        }
+    }
+}
+
+fn draw_palette(image: &mut Image, ppu: &Ppu) {
+    for (i, &color) in ppu.palette().iter().enumerate() {
+        image.set(i % 16, i / 16, NES_COLORS[(color & 0x3F) as usize]);
+    }
+}
+
+fn draw_hex(image: &mut Image, hex: &HexView) {
+    for (row, bytes) in hex.current.chunks(HEX_ROW_BYTES).enumerate() {
+        image.hex(0, row, hex.addr + row * HEX_ROW_BYTES, HEX_ADDRESS_DIGITS, HEX_ADDRESS_COLOR);
+        for (col, &byte) in bytes.iter().enumerate() {
+            let color = if hex.changed(row * HEX_ROW_BYTES + col) { HEX_CHANGED_COLOR } else { HEX_COLOR };
+            image.hex(HEX_ADDRESS_DIGITS + 1 + col * 3, row, byte as usize, 2, color);
+        }
+    }
+}
+
+fn event_color(kind: EventKind) -> u32 {
+    match kind {
+        EventKind::PpuWrite => 0xF85898,
+        EventKind::ApuWrite => 0x58D854,
+        EventKind::MapperWrite => 0xF8B800,
+        EventKind::Nmi => 0x3CBCFC,
+        EventKind::Irq => 0xF878F8,
+    }
+}
+
+// One row per scanline and one column per dot. Events after the beam are from the
+// previous frame, so a paused frame still shows a full timeline.
+fn draw_events(image: &mut Image, events: &EventLog, ppu: &Ppu) {
+    for y in 0..SCANLINES {
+        for x in 0..DOTS {
+            let rendering = y < NAMETABLE_HEIGHT && (1..=NAMETABLE_WIDTH).contains(&x);
+            image.set(x, y, if rendering { RENDERING_COLOR } else { BLANKING_COLOR });
+        }
+    }
+    let now = (ppu.scanline(), ppu.dot());
+    for x in 0..(now.1 as usize).min(DOTS) {
+        image.set(x, now.0 as usize, BEAM_COLOR);
+    }
+
+    let (last, current) = events.frames(ppu.frame());
+    let later = last.iter().filter(|event| (event.scanline, event.dot) > now);
+    for event in later.chain(current.iter()) {
+        // 3x3 markers so single writes can be spotted
+        let (x, y) = (event.dot as usize, event.scanline as usize);
+        for marker_y in y.saturating_sub(1)..(y + 2).min(SCANLINES) {
+            for marker_x in x.saturating_sub(1)..(x + 2).min(DOTS) {
+                image.set(marker_x, marker_y, event_color(event.kind));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cart::Cartridge;
+    use rom::test_rom;
+    use super::*;
+
+    fn pixel(image: &Image, x: usize, y: usize) -> u32 {
+        let i = (y * image.width + x) * 3;
+        (image.pixels[i] as u32) << 16 | (image.pixels[i + 1] as u32) << 8 | image.pixels[i + 2] as u32
+    }
+
+    #[test]
+    fn scroll_viewport() {
+        let mut cart = Cartridge::new(test_rom(&[])).unwrap();
+        let mut ppu = Ppu::new();
+        // Second nametable, scrolled 12 right and 5 down
+        ppu.write_register(&mut cart, 0x2000, 0x01);
+        ppu.write_register(&mut cart, 0x2005, 12);
+        ppu.write_register(&mut cart, 0x2005, 5);
+
+        let (width, height, _) = View::Nametables.size();
+        let mut image = Image::new(width as usize, height as usize);
+        draw_nametables(&mut image, &ppu, &cart);
+        let (left, top) = (NAMETABLE_WIDTH + 12, 5);
+        assert_eq!(pixel(&image, left, top), VIEWPORT_COLOR);
+        assert_eq!(pixel(&image, left, top + NAMETABLE_HEIGHT - 1), VIEWPORT_COLOR);
+        assert_ne!(pixel(&image, left - 1, top), VIEWPORT_COLOR);
+        assert_ne!(pixel(&image, left + 1, top + 1), VIEWPORT_COLOR);
+        // The right edge wraps around to the first nametable
+        assert_eq!(pixel(&image, 11, top + 100), VIEWPORT_COLOR);
+        assert_eq!(pixel(&image, 0, top), VIEWPORT_COLOR);
+        assert_ne!(pixel(&image, 12, top), VIEWPORT_COLOR);
+    }
+}